@@ -0,0 +1,5763 @@
+//! Detection of broken AUR packages, interpreter packages and systemd links after system upgrades
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use anyhow::Context;
+use crossbeam::thread as cb_thread;
+use glob::glob;
+use goblin::elf::header::{
+    machine_to_str, ELFCLASS32, ELFCLASS64, EM_386, EM_AARCH64, EM_ARM, EM_PPC64, EM_RISCV,
+    EM_X86_64,
+};
+use log::{debug, info, trace};
+use notify::Watcher;
+use serde::{Deserialize, Serialize};
+use wait_timeout::ChildExt;
+
+type CrossbeamChannel<T> = (
+    crossbeam::channel::Sender<T>,
+    crossbeam::channel::Receiver<T>,
+);
+
+/// Executable file work unit for a worker thread to process
+#[derive(Debug)]
+struct ExecFileWork {
+    /// AUR package name
+    #[allow(clippy::rc_buffer)]
+    package: Arc<String>,
+
+    // Executable filepath
+    #[allow(clippy::rc_buffer)]
+    exec_filepath: Arc<PathBuf>,
+
+    /// True if this is the last executable filepath for the package (used to report progress)
+    package_last: bool,
+}
+
+/// Missing library of a broken package
+#[derive(Debug, Serialize)]
+pub struct MissingLibrary {
+    /// Missing soname
+    pub soname: String,
+
+    /// Package files linking to the missing soname
+    pub files: Vec<String>,
+
+    /// Repository package providing the soname, if known
+    pub provider: Option<String>,
+
+    /// True if the providing package is installed, false if it is not or is unknown
+    pub provider_installed: bool,
+
+    /// Whether another version of the library is installed
+    pub kind: MissingKind,
+}
+
+/// Cause of a missing library, telling how to fix it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MissingKind {
+    /// Another version of the library is installed, the package needs to be rebuilt against it
+    VersionBump,
+
+    /// No version of the library is installed, the package providing it needs to be installed
+    Absent,
+}
+
+impl fmt::Display for MissingKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MissingKind::VersionBump => write!(f, "version bump, rebuild"),
+            MissingKind::Absent => write!(f, "provider missing, install"),
+        }
+    }
+}
+
+/// Package with missing direct library dependencies
+#[derive(Debug, Serialize)]
+pub struct BrokenPackage {
+    /// AUR package name
+    pub package: String,
+
+    /// Missing libraries
+    pub missing: Vec<MissingLibrary>,
+
+    /// Installed packages requiring this package, that may also need to be rebuilt
+    pub required_by: Vec<String>,
+
+    /// PAM modules and NSS plugins among the files missing libraries, that can break login
+    pub security_modules: Vec<String>,
+}
+
+/// Package with files in a directory ignored by the current version of an interpreter
+#[derive(Debug, PartialEq, Serialize)]
+pub struct BrokenRuntimePackage {
+    /// Interpreter or toolkit name
+    pub runtime: String,
+
+    /// Package name
+    pub package: String,
+
+    /// Outdated versioned directory
+    pub dir: String,
+}
+
+/// Symlink that can not be resolved to an existing file
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct BrokenLink {
+    /// Broken symlink
+    pub link: PathBuf,
+
+    /// Target of the symlink, as stored in the link
+    pub target: PathBuf,
+
+    /// First path that does not exist when following the link
+    pub missing: PathBuf,
+}
+
+impl fmt::Display for BrokenLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "link {} -> {} is broken (missing {})",
+            self.link.display(),
+            self.target.display(),
+            self.missing.display()
+        )
+    }
+}
+
+/// Library linked by a package file that is found on disk, but resolves to a library with another
+/// soname, like through a `libfoo.so.1 -> libfoo.so.2` symlink
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnprovidedLibrary {
+    /// Package name
+    pub package: String,
+
+    /// Linked soname
+    pub soname: String,
+
+    /// Files of the package linking the library
+    pub files: Vec<String>,
+}
+
+/// Package file that is a symlink to a missing target
+#[derive(Debug, Serialize)]
+pub struct BrokenSymlink {
+    /// Package name
+    pub package: String,
+
+    /// Broken symlink
+    #[serde(flatten)]
+    pub broken_link: BrokenLink,
+}
+
+/// GObject introspection typelib loading libraries that can not be found
+#[derive(Debug, Serialize)]
+pub struct BrokenTypelib {
+    /// Package name
+    pub package: String,
+
+    /// Typelib file
+    pub typelib: PathBuf,
+
+    /// Missing libraries
+    pub missing: Vec<String>,
+}
+
+/// Udev rule running a helper program that is missing or not executable
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BrokenUdevRule {
+    /// Package name
+    pub package: String,
+
+    /// Rules file
+    pub rule: PathBuf,
+
+    /// Helper program run by the rule
+    pub program: PathBuf,
+}
+
+/// Desktop entry launching a program that can not be found
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BrokenDesktopEntry {
+    /// Package name
+    pub package: String,
+
+    /// Desktop entry file
+    pub desktop_file: PathBuf,
+
+    /// Program launched by the entry, an absolute path or a name searched in `PATH`
+    pub program: String,
+}
+
+/// Soname provided by several installed packages in library directories, the dynamic loader
+/// only loads the first one it finds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct DuplicateSoname {
+    /// Library file name
+    pub soname: String,
+
+    /// Packages providing the soname, with their copies of the library
+    pub providers: BTreeMap<String, Vec<PathBuf>>,
+}
+
+impl fmt::Display for DuplicateSoname {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Soname {} is provided by several packages: ",
+            self.soname
+        )?;
+        for (i, (package, files)) in self.providers.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            let files: Vec<String> = files.iter().map(|p| p.display().to_string()).collect();
+            write!(f, "{:?} ({})", package, files.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Package file built for a machine type the host can not run
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ForeignArchFile {
+    /// Package name
+    pub package: String,
+
+    /// ELF file
+    pub file: PathBuf,
+
+    /// Architecture the file is built for
+    pub arch: String,
+}
+
+impl fmt::Display for ForeignArchFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Package {:?} has file {} built for {}, host is {}",
+            self.package,
+            self.file.display(),
+            self.arch,
+            std::env::consts::ARCH
+        )
+    }
+}
+
+/// Package file that could not be analyzed, so libraries it misses may not be reported
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UncheckedFile {
+    /// Package name
+    pub package: String,
+
+    /// ELF file
+    pub file: PathBuf,
+
+    /// Why the file could not be analyzed
+    pub error: String,
+}
+
+impl fmt::Display for UncheckedFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Package {:?} has file {} that could not be analyzed: {}",
+            self.package,
+            self.file.display(),
+            self.error
+        )
+    }
+}
+
+/// Package whose files pacman could not list, so none of its files are checked
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnlistedPackage {
+    /// Package name
+    pub package: String,
+
+    /// Pacman error message
+    pub error: String,
+}
+
+impl fmt::Display for UnlistedPackage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Files of package {:?} could not be listed: {}",
+            self.package, self.error
+        )
+    }
+}
+
+/// Full report of all detected problems
+#[derive(Debug, Default, Serialize)]
+pub struct BrokenReport {
+    /// Packages with missing direct library dependencies
+    pub broken_packages: Vec<BrokenPackage>,
+
+    /// Packages only missing libraries through their dependencies, or requiring a broken package,
+    /// directly or not
+    pub transitively_broken_packages: Vec<String>,
+
+    /// Packages with files in outdated Python, Perl, Ruby or Lua directories
+    pub broken_runtime_packages: Vec<BrokenRuntimePackage>,
+
+    /// User pip and pipx packages installed for an older Python version, only if checked
+    pub broken_pip_packages: Vec<BrokenRuntimePackage>,
+
+    /// Broken systemd enabled service links
+    pub broken_systemd_links: Vec<BrokenLink>,
+
+    /// Package files that are broken symlinks
+    pub broken_symlinks: Vec<BrokenSymlink>,
+
+    /// Linked libraries resolving to a library with another soname
+    pub unprovided_libraries: Vec<UnprovidedLibrary>,
+
+    /// GObject introspection typelibs loading missing libraries
+    pub broken_typelibs: Vec<BrokenTypelib>,
+
+    /// Udev rules running missing helper programs
+    pub broken_udev_rules: Vec<BrokenUdevRule>,
+
+    /// Desktop entries launching missing programs
+    pub broken_desktop_entries: Vec<BrokenDesktopEntry>,
+
+    /// Sonames provided by several installed packages
+    pub duplicate_sonames: Vec<DuplicateSoname>,
+
+    /// Package files built for another architecture, whose libraries are not checked
+    pub foreign_arch_files: Vec<ForeignArchFile>,
+
+    /// Package files that could not be analyzed, not counted as problems
+    pub unchecked_files: Vec<UncheckedFile>,
+
+    /// Packages whose files could not be listed, not counted as problems
+    pub unlisted_packages: Vec<UnlistedPackage>,
+
+    /// Problems that did not stop the scan, but may make its results less accurate
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+
+    /// Suggested command to rebuild broken packages
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rebuild_command: Option<String>,
+
+    /// Problem counts and scan duration
+    pub summary: Summary,
+
+    /// Installed packages requiring broken or transitively broken packages
+    #[serde(skip)]
+    pub reverse_deps: HashMap<String, Vec<String>>,
+}
+
+/// Counts of scanned packages and problems found
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    /// Number of scanned packages
+    pub scanned_packages: usize,
+
+    /// Number of packages with missing direct library dependencies
+    pub broken_packages: usize,
+
+    /// Number of packages only missing libraries through their dependencies
+    pub transitively_broken_packages: usize,
+
+    /// Number of outdated interpreter package directories
+    pub broken_runtime_packages: usize,
+
+    /// Number of user pip and pipx packages installed for an older Python version
+    pub broken_pip_packages: usize,
+
+    /// Number of broken systemd links
+    pub broken_systemd_links: usize,
+
+    /// Number of broken package symlinks
+    pub broken_symlinks: usize,
+
+    /// Number of linked libraries resolving to a library with another soname
+    pub unprovided_libraries: usize,
+
+    /// Number of GObject introspection typelibs loading missing libraries
+    pub broken_typelibs: usize,
+
+    /// Number of udev rules running missing helper programs
+    pub broken_udev_rules: usize,
+
+    /// Number of desktop entries launching missing programs
+    pub broken_desktop_entries: usize,
+
+    /// Number of sonames provided by several installed packages
+    pub duplicate_sonames: usize,
+
+    /// Number of package files built for another architecture
+    pub foreign_arch_files: usize,
+
+    /// Number of package files that could not be analyzed
+    pub unchecked_files: usize,
+
+    /// Number of packages whose files could not be listed
+    pub unlisted_packages: usize,
+
+    /// Scan duration in seconds
+    pub duration_secs: f64,
+}
+
+impl Summary {
+    /// Count problems of a report
+    pub fn new(report: &BrokenReport, scanned_packages: usize, duration: Duration) -> Self {
+        Self {
+            scanned_packages,
+            broken_packages: report.broken_packages.len(),
+            transitively_broken_packages: report.transitively_broken_packages.len(),
+            broken_runtime_packages: report.broken_runtime_packages.len(),
+            broken_pip_packages: report.broken_pip_packages.len(),
+            broken_systemd_links: report.broken_systemd_links.len(),
+            broken_symlinks: report.broken_symlinks.len(),
+            unprovided_libraries: report.unprovided_libraries.len(),
+            broken_typelibs: report.broken_typelibs.len(),
+            broken_udev_rules: report.broken_udev_rules.len(),
+            broken_desktop_entries: report.broken_desktop_entries.len(),
+            duplicate_sonames: report.duplicate_sonames.len(),
+            foreign_arch_files: report.foreign_arch_files.len(),
+            unchecked_files: report.unchecked_files.len(),
+            unlisted_packages: report.unlisted_packages.len(),
+            duration_secs: duration.as_secs_f64(),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Scanned {} package{} in {:.1}s: ",
+            self.scanned_packages,
+            if self.scanned_packages == 1 { "" } else { "s" },
+            self.duration_secs
+        )?;
+        let counts: Vec<String> = [
+            (self.broken_packages, "need rebuild", "need rebuild"),
+            (
+                self.transitively_broken_packages,
+                "transitively broken",
+                "transitively broken",
+            ),
+            (
+                self.broken_runtime_packages,
+                "broken interpreter dir",
+                "broken interpreter dirs",
+            ),
+            (
+                self.broken_pip_packages,
+                "stranded pip package",
+                "stranded pip packages",
+            ),
+            (
+                self.broken_systemd_links,
+                "broken systemd link",
+                "broken systemd links",
+            ),
+            (self.broken_symlinks, "broken symlink", "broken symlinks"),
+            (
+                self.unprovided_libraries,
+                "mismatched library",
+                "mismatched libraries",
+            ),
+            (self.broken_typelibs, "broken typelib", "broken typelibs"),
+            (
+                self.broken_udev_rules,
+                "broken udev rule",
+                "broken udev rules",
+            ),
+            (
+                self.broken_desktop_entries,
+                "broken desktop entry",
+                "broken desktop entries",
+            ),
+            (
+                self.foreign_arch_files,
+                "foreign architecture file",
+                "foreign architecture files",
+            ),
+        ]
+        .iter()
+        .filter(|(count, _, _)| *count > 0)
+        .map(|(count, singular, plural)| {
+            format!("{} {}", count, if *count == 1 { singular } else { plural })
+        })
+        .collect();
+        if counts.is_empty() {
+            write!(f, "no problem found")?;
+        } else {
+            write!(f, "{}", counts.join(", "))?;
+        }
+        // Coverage was incomplete, even if no problem was found
+        let mut incomplete = Vec::new();
+        if self.unchecked_files > 0 {
+            incomplete.push(format!(
+                "{} file{} could not be analyzed",
+                self.unchecked_files,
+                if self.unchecked_files == 1 { "" } else { "s" }
+            ));
+        }
+        if self.unlisted_packages > 0 {
+            incomplete.push(format!(
+                "{} package{} could not be listed",
+                self.unlisted_packages,
+                if self.unlisted_packages == 1 { "" } else { "s" }
+            ));
+        }
+        if !incomplete.is_empty() {
+            write!(f, " ({})", incomplete.join(", "))?;
+        }
+        write!(f, ".")?;
+        // Duplicate sonames are not problems, they are told apart from them
+        if self.duplicate_sonames > 0 {
+            write!(
+                f,
+                "\nFor information: {} soname{} provided by several packages.",
+                self.duplicate_sonames,
+                if self.duplicate_sonames == 1 { "" } else { "s" }
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl BrokenReport {
+    /// Build a report from the results of all checks, without summary
+    pub fn new(
+        libraries: LibraryCheck,
+        runtimes: RuntimeCheck,
+        broken_systemd_links: Vec<BrokenLink>,
+    ) -> Self {
+        let mut warnings = libraries.warnings;
+        warnings.extend(runtimes.warnings);
+        Self {
+            broken_packages: libraries.broken_packages,
+            transitively_broken_packages: libraries.transitively_broken_packages,
+            broken_runtime_packages: runtimes.broken_packages,
+            broken_systemd_links,
+            unprovided_libraries: libraries.unprovided_libraries,
+            foreign_arch_files: libraries.foreign_arch_files,
+            unchecked_files: libraries.unchecked_files,
+            reverse_deps: libraries.reverse_deps,
+            warnings,
+            ..Default::default()
+        }
+    }
+
+    /// True if no problem of any kind was found
+    pub fn is_clean(&self) -> bool {
+        self.broken_packages.is_empty()
+            && self.transitively_broken_packages.is_empty()
+            && self.broken_runtime_packages.is_empty()
+            && self.broken_pip_packages.is_empty()
+            && self.broken_systemd_links.is_empty()
+            && self.broken_symlinks.is_empty()
+            && self.unprovided_libraries.is_empty()
+            && self.broken_typelibs.is_empty()
+            && self.broken_udev_rules.is_empty()
+            && self.broken_desktop_entries.is_empty()
+            && self.foreign_arch_files.is_empty()
+    }
+
+    /// Get sorted and deduplicated names of packages that need to be rebuilt
+    pub fn packages_to_rebuild(&self) -> Vec<String> {
+        let mut packages: Vec<String> = self
+            .broken_packages
+            .iter()
+            .map(|p| p.package.to_owned())
+            .chain(self.transitively_broken_packages.iter().cloned())
+            .chain(
+                self.unprovided_libraries
+                    .iter()
+                    .map(|u| u.package.to_owned()),
+            )
+            .chain(self.broken_typelibs.iter().map(|t| t.package.to_owned()))
+            .collect();
+        packages.sort();
+        packages.dedup();
+        packages
+    }
+
+    /// Get sorted names of broken packages, grouped by repository package providing the libraries
+    /// they miss, packages missing libraries with unknown providers are left out
+    pub fn packages_by_provider(&self) -> BTreeMap<String, Vec<String>> {
+        let mut packages_by_provider = BTreeMap::<String, Vec<String>>::new();
+        for broken_package in &self.broken_packages {
+            for provider in broken_package
+                .missing
+                .iter()
+                .filter_map(|m| m.provider.as_ref())
+                .filter(|p| *p != "?")
+            {
+                let packages = packages_by_provider.entry(provider.to_owned()).or_default();
+                if !packages.contains(&broken_package.package) {
+                    packages.push(broken_package.package.to_owned());
+                }
+            }
+        }
+        for packages in packages_by_provider.values_mut() {
+            packages.sort();
+        }
+        packages_by_provider
+    }
+
+    /// Get repository packages whose update broke packages, with the broken packages, the ones
+    /// breaking the most packages first
+    pub fn breaking_providers(&self) -> Vec<(String, Vec<String>)> {
+        let mut breaking_providers: Vec<(String, Vec<String>)> =
+            self.packages_by_provider().into_iter().collect();
+        // Sorting is stable, so equally breaking providers stay in alphabetical order
+        breaking_providers.sort_by_key(|(_, packages)| cmp::Reverse(packages.len()));
+        breaking_providers
+    }
+
+    /// Get packages to rebuild with dependencies before their dependents, None if there is a
+    /// dependency cycle
+    pub fn rebuild_order(&self) -> Option<Vec<String>> {
+        sort_by_dependencies(&self.packages_to_rebuild(), &self.reverse_deps)
+    }
+}
+
+/// Sort packages so that dependencies come before their dependents, with alphabetical order for
+/// independent packages, return None if there is a dependency cycle
+fn sort_by_dependencies(
+    packages: &[String],
+    reverse_deps: &HashMap<String, Vec<String>>,
+) -> Option<Vec<String>> {
+    let package_set: HashSet<&String> = packages.iter().collect();
+    let mut dep_counts: HashMap<&String, usize> = packages.iter().map(|p| (p, 0)).collect();
+    for package in packages {
+        for reverse_dep in reverse_deps.get(package).into_iter().flatten() {
+            if let Some(count) = dep_counts.get_mut(reverse_dep) {
+                *count += 1;
+            }
+        }
+    }
+
+    let mut ready: BTreeSet<&String> = dep_counts
+        .iter()
+        .filter(|(_, &c)| c == 0)
+        .map(|(&p, _)| p)
+        .collect();
+    let mut sorted = Vec::with_capacity(packages.len());
+    while let Some(package) = ready.pop_first() {
+        sorted.push(package.to_owned());
+        for reverse_dep in reverse_deps.get(package).into_iter().flatten() {
+            if !package_set.contains(reverse_dep) {
+                continue;
+            }
+            let count = dep_counts.get_mut(reverse_dep).unwrap();
+            *count -= 1;
+            if *count == 0 {
+                ready.insert(package_set.get(reverse_dep).unwrap());
+            }
+        }
+    }
+
+    if sorted.len() == packages.len() {
+        Some(sorted)
+    } else {
+        None
+    }
+}
+
+/// Default timeout for external commands
+pub const COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Additional timeout to list files, for each package
+const FILES_LISTING_TIMEOUT_PER_PACKAGE: Duration = Duration::from_millis(50);
+
+/// Additional timeout to query package information, for each package
+const INFO_TIMEOUT_PER_PACKAGE: Duration = Duration::from_millis(10);
+
+/// Check if a path is a regular file with an executable permission bit set, following symlinks
+fn is_executable_file(path: &Path) -> bool {
+    fs::metadata(path).is_ok_and(|m| m.is_file() && (m.permissions().mode() & 0o111) != 0)
+}
+
+/// Find an executable program in the directories of a `PATH` like list
+fn find_program_in(name: &str, path_var: &OsStr) -> Option<PathBuf> {
+    std::env::split_paths(path_var)
+        .map(|d| d.join(name))
+        .find(|p| is_executable_file(p))
+}
+
+/// Find an executable program in `PATH`, like the shell does
+pub fn find_program(name: &str) -> Option<PathBuf> {
+    find_program_in(name, &std::env::var_os("PATH")?)
+}
+
+/// Run a command and capture its output, killing it if it does not complete in time
+pub fn run_with_timeout(cmd: &mut Command, timeout: Duration) -> anyhow::Result<Output> {
+    let mut child = cmd
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {:?}", cmd))?;
+
+    // Read output from threads, so that the child never blocks on a full pipe
+    let mut stdout = child.stdout.take().unwrap();
+    let stdout_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).map(|_| buf)
+    });
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_reader = thread::spawn(move || {
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).map(|_| buf)
+    });
+
+    let status = match child.wait_timeout(timeout)? {
+        Some(status) => status,
+        None => {
+            child.kill()?;
+            child.wait()?;
+            anyhow::bail!("Command {:?} timed out after {:?}", cmd, timeout);
+        }
+    };
+
+    let stdout = stdout_reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("Failed to read output of {:?}", cmd))??;
+    let stderr = stderr_reader
+        .join()
+        .map_err(|_| anyhow::anyhow!("Failed to read output of {:?}", cmd))??;
+
+    Ok(Output {
+        status,
+        stdout,
+        stderr,
+    })
+}
+
+/// Number of attempts to run a pacman command that fails transiently, before giving up
+const PACMAN_ATTEMPTS: u32 = 3;
+
+/// Delay before retrying a pacman command, doubled after each failed attempt
+const PACMAN_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Pacman error message when another process holds the database lock, which is only transient
+const PACMAN_LOCKED_ERROR: &str = "unable to lock database";
+
+/// Run a command built by `cmd`, retrying with exponential backoff if it can not be started, or
+/// exits with a failure telling `is_transient` it may succeed later
+///
+/// Missing programs and timeouts are not retried, as running again would not help, or take as long.
+/// Other exit statuses are left to the caller, since many pacman queries exit with an error when
+/// they only find nothing.
+fn run_with_retries(
+    cmd: impl Fn() -> Command,
+    timeout: Duration,
+    attempts: u32,
+    retry_delay: Duration,
+    is_transient: impl Fn(&Output) -> bool,
+) -> anyhow::Result<Output> {
+    let mut delay = retry_delay;
+    let mut attempt = 1;
+    loop {
+        let failure = match run_with_timeout(&mut cmd(), timeout) {
+            Ok(output)
+                if attempt < attempts && !output.status.success() && is_transient(&output) =>
+            {
+                String::from_utf8_lossy(&output.stderr).trim().to_owned()
+            }
+            Ok(output) => return Ok(output),
+            Err(err)
+                if attempt < attempts
+                    && err
+                        .root_cause()
+                        .downcast_ref::<std::io::Error>()
+                        .is_some_and(|e| e.kind() != std::io::ErrorKind::NotFound) =>
+            {
+                format!("{:#}", err)
+            }
+            Err(err) => return Err(err),
+        };
+        debug!(
+            "Attempt {}/{} failed: {}, retrying in {:?}",
+            attempt, attempts, failure, delay
+        );
+        thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Default pacman database directory, in the install root
+const PACMAN_DB_DIR: &str = "/var/lib/pacman";
+
+/// Install root and pacman database directory of the checked system
+#[derive(Debug, Clone, Default)]
+pub struct SystemRoot {
+    /// Install root, like a chroot or container directory, None for the running system
+    pub root: Option<PathBuf>,
+
+    /// Pacman database directory, None for the default one of the install root
+    pub dbpath: Option<PathBuf>,
+}
+
+impl SystemRoot {
+    /// Get where an absolute path of the checked system is on the running one
+    fn path(&self, path: impl AsRef<Path>) -> PathBuf {
+        let path = path.as_ref();
+        match &self.root {
+            Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+            None => path.to_owned(),
+        }
+    }
+
+    /// Get the absolute path in the checked system of a path of the running one, reverse of `path`
+    fn logical_path(&self, path: &Path) -> PathBuf {
+        match self
+            .root
+            .as_ref()
+            .and_then(|root| path.strip_prefix(root).ok())
+        {
+            Some(relative_path) => Path::new("/").join(relative_path),
+            None => path.to_owned(),
+        }
+    }
+
+    /// Get the pacman database directory
+    fn db_dir(&self) -> PathBuf {
+        self.dbpath
+            .clone()
+            .unwrap_or_else(|| self.path(PACMAN_DB_DIR))
+    }
+
+    /// Get the lock file pacman creates in its database directory while it runs
+    pub fn db_lock_path(&self) -> PathBuf {
+        self.db_dir().join(PACMAN_DB_LOCK_PATH)
+    }
+}
+
+/// Run pacman on the checked system with C locale messages, retrying if it fails to start or the
+/// database is locked
+fn run_pacman(
+    system_root: &SystemRoot,
+    args: &[&str],
+    timeout: Duration,
+) -> anyhow::Result<Output> {
+    run_with_retries(
+        || {
+            let mut cmd = Command::new("pacman");
+            cmd.args(args).env("LANG", "C");
+            if let Some(root) = &system_root.root {
+                cmd.arg("--root").arg(root);
+            }
+            if let Some(dbpath) = &system_root.dbpath {
+                cmd.arg("--dbpath").arg(dbpath);
+            }
+            cmd
+        },
+        timeout,
+        PACMAN_ATTEMPTS,
+        PACMAN_RETRY_DELAY,
+        |output| String::from_utf8_lossy(&output.stderr).contains(PACMAN_LOCKED_ERROR),
+    )
+}
+
+/// Version of a pacman package
+struct PackageVersion {
+    major: u8,
+    minor: u8,
+    release: u8,
+    package: u8,
+}
+
+impl fmt::Display for PackageVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}-{}",
+            self.major, self.minor, self.release, self.package
+        )
+    }
+}
+
+/// Parse leading digits of a version component, tolerating suffixes like in `0rc1`
+fn parse_version_component(s: &str) -> anyhow::Result<u8> {
+    let digits_end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    Ok(u8::from_str(&s[..digits_end])?)
+}
+
+impl FromStr for PackageVersion {
+    type Err = anyhow::Error;
+
+    /// Parse a pacman package version like `[epoch:]major.minor[.release][-package]`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.split_once(':').map_or(s, |(_epoch, v)| v);
+        let (version, package) = s.split_once('-').unwrap_or((s, "0"));
+
+        let mut dot_iter = version.split('.');
+        let major = parse_version_component(
+            dot_iter
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing version major part"))?,
+        )?;
+        let minor = parse_version_component(
+            dot_iter
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing version minor part"))?,
+        )?;
+        let release = dot_iter
+            .next()
+            .map(parse_version_component)
+            .transpose()?
+            .unwrap_or(0);
+        let package = parse_version_component(package)?;
+
+        Ok(PackageVersion {
+            major,
+            minor,
+            release,
+            package,
+        })
+    }
+}
+
+/// Get version of an installed package, or None if it is not installed
+fn get_package_version(
+    system_root: &SystemRoot,
+    package: &str,
+) -> anyhow::Result<Option<PackageVersion>> {
+    let output = run_pacman(system_root, &["-Qi", package], COMMAND_TIMEOUT)?;
+
+    if !output.status.success() {
+        if String::from_utf8_lossy(&output.stderr).contains("was not found") {
+            return Ok(None);
+        }
+        anyhow::bail!(
+            "Failed to query version of package {:?} with pacman",
+            package
+        );
+    }
+
+    let version_line = output
+        .stdout
+        .lines()
+        .map_while(Result::ok)
+        .find(|l| l.starts_with("Version"))
+        .ok_or_else(|| anyhow::anyhow!("Unexpected pacman output: unable to find version line"))?;
+    let version_str = version_line
+        .split_once(':')
+        .ok_or_else(|| anyhow::anyhow!("Unexpected pacman output: unable to parse version line"))?
+        .1
+        .trim();
+
+    version_str.parse().map(Some).with_context(|| {
+        format!(
+            "Unexpected pacman output: unable to parse version {:?} of package {:?}",
+            version_str, package
+        )
+    })
+}
+
+/// Directory of pacman sync databases, in the database directory
+const PACMAN_SYNC_DB_DIR: &str = "sync";
+
+/// Directory of the pacman local database, changed by each transaction, in the database directory
+const PACMAN_LOCAL_DB_DIR: &str = "local";
+
+/// Pacman database lock file, present while a transaction runs, in the database directory
+const PACMAN_DB_LOCK_PATH: &str = "db.lck";
+
+/// Interval between two checks for interruption while waiting for local database changes in watch
+/// mode
+const WATCH_STOP_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time without local database change after which a transaction is considered done
+const WATCH_SETTLE_DELAY: Duration = Duration::from_secs(5);
+
+/// Wait until entries of a directory change, notified by the kernel, and then are left untouched and
+/// unlocked for a while, return false if stopped before
+fn wait_for_dir_change(
+    dir: &Path,
+    lock_path: &Path,
+    stop_check_interval: Duration,
+    settle_delay: Duration,
+    stop: &AtomicBool,
+) -> anyhow::Result<bool> {
+    let (events_tx, events_rx) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(events_tx).context("Failed to create file watcher")?;
+    watcher
+        .watch(dir, notify::RecursiveMode::NonRecursive)
+        .with_context(|| format!("Failed to watch {:?}", dir))?;
+
+    let mut last_change = None;
+    while !stop.load(Ordering::Relaxed) {
+        match events_rx.recv_timeout(stop_check_interval) {
+            // Reading the database, like scans do, is not a change
+            Ok(Ok(event)) if !event.kind.is_access() => last_change = Some(Instant::now()),
+            Ok(Ok(_)) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Ok(Err(err)) => return Err(err).with_context(|| format!("Failed to watch {:?}", dir)),
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("File watcher of {:?} stopped", dir)
+            }
+        }
+        if last_change.is_some_and(|t: Instant| t.elapsed() >= settle_delay) && !lock_path.exists()
+        {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Interval between two checks for the pacman database lock release
+const LOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Maximum time to wait for the pacman database lock release
+pub const LOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Check if the pacman database is locked by a running transaction
+pub fn pacman_database_locked(system_root: &SystemRoot) -> bool {
+    system_root.db_lock_path().exists()
+}
+
+/// Wait until a lock file is removed, return false if stopped or timed out before
+fn wait_for_lock_release(
+    lock_path: &Path,
+    poll_interval: Duration,
+    timeout: Duration,
+    stop: &AtomicBool,
+) -> bool {
+    let start = Instant::now();
+    while lock_path.exists() {
+        if stop.load(Ordering::Relaxed) || start.elapsed() >= timeout {
+            return false;
+        }
+        thread::sleep(poll_interval);
+    }
+    true
+}
+
+/// Wait until the pacman database lock is released, return false if stopped or timed out before
+pub fn wait_for_database_unlock(
+    system_root: &SystemRoot,
+    timeout: Duration,
+    stop: &AtomicBool,
+) -> bool {
+    wait_for_lock_release(
+        &system_root.db_lock_path(),
+        LOCK_POLL_INTERVAL,
+        timeout,
+        stop,
+    )
+}
+
+/// Wait until a pacman transaction changed installed packages and is done, return false if stopped
+/// before
+pub fn wait_for_local_database_change(
+    system_root: &SystemRoot,
+    stop: &AtomicBool,
+) -> anyhow::Result<bool> {
+    let db_dir = system_root.db_dir();
+    wait_for_dir_change(
+        &db_dir.join(PACMAN_LOCAL_DB_DIR),
+        &system_root.db_lock_path(),
+        WATCH_STOP_CHECK_INTERVAL,
+        WATCH_SETTLE_DELAY,
+        stop,
+    )
+}
+
+/// Timeout to download file databases, much longer than for local queries
+const SYNC_FILES_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Check if a directory contains at least one non empty file database
+fn files_databases_synced_in(sync_db_dir: &Path) -> bool {
+    fs::read_dir(sync_db_dir)
+        .map(|entries| {
+            entries.flatten().any(|e| {
+                e.path().extension() == Some(OsStr::new("files"))
+                    && e.metadata().is_ok_and(|m| m.len() > 0)
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Check if pacman file databases were downloaded, without them providers of missing libraries
+/// can not be found
+pub fn files_databases_synced(system_root: &SystemRoot) -> bool {
+    files_databases_synced_in(&system_root.db_dir().join(PACMAN_SYNC_DB_DIR))
+}
+
+/// Download pacman file databases, this requires root privileges
+pub fn sync_files_databases(system_root: &SystemRoot) -> anyhow::Result<()> {
+    let output = run_pacman(system_root, &["-Fy"], SYNC_FILES_TIMEOUT)?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "Failed to sync file databases with pacman: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+fn get_package_owning_path(system_root: &SystemRoot, path: &str) -> anyhow::Result<Vec<String>> {
+    query_file_databases(system_root, &["-Fq", path])
+}
+
+/// Parse owning packages by path from `pacman -Qo` output, directories without trailing slash
+fn parse_path_owners(pacman_output: &str) -> HashMap<String, Vec<String>> {
+    let mut owners: HashMap<String, Vec<String>> = HashMap::new();
+    for line in pacman_output.lines() {
+        let (path, owner) = match line.split_once(" is owned by ") {
+            Some(line) => line,
+            None => continue,
+        };
+        if let Some(package) = owner.split(' ').next() {
+            owners
+                .entry(path.trim_end_matches('/').to_owned())
+                .or_default()
+                .push(package.to_owned());
+        }
+    }
+    owners
+}
+
+/// Get installed packages owning paths, from the local database with a single pacman run, paths
+/// no package owns being left out
+fn get_local_packages_owning_paths(
+    system_root: &SystemRoot,
+    paths: &[String],
+) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    if paths.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    // pacman expects paths on the running system, below the root directory
+    let physical_paths: Vec<String> = paths
+        .iter()
+        .map(|p| system_root.path(p).to_string_lossy().into_owned())
+        .collect();
+    let args: Vec<&str> = std::iter::once("-Qo")
+        .chain(physical_paths.iter().map(String::as_str))
+        .collect();
+    let output = run_pacman(system_root, &args, COMMAND_TIMEOUT)?;
+
+    // pacman fails when no package owns one of the paths, but still reports the others
+    if !output.status.success() {
+        debug!(
+            "Some paths are not owned by any installed package: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(parse_path_owners(&String::from_utf8_lossy(&output.stdout))
+        .into_iter()
+        .map(|(path, packages)| {
+            let path = system_root.logical_path(Path::new(&path));
+            (path.to_string_lossy().into_owned(), packages)
+        })
+        .collect())
+}
+
+/// Get repository packages providing files, from a pacman file database query
+fn query_file_databases(system_root: &SystemRoot, args: &[&str]) -> anyhow::Result<Vec<String>> {
+    let output = run_pacman(system_root, args, COMMAND_TIMEOUT)?;
+
+    Ok(parse_file_databases_output(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Parse package names from `repository/package` lines of a file database query, skipping
+/// malformed lines
+fn parse_file_databases_output(pacman_output: &str) -> Vec<String> {
+    let mut packages: Vec<String> = pacman_output
+        .lines()
+        .filter_map(|l| match l.split_once('/') {
+            Some((_repo, package)) => Some(package.to_string()),
+            None => {
+                debug!("Skipping unexpected file database query line {:?}", l);
+                None
+            }
+        })
+        .collect();
+    packages.dedup();
+    packages
+}
+
+/// Escape characters with a special meaning in regular expressions
+fn escape_regex(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Get a regular expression matching paths of any version of a library, in any directory
+fn soname_versions_regex(soname: &str) -> String {
+    let base = soname.split_inclusive(".so").next().unwrap_or(soname);
+    format!(".*/{}\\..*", escape_regex(base))
+}
+
+/// Get repository packages providing a library, by exact file name first, or any versioned
+/// file of the library in any directory
+fn get_package_owning_soname(
+    system_root: &SystemRoot,
+    soname: &str,
+) -> anyhow::Result<Vec<String>> {
+    let packages = get_package_owning_path(system_root, soname)?;
+    if !packages.is_empty() {
+        return Ok(packages);
+    }
+    query_file_databases(system_root, &["-Fqx", &soname_versions_regex(soname)])
+}
+
+/// Cached query result, None until computed
+type QueryCacheEntry = Arc<Mutex<Option<Vec<String>>>>;
+
+/// Number of library provider queries after which all files of repository packages are indexed at
+/// once, instead of querying the file databases for each library
+const PROVIDER_INDEX_MIN_QUERIES: usize = 16;
+
+/// Timeout to list files of all repository packages
+const PROVIDER_INDEX_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Repository packages providing each shared object file name, from a single file database listing
+#[derive(Debug, Default)]
+struct ProviderIndex {
+    /// Packages by file name, sorted to find versioned names by prefix
+    providers: BTreeMap<String, Vec<String>>,
+}
+
+impl ProviderIndex {
+    /// Parse `package path` lines of a file database listing, only keeping shared objects
+    fn parse(pacman_output: &str) -> Self {
+        let mut index = Self::default();
+        for line in pacman_output.lines() {
+            let (package, path) = match line.split_once(' ') {
+                Some(parsed) => parsed,
+                None => continue,
+            };
+            let filename = path.rsplit('/').next().unwrap();
+            if !is_shared_object_name(Path::new(filename)) {
+                continue;
+            }
+            let packages = index.providers.entry(filename.to_owned()).or_default();
+            if !packages.iter().any(|p| p == package) {
+                packages.push(package.to_owned());
+            }
+        }
+        index
+    }
+
+    /// Get packages providing a library, by exact file name first, or any versioned file of the
+    /// library, like get_package_owning_soname does
+    fn get(&self, soname: &str) -> Vec<String> {
+        if let Some(packages) = self.providers.get(soname) {
+            return packages.clone();
+        }
+        let prefix = format!("{}.", soname);
+        let mut packages: Vec<String> = Vec::new();
+        for (_, name_packages) in self
+            .providers
+            .range(prefix.clone()..)
+            .take_while(|(n, _)| n.starts_with(&prefix))
+        {
+            for package in name_packages {
+                if !packages.contains(package) {
+                    packages.push(package.to_owned());
+                }
+            }
+        }
+        packages
+    }
+}
+
+/// Index shared objects of all repository packages, from the file databases
+fn build_provider_index(system_root: &SystemRoot) -> anyhow::Result<ProviderIndex> {
+    let output = run_pacman(system_root, &["-Fl"], PROVIDER_INDEX_TIMEOUT)?;
+    if !output.status.success() {
+        anyhow::bail!("Failed to list files of repository packages with pacman");
+    }
+    Ok(ProviderIndex::parse(&String::from_utf8_lossy(
+        &output.stdout,
+    )))
+}
+
+/// Library provider lookups, querying the file databases for each library at first, then indexing
+/// all their files once enough libraries were looked up
+#[derive(Default)]
+struct ProviderLookup {
+    /// Number of libraries looked up so far
+    query_count: AtomicUsize,
+
+    /// Index of all repository shared objects, None if it could not be built
+    index: OnceLock<Option<ProviderIndex>>,
+}
+
+impl ProviderLookup {
+    /// Get repository packages providing a library
+    fn get(&self, system_root: &SystemRoot, soname: &str) -> anyhow::Result<Vec<String>> {
+        if self.query_count.fetch_add(1, Ordering::Relaxed) < PROVIDER_INDEX_MIN_QUERIES {
+            return get_package_owning_soname(system_root, soname);
+        }
+        let index = self.index.get_or_init(|| {
+            debug!("Indexing shared objects of all repository packages");
+            build_provider_index(system_root)
+                .map_err(|err| debug!("Falling back to querying each library: {}", err))
+                .ok()
+        });
+        match index {
+            Some(index) => Ok(index.get(soname)),
+            None => get_package_owning_soname(system_root, soname),
+        }
+    }
+}
+
+/// Thread safe memoization of query results, each key being computed only once
+#[derive(Default)]
+struct QueryCache {
+    entries: Mutex<HashMap<String, QueryCacheEntry>>,
+}
+
+impl QueryCache {
+    /// Get cached result for key, or compute it while other threads querying the same key wait
+    fn get_or_try_insert_with<F>(&self, key: &str, f: F) -> anyhow::Result<Vec<String>>
+    where
+        F: FnOnce() -> anyhow::Result<Vec<String>>,
+    {
+        let entry = Arc::clone(
+            self.entries
+                .lock()
+                .unwrap()
+                .entry(key.to_owned())
+                .or_default(),
+        );
+        let mut entry = entry.lock().unwrap();
+        if let Some(value) = entry.as_ref() {
+            trace!("Cache hit for {:?}", key);
+            return Ok(value.clone());
+        }
+        let value = f()?;
+        *entry = Some(value.clone());
+        Ok(value)
+    }
+
+    /// Create a cache already holding results
+    fn with_entries(entries: HashMap<String, Vec<String>>) -> Self {
+        Self {
+            entries: Mutex::new(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, Arc::new(Mutex::new(Some(v)))))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Get all computed results
+    fn computed_entries(&self) -> HashMap<String, Vec<String>> {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(k, v)| v.lock().unwrap().clone().map(|v| (k.to_owned(), v)))
+            .collect()
+    }
+}
+
+/// Library providers found in the file databases, saved across runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct ProviderCache {
+    /// Modification time of the newest file database when the providers were queried
+    files_db_timestamp: u64,
+
+    /// Packages providing each queried library
+    providers: HashMap<String, Vec<String>>,
+}
+
+/// Get modification time of the newest file database in a directory, in seconds since the epoch
+fn files_databases_timestamp_in(sync_db_dir: &Path) -> Option<u64> {
+    fs::read_dir(sync_db_dir)
+        .ok()?
+        .flatten()
+        .filter(|e| e.path().extension() == Some(OsStr::new("files")))
+        .filter_map(|e| e.metadata().and_then(|m| m.modified()).ok())
+        .filter_map(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .max()
+}
+
+/// Load library providers saved for the current file databases, empty if the cache is missing or
+/// outdated
+fn load_provider_cache(path: &Path, files_db_timestamp: u64) -> HashMap<String, Vec<String>> {
+    let cache: ProviderCache = match fs::read(path)
+        .map_err(anyhow::Error::from)
+        .and_then(|d| serde_json::from_slice(&d).map_err(anyhow::Error::from))
+    {
+        Ok(cache) => cache,
+        Err(err) => {
+            debug!("Unable to load provider cache {:?}: {}", path, err);
+            return HashMap::new();
+        }
+    };
+    if cache.files_db_timestamp != files_db_timestamp {
+        debug!("Provider cache {:?} is outdated", path);
+        return HashMap::new();
+    }
+    cache.providers
+}
+
+/// Save library providers queried for the current file databases
+fn save_provider_cache(
+    path: &Path,
+    files_db_timestamp: u64,
+    providers: HashMap<String, Vec<String>>,
+) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {:?}", parent))?;
+    }
+    let cache = ProviderCache {
+        files_db_timestamp,
+        providers,
+    };
+    fs::write(path, serde_json::to_vec(&cache)?)
+        .with_context(|| format!("Failed to write file {:?}", path))
+}
+
+/// Interpreter or toolkit installing packages in version stamped directories
+struct VersionedRuntime {
+    /// Interpreter or toolkit name
+    name: &'static str,
+
+    /// Package providing the interpreter
+    package: &'static str,
+
+    /// Packages providing other versions of the interpreter, that can be installed in parallel
+    parallel_packages: &'static [&'static str],
+
+    /// Get versioned directory glob prefixes, and current directories, for an interpreter version
+    dirs: fn(&SystemRoot, &PackageVersion) -> anyhow::Result<RuntimeDirs>,
+}
+
+/// Versioned directory glob prefixes of an interpreter, with their current directories
+type RuntimeDirs = Vec<(String, String)>;
+
+fn python_dirs(_system_root: &SystemRoot, version: &PackageVersion) -> anyhow::Result<RuntimeDirs> {
+    Ok(vec![(
+        format!("/usr/lib/python{}", version.major),
+        format!("/usr/lib/python{}.{}", version.major, version.minor),
+    )])
+}
+
+/// Library directory of the PyPy 3 interpreters, followed by the implemented Python version
+const PYPY3_LIB_DIR: &str = "/opt/pypy3/lib";
+
+/// Get the versioned PyPy 3 library directory among the interpreter package files
+fn pypy3_current_dir(system_root: &SystemRoot, files: &[PathBuf]) -> Option<String> {
+    files.iter().find_map(|file| {
+        let name = system_root
+            .logical_path(file)
+            .strip_prefix(PYPY3_LIB_DIR)
+            .ok()?
+            .components()
+            .next()?
+            .as_os_str()
+            .to_str()?
+            .to_owned();
+        name.starts_with("pypy3.")
+            .then(|| format!("{}/{}", PYPY3_LIB_DIR, name))
+    })
+}
+
+/// The pypy3 package version is the PyPy one, so the implemented Python version is found from the
+/// directory the package itself installs
+fn pypy3_dirs(system_root: &SystemRoot, _version: &PackageVersion) -> anyhow::Result<RuntimeDirs> {
+    let files = get_packages_files(system_root, &["pypy3".to_owned()])
+        .context("Failed to list files of package \"pypy3\"")?
+        .remove("pypy3")
+        .unwrap_or_default();
+    Ok(pypy3_current_dir(system_root, &files)
+        .map(|current_dir| (format!("{}/pypy3.", PYPY3_LIB_DIR), current_dir))
+        .into_iter()
+        .collect())
+}
+
+fn perl_dirs(_system_root: &SystemRoot, version: &PackageVersion) -> anyhow::Result<RuntimeDirs> {
+    Ok(vec![(
+        format!("/usr/lib/perl5/{}.", version.major),
+        format!("/usr/lib/perl5/{}.{}", version.major, version.minor),
+    )])
+}
+
+fn ruby_dirs(_system_root: &SystemRoot, version: &PackageVersion) -> anyhow::Result<RuntimeDirs> {
+    Ok(vec![
+        (
+            format!("/usr/lib/ruby/{}.", version.major),
+            format!("/usr/lib/ruby/{}.{}.0", version.major, version.minor),
+        ),
+        (
+            format!("/usr/lib/ruby/gems/{}.", version.major),
+            format!("/usr/lib/ruby/gems/{}.{}.0", version.major, version.minor),
+        ),
+    ])
+}
+
+fn lua_dirs(_system_root: &SystemRoot, version: &PackageVersion) -> anyhow::Result<RuntimeDirs> {
+    Ok(vec![(
+        format!("/usr/lib/lua/{}.", version.major),
+        format!("/usr/lib/lua/{}.{}", version.major, version.minor),
+    )])
+}
+
+/// Qt plugin directory for a major version, Qt 5 uses the unversioned one
+fn qt_plugin_dir(major: u8) -> String {
+    if major <= 5 {
+        "/usr/lib/qt/plugins".to_owned()
+    } else {
+        format!("/usr/lib/qt{}/plugins", major)
+    }
+}
+
+fn qt_dirs(_system_root: &SystemRoot, version: &PackageVersion) -> anyhow::Result<RuntimeDirs> {
+    let current_dir = qt_plugin_dir(version.major);
+    Ok((5..=version.major)
+        .map(|major| (qt_plugin_dir(major), current_dir.clone()))
+        .collect())
+}
+
+fn gtk_dirs(_system_root: &SystemRoot, version: &PackageVersion) -> anyhow::Result<RuntimeDirs> {
+    Ok(vec![(
+        "/usr/lib/gtk-".to_owned(),
+        format!("/usr/lib/gtk-{}.0", version.major),
+    )])
+}
+
+/// Interpreters and toolkits to check for packages in outdated directories
+const VERSIONED_RUNTIMES: [VersionedRuntime; 8] = [
+    VersionedRuntime {
+        name: "Python",
+        package: "python",
+        parallel_packages: &[],
+        dirs: python_dirs,
+    },
+    VersionedRuntime {
+        name: "Python 2",
+        package: "python2",
+        parallel_packages: &[],
+        dirs: python_dirs,
+    },
+    VersionedRuntime {
+        name: "PyPy",
+        package: "pypy3",
+        parallel_packages: &[],
+        dirs: pypy3_dirs,
+    },
+    VersionedRuntime {
+        name: "Perl",
+        package: "perl",
+        parallel_packages: &[],
+        dirs: perl_dirs,
+    },
+    VersionedRuntime {
+        name: "Ruby",
+        package: "ruby",
+        parallel_packages: &[],
+        dirs: ruby_dirs,
+    },
+    VersionedRuntime {
+        name: "Lua",
+        package: "lua",
+        parallel_packages: &["lua51", "lua52", "lua53"],
+        dirs: lua_dirs,
+    },
+    VersionedRuntime {
+        name: "Qt",
+        package: "qt6-base",
+        parallel_packages: &["qt5-base"],
+        dirs: qt_dirs,
+    },
+    VersionedRuntime {
+        name: "GTK",
+        package: "gtk4",
+        parallel_packages: &["gtk3", "gtk2"],
+        dirs: gtk_dirs,
+    },
+];
+
+/// Get unique packages and directories they own among stranded directories, in directory order
+fn stranded_dirs_packages(
+    stranded_dirs: &[String],
+    dirs_owners: &HashMap<String, Vec<String>>,
+) -> Vec<(String, String)> {
+    let mut seen = HashSet::new();
+    let mut packages = Vec::new();
+    for dir in stranded_dirs {
+        for package in dirs_owners.get(dir).into_iter().flatten() {
+            let couple = (package.to_owned(), dir.to_owned());
+            if seen.insert(couple.clone()) {
+                packages.push(couple);
+            }
+        }
+    }
+    packages
+}
+
+/// Get packages owning files in directories matching a prefix, other than the current version ones
+///
+/// `dirs_owners` caches packages owning directories already queried, since prefixes of several
+/// runtimes can match the same directories.
+fn check_versioned_libdir(
+    system_root: &SystemRoot,
+    prefix: &str,
+    current_version_dirs: &[String],
+    dirs_owners: &mut HashMap<String, Vec<String>>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut stranded_dirs = Vec::new();
+
+    let pattern = format!(
+        "{}*",
+        glob::Pattern::escape(&system_root.path(prefix).to_string_lossy())
+    );
+    for dir_entry in glob(&pattern)? {
+        let dir = system_root
+            .logical_path(&dir_entry?)
+            .into_os_string()
+            .into_string()
+            .map_err(|_| anyhow::anyhow!("Failed to convert OS string to native string"))?;
+
+        if !current_version_dirs.contains(&dir) {
+            stranded_dirs.push(dir);
+        }
+    }
+
+    // Files left in outdated directories belong to installed packages, query owners of all
+    // directories not already known from the local database at once
+    let unknown_dirs: Vec<String> = stranded_dirs
+        .iter()
+        .filter(|d| !dirs_owners.contains_key(*d))
+        .cloned()
+        .collect();
+    let mut unknown_dirs_owners = get_local_packages_owning_paths(system_root, &unknown_dirs)?;
+    for dir in unknown_dirs {
+        let owners = unknown_dirs_owners.remove(&dir).unwrap_or_default();
+        dirs_owners.insert(dir, owners);
+    }
+
+    Ok(stranded_dirs_packages(&stranded_dirs, dirs_owners))
+}
+
+/// Get packages with files ignored by the current version of an interpreter, if it is installed
+fn get_broken_runtime_packages(
+    system_root: &SystemRoot,
+    runtime: &VersionedRuntime,
+    dirs_owners: &mut HashMap<String, Vec<String>>,
+) -> anyhow::Result<Vec<BrokenRuntimePackage>> {
+    let version = match get_package_version(system_root, runtime.package)? {
+        Some(version) => version,
+        None => return Ok(Vec::new()),
+    };
+    debug!("{} version: {}", runtime.name, version);
+
+    let dirs = (runtime.dirs)(system_root, &version)?;
+    let mut current_version_dirs: Vec<String> = dirs.iter().map(|(_, d)| d.to_owned()).collect();
+    for parallel_package in runtime.parallel_packages {
+        if let Some(parallel_version) = get_package_version(system_root, parallel_package)? {
+            current_version_dirs.extend(
+                (runtime.dirs)(system_root, &parallel_version)?
+                    .into_iter()
+                    .map(|(_, d)| d),
+            );
+        }
+    }
+
+    let mut packages = Vec::new();
+    for (prefix, _) in &dirs {
+        packages.extend(
+            check_versioned_libdir(system_root, prefix, &current_version_dirs, dirs_owners)?
+                .into_iter()
+                .map(|(package, dir)| BrokenRuntimePackage {
+                    runtime: runtime.name.to_owned(),
+                    package,
+                    dir,
+                }),
+        );
+    }
+
+    Ok(packages)
+}
+
+/// Get user pip packages and pipx virtual environments installed for another Python version than
+/// the current one, under a home directory
+fn get_stranded_pip_packages_in(
+    home: &Path,
+    version: &PackageVersion,
+) -> anyhow::Result<Vec<BrokenRuntimePackage>> {
+    let home = glob::Pattern::escape(&home.to_string_lossy());
+    let current_dir_name = format!("python{}.{}", version.major, version.minor);
+    let is_stranded = |dir: &Path| dir.file_name().is_some_and(|n| *n != *current_dir_name);
+    let mut packages = Vec::new();
+
+    // Packages installed with pip install --user, named after their metadata directories
+    let pattern = format!("{}/.local/lib/python{}.*", home, version.major);
+    for dir in glob(&pattern)? {
+        let dir = dir?;
+        if !is_stranded(&dir) {
+            continue;
+        }
+        let site_packages_dir = glob::Pattern::escape(&dir.join("site-packages").to_string_lossy());
+        for metadata_dir in glob(&format!("{}/*.dist-info", site_packages_dir))?
+            .chain(glob(&format!("{}/*.egg-info", site_packages_dir))?)
+        {
+            let metadata_dir = metadata_dir?;
+            // Names can contain dots, like zope.interface, but dashes are escaped before the version
+            if let Some(package) = metadata_dir
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| {
+                    n.strip_suffix(".dist-info")
+                        .or_else(|| n.strip_suffix(".egg-info"))
+                })
+                .and_then(|n| n.split('-').next())
+            {
+                packages.push(BrokenRuntimePackage {
+                    runtime: "Python".to_owned(),
+                    package: package.to_owned(),
+                    dir: dir.to_string_lossy().into_owned(),
+                });
+            }
+        }
+    }
+
+    // Applications installed with pipx, named after their virtual environments
+    let pattern = format!(
+        "{}/.local/share/pipx/venvs/*/lib/python{}.*",
+        home, version.major
+    );
+    for dir in glob(&pattern)? {
+        let dir = dir?;
+        if !is_stranded(&dir) {
+            continue;
+        }
+        if let Some(package) = dir
+            .parent()
+            .and_then(Path::parent)
+            .and_then(Path::file_name)
+        {
+            packages.push(BrokenRuntimePackage {
+                runtime: "Python".to_owned(),
+                package: package.to_string_lossy().into_owned(),
+                dir: dir.to_string_lossy().into_owned(),
+            });
+        }
+    }
+
+    packages.sort_by(|a, b| (&a.package, &a.dir).cmp(&(&b.package, &b.dir)));
+    packages.dedup();
+    Ok(packages)
+}
+
+/// Get user pip and pipx packages installed for another Python version than the current one, if
+/// Python is installed
+pub fn check_pip_packages(
+    system_root: &SystemRoot,
+    home: &Path,
+) -> anyhow::Result<Vec<BrokenRuntimePackage>> {
+    match get_package_version(system_root, "python")? {
+        Some(version) => get_stranded_pip_packages_in(home, &version),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Get names of installed packages matching a pacman query filter option
+fn get_package_names(system_root: &SystemRoot, filter: &str) -> anyhow::Result<Vec<String>> {
+    let output = run_pacman(system_root, &["-Qq", filter], COMMAND_TIMEOUT)?;
+
+    Ok(output
+        .stdout
+        .lines()
+        .collect::<Result<Vec<String>, std::io::Error>>()?)
+}
+
+/// Get packages not installed from a repository, usually from the AUR
+pub fn get_aur_packages(system_root: &SystemRoot) -> anyhow::Result<Vec<String>> {
+    get_package_names(system_root, "-m")
+}
+
+/// Get packages installed from the official repositories
+pub fn get_official_packages(system_root: &SystemRoot) -> anyhow::Result<Vec<String>> {
+    get_package_names(system_root, "-n")
+}
+
+fn parse_required_by(pacman_output: &str) -> Vec<String> {
+    let mut packages = Vec::new();
+    let mut in_field = false;
+    for line in pacman_output.lines() {
+        if let Some(value) = line.strip_prefix("Required By") {
+            in_field = true;
+            packages.extend(
+                value
+                    .trim_start()
+                    .trim_start_matches(':')
+                    .split_whitespace()
+                    .map(|p| p.to_string()),
+            );
+        } else if in_field && line.starts_with(char::is_whitespace) {
+            // Continuation line of a wrapped field
+            packages.extend(line.split_whitespace().map(|p| p.to_string()));
+        } else {
+            in_field = false;
+        }
+    }
+    packages.retain(|p| p != "None");
+    packages
+}
+
+/// Parse the reverse dependencies of each package described by `pacman -Qi`, in blocks separated by
+/// empty lines
+fn parse_packages_required_by(pacman_output: &str) -> HashMap<String, Vec<String>> {
+    pacman_output
+        .split("\n\n")
+        .filter_map(|block| {
+            let name = block.lines().find_map(|line| {
+                let value = line.strip_prefix("Name")?.trim_start().strip_prefix(':')?;
+                Some(value.trim().to_string())
+            })?;
+            Some((name, parse_required_by(block)))
+        })
+        .collect()
+}
+
+/// Get installed packages requiring each package, with a single pacman run
+///
+/// Packages that are not installed are missing from the returned map.
+pub fn get_reverse_deps(
+    system_root: &SystemRoot,
+    packages: &[String],
+) -> anyhow::Result<HashMap<String, Vec<String>>> {
+    if packages.is_empty() {
+        // pacman would describe all installed packages
+        return Ok(HashMap::new());
+    }
+
+    let args: Vec<&str> = std::iter::once("-Qi")
+        .chain(packages.iter().map(String::as_str))
+        .collect();
+    let timeout = COMMAND_TIMEOUT + INFO_TIMEOUT_PER_PACKAGE * packages.len() as u32;
+    let output = run_pacman(system_root, &args, timeout)?;
+    let reverse_deps = parse_packages_required_by(&String::from_utf8_lossy(&output.stdout));
+
+    // Pacman also fails if only some packages are not installed
+    if !output.status.success() && reverse_deps.is_empty() {
+        anyhow::bail!(
+            "Failed to query reverse dependencies of packages {:?} with pacman: {}",
+            packages,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(reverse_deps)
+}
+
+/// Get the reverse dependencies of packages, and recursively of their reverse dependencies, querying
+/// `get_reverse_deps` for each level of newly found packages
+///
+/// All visited packages are keys of the returned map, with their sorted reverse dependencies.
+fn reverse_deps_closure(
+    packages: &[String],
+    mut get_reverse_deps: impl FnMut(&[String]) -> HashMap<String, Vec<String>>,
+) -> HashMap<String, Vec<String>> {
+    let mut closure = HashMap::<String, Vec<String>>::new();
+    let mut level = packages.to_vec();
+    level.sort();
+    level.dedup();
+    while !level.is_empty() {
+        let mut level_reverse_deps = get_reverse_deps(&level);
+        let mut next_level = Vec::new();
+        for package in level {
+            let mut reverse_deps = level_reverse_deps.remove(&package).unwrap_or_default();
+            reverse_deps.sort();
+            reverse_deps.dedup();
+            next_level.extend(reverse_deps.iter().cloned());
+            closure.insert(package, reverse_deps);
+        }
+        next_level.sort();
+        next_level.dedup();
+        next_level.retain(|p| !closure.contains_key(p));
+        level = next_level;
+    }
+    closure
+}
+
+/// Get packages and all their direct and indirect reverse dependencies
+pub fn get_reverse_deps_closure(system_root: &SystemRoot, packages: &[String]) -> HashSet<String> {
+    reverse_deps_closure(packages, |level| {
+        get_reverse_deps(system_root, level).unwrap_or_else(|err| {
+            debug!("{}", err);
+            HashMap::new()
+        })
+    })
+    .into_keys()
+    .collect()
+}
+
+/// Pacman log file
+const PACMAN_LOG_PATH: &str = "/var/log/pacman.log";
+
+/// Pacman log actions changing the files of a package
+const PACMAN_LOG_CHANGE_ACTIONS: [&str; 4] = ["installed", "upgraded", "reinstalled", "downgraded"];
+
+/// Local date and time, as year, month, day, hour, minute and second, ordered chronologically
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LogTimestamp([u32; 6]);
+
+impl FromStr for LogTimestamp {
+    type Err = anyhow::Error;
+
+    /// Parse a pacman log timestamp like `2024-01-31T18:05:12+0100` or `2019-03-01 12:34`, the time
+    /// being optional and the UTC offset ignored
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (date, time) = s.split_once(['T', ' ']).unwrap_or((s, ""));
+        let time = time
+            .split(['+', '-', 'Z'])
+            .next()
+            .unwrap_or_default()
+            .trim();
+
+        let mut fields = [0; 6];
+        let date_fields: Vec<&str> = date.split('-').collect();
+        let time_fields: Vec<&str> = if time.is_empty() {
+            Vec::new()
+        } else {
+            time.split(':').collect()
+        };
+        if date_fields.len() != 3 || time_fields.len() == 1 || time_fields.len() > 3 {
+            anyhow::bail!(
+                "Invalid timestamp {:?}, expected YYYY-MM-DD[THH:MM[:SS]]",
+                s
+            );
+        }
+        for (field, value) in fields
+            .iter_mut()
+            .zip(date_fields.into_iter().chain(time_fields))
+        {
+            *field = value
+                .parse()
+                .with_context(|| format!("Invalid timestamp {:?}", s))?;
+        }
+        if !(1..=12).contains(&fields[1]) || !(1..=31).contains(&fields[2]) {
+            anyhow::bail!("Invalid date in timestamp {:?}", s);
+        }
+        Ok(Self(fields))
+    }
+}
+
+/// Parse names of packages installed or upgraded at or after a time from pacman log content
+fn parse_pacman_log_changes(log: &str, since: LogTimestamp) -> HashSet<String> {
+    let mut packages = HashSet::new();
+    for line in log.lines() {
+        let (timestamp, entry) = match line.strip_prefix('[').and_then(|l| l.split_once("] ")) {
+            Some(parsed) => parsed,
+            None => continue,
+        };
+        match LogTimestamp::from_str(timestamp) {
+            Ok(timestamp) if timestamp >= since => {}
+            _ => continue,
+        }
+        // Old logs have no [ALPM] tag
+        let entry = entry.strip_prefix("[ALPM] ").unwrap_or(entry);
+        let mut words = entry.split_whitespace();
+        if let (Some(action), Some(package)) = (words.next(), words.next()) {
+            if PACMAN_LOG_CHANGE_ACTIONS.contains(&action) {
+                packages.insert(package.to_string());
+            }
+        }
+    }
+    packages
+}
+
+/// Get packages installed or upgraded at or after a time, according to the pacman log
+pub fn get_packages_changed_since(
+    system_root: &SystemRoot,
+    since: LogTimestamp,
+) -> anyhow::Result<HashSet<String>> {
+    let log_path = system_root.path(PACMAN_LOG_PATH);
+    let log = fs::read(&log_path).with_context(|| format!("Failed to read {:?}", log_path))?;
+    Ok(parse_pacman_log_changes(
+        &String::from_utf8_lossy(&log),
+        since,
+    ))
+}
+
+/// Parse names of packages reported as not found in pacman error output
+fn parse_not_found_packages(pacman_stderr: &str) -> Vec<String> {
+    pacman_stderr
+        .lines()
+        .filter_map(|l| {
+            l.strip_prefix("error: package '")
+                .and_then(|l| l.strip_suffix("' was not found"))
+        })
+        .map(|p| p.to_string())
+        .collect()
+}
+
+/// Get packages that are not installed among the given ones
+pub fn get_uninstalled_packages(
+    system_root: &SystemRoot,
+    packages: &[String],
+) -> anyhow::Result<Vec<String>> {
+    let args: Vec<&str> = std::iter::once("-Q")
+        .chain(packages.iter().map(String::as_str))
+        .collect();
+    let output = run_pacman(system_root, &args, COMMAND_TIMEOUT)?;
+
+    let uninstalled_packages = parse_not_found_packages(&String::from_utf8_lossy(&output.stderr));
+    if !output.status.success() && uninstalled_packages.is_empty() {
+        anyhow::bail!("Failed to query installed packages with pacman");
+    }
+
+    Ok(uninstalled_packages)
+}
+
+/// Files by package
+pub type PackagesFiles = HashMap<String, Vec<PathBuf>>;
+
+/// Parse file paths by package from `pacman -Ql` output, paths may not be valid UTF-8
+fn parse_package_file_list(pacman_output: &[u8]) -> HashMap<String, Vec<PathBuf>> {
+    let mut files: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for line in pacman_output.split(|&b| b == b'\n') {
+        if let Some(i) = line.iter().position(|&b| b == b' ') {
+            files
+                .entry(String::from_utf8_lossy(&line[..i]).into_owned())
+                .or_default()
+                .push(PathBuf::from(OsStr::from_bytes(&line[i + 1..])));
+        }
+    }
+    files
+}
+
+/// Check if a file name looks like a shared object, ie. "libfoo.so" or a versioned "libfoo.so.1.2"
+fn is_shared_object_name(path: &Path) -> bool {
+    let name = match path.file_name() {
+        Some(name) => name.as_bytes(),
+        None => return false,
+    };
+    name.windows(3).enumerate().any(|(i, w)| {
+        w == b".so"
+            && name[i + 3..]
+                .split(|&c| c == b'.')
+                .skip(1)
+                .all(|v| !v.is_empty() && v.iter().all(u8::is_ascii_digit))
+            && name[i + 3..].first().is_none_or(|&c| c == b'.')
+    })
+}
+
+/// Directory of PAM modules
+const PAM_MODULE_DIR: &str = "/usr/lib/security";
+
+/// Directories of NSS plugins
+const NSS_PLUGIN_DIRS: [&str; 2] = ["/usr/lib", "/usr/lib32"];
+
+/// Check if a file is a PAM module or an NSS plugin, loaded by programs handling authentication
+fn is_security_module(system_root: &SystemRoot, path: &Path) -> bool {
+    let path = system_root.logical_path(path);
+    let (dir, name) = match (path.parent(), path.file_name()) {
+        (Some(dir), Some(name)) => (dir, name.to_string_lossy()),
+        _ => return false,
+    };
+    is_shared_object_name(&path)
+        && ((dir == Path::new(PAM_MODULE_DIR) && name.starts_with("pam_"))
+            || (NSS_PLUGIN_DIRS.iter().any(|d| dir == Path::new(d)) && name.starts_with("libnss_")))
+}
+
+/// Keep executable files and shared objects, resolved to their real paths in the checked system,
+/// each file only once even if it is reachable through several symlinks
+fn filter_linked_files(system_root: &SystemRoot, files: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    files
+        .into_iter()
+        .filter_map(|p| canonicalize_in(system_root, &p).ok())
+        .filter(|p| seen.insert(p.clone()))
+        .filter(|p| {
+            fs::metadata(p)
+                .map(|m| {
+                    m.file_type().is_file()
+                        && ((m.permissions().mode() & 0o111) != 0
+                            || (is_shared_object_name(p) && is_elf_file(p)))
+                })
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+/// Parse packages pacman failed to list from `pacman -Ql` error output
+fn parse_package_file_list_errors(pacman_stderr: &str) -> Vec<UnlistedPackage> {
+    pacman_stderr
+        .lines()
+        .filter_map(|l| l.strip_prefix("error: "))
+        .filter_map(|error| {
+            let package = error.strip_prefix("package '")?.split('\'').next()?;
+            Some(UnlistedPackage {
+                package: package.to_owned(),
+                error: error.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// List files of all packages with a single pacman invocation, with the packages that could not
+/// be listed
+pub fn list_packages_files(
+    system_root: &SystemRoot,
+    packages: &[String],
+) -> anyhow::Result<(PackagesFiles, Vec<UnlistedPackage>)> {
+    if packages.is_empty() {
+        // pacman would list files of all installed packages
+        return Ok((HashMap::new(), Vec::new()));
+    }
+
+    let args: Vec<&str> = std::iter::once("-Ql")
+        .chain(packages.iter().map(String::as_str))
+        .collect();
+    let timeout = COMMAND_TIMEOUT + FILES_LISTING_TIMEOUT_PER_PACKAGE * packages.len() as u32;
+    let output = run_pacman(system_root, &args, timeout)?;
+
+    // Pacman lists the packages it finds even if it fails for some others
+    let unlisted = if output.status.success() {
+        Vec::new()
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let unlisted = parse_package_file_list_errors(&stderr);
+        if unlisted.is_empty() {
+            anyhow::bail!(
+                "Failed to list files for packages with pacman: {}",
+                stderr.trim()
+            );
+        }
+        unlisted
+    };
+
+    Ok((parse_package_file_list(&output.stdout), unlisted))
+}
+
+/// List files of all packages with a single pacman invocation, skipping the ones that could not
+/// be listed
+fn get_packages_files(
+    system_root: &SystemRoot,
+    packages: &[String],
+) -> anyhow::Result<HashMap<String, Vec<PathBuf>>> {
+    let (packages_files, unlisted) = list_packages_files(system_root, packages)?;
+    for unlisted_package in unlisted {
+        debug!("{}", unlisted_package);
+    }
+    Ok(packages_files)
+}
+
+/// Get files of all installed packages
+fn get_installed_packages_files(
+    system_root: &SystemRoot,
+) -> anyhow::Result<HashMap<String, Vec<PathBuf>>> {
+    let output = run_pacman(system_root, &["-Ql"], COMMAND_TIMEOUT)?;
+
+    if !output.status.success() {
+        anyhow::bail!("Failed to list files of installed packages with pacman");
+    }
+
+    Ok(parse_package_file_list(&output.stdout))
+}
+
+/// Get shared objects provided by several packages directly in library directories, 32-bit ones
+/// being compared separately as the dynamic loader skips objects of the other class
+fn find_duplicate_sonames(
+    system_root: &SystemRoot,
+    packages_files: &HashMap<String, Vec<PathBuf>>,
+    lib_dirs: &[PathBuf],
+) -> Vec<DuplicateSoname> {
+    let lib32_dirs: Vec<PathBuf> = LIB32_DIRS.iter().map(|d| system_root.path(d)).collect();
+    let mut providers = BTreeMap::<(String, bool), BTreeMap<String, Vec<PathBuf>>>::new();
+    for (package, files) in packages_files {
+        for file in files.iter().filter(|f| is_shared_object_name(f)) {
+            let dir = match file.parent() {
+                Some(dir) if lib_dirs.iter().any(|d| d == dir) => dir,
+                _ => continue,
+            };
+            let soname = file.file_name().unwrap().to_string_lossy().into_owned();
+            let is_lib32 = lib32_dirs.iter().any(|d| d == dir);
+            providers
+                .entry((soname, is_lib32))
+                .or_default()
+                .entry(package.to_owned())
+                .or_default()
+                .push(file.to_owned());
+        }
+    }
+    providers
+        .into_iter()
+        .filter(|(_, providers)| providers.len() > 1)
+        .map(|((soname, _), providers)| DuplicateSoname { soname, providers })
+        .collect()
+}
+
+/// Check installed packages for sonames provided by more than one of them, in the dynamic loader
+/// and additional library directories
+pub fn check_duplicate_sonames(
+    system_root: &SystemRoot,
+    extra_lib_dirs: &[PathBuf],
+) -> anyhow::Result<Vec<DuplicateSoname>> {
+    let lib_dirs = library_search_paths(system_root, extra_lib_dirs)
+        .context("Unable to get library search paths")?;
+    let packages_files = get_installed_packages_files(system_root)
+        .context("Unable to list files of installed packages")?;
+    Ok(find_duplicate_sonames(
+        system_root,
+        &packages_files,
+        &lib_dirs,
+    ))
+}
+
+/// Default library directories searched by the dynamic loader
+const DEFAULT_LIB_DIRS: [&str; 2] = ["/usr/lib", "/usr/lib64"];
+
+/// Library directories searched for 32-bit objects on multilib systems
+const LIB32_DIRS: [&str; 1] = ["/usr/lib32"];
+
+/// Expansion of $LIB in RPATH/RUNPATH for 64-bit and 32-bit objects, as configured in Arch Linux glibc
+const LIB_DST_64: &str = "lib";
+const LIB_DST_32: &str = "lib32";
+
+/// Dynamic loader configuration file
+const LD_SO_CONF_PATH: &str = "/etc/ld.so.conf";
+
+/// Directory of dynamic loader configuration files, next to the main file, read by ldconfig even
+/// if not included
+const LD_SO_CONF_DIR_NAME: &str = "ld.so.conf.d";
+
+/// Parse a dynamic loader configuration file, following include directives
+fn parse_ld_so_conf(
+    system_root: &SystemRoot,
+    conf_path: &Path,
+    dirs: &mut Vec<PathBuf>,
+    visited: &mut HashSet<PathBuf>,
+) -> anyhow::Result<()> {
+    if !visited.insert(conf_path.to_owned()) {
+        return Ok(());
+    }
+    let conf =
+        fs::read_to_string(conf_path).with_context(|| format!("Failed to read {:?}", conf_path))?;
+    let conf_dir = conf_path.parent().unwrap_or_else(|| Path::new("/"));
+
+    for line in conf.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        if let Some(pattern) = line.strip_prefix("include") {
+            for pattern in pattern.split_whitespace() {
+                // Absolute patterns are in the checked system
+                let pattern = system_root.path(conf_dir.join(pattern));
+                let mut included_paths =
+                    glob(&pattern.to_string_lossy())?.collect::<Result<Vec<PathBuf>, _>>()?;
+                included_paths.sort();
+                for included_path in included_paths {
+                    parse_ld_so_conf(system_root, &included_path, dirs, visited)?;
+                }
+            }
+        } else if !line.starts_with("hwcap") {
+            dirs.extend(
+                line.split(|c: char| c.is_whitespace() || c == ':' || c == ',')
+                    .filter(|d| !d.is_empty())
+                    .map(|d| system_root.path(d)),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Get library directories searched by the dynamic loader, from its configuration and defaults
+fn ld_search_paths(system_root: &SystemRoot) -> anyhow::Result<Vec<PathBuf>> {
+    ld_search_paths_from(system_root, &system_root.path(LD_SO_CONF_PATH))
+}
+
+/// Get library directories searched for missing libraries, additional ones first as for
+/// `LD_LIBRARY_PATH`, followed by the dynamic loader ones
+pub fn library_search_paths(
+    system_root: &SystemRoot,
+    extra_lib_dirs: &[PathBuf],
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs: Vec<PathBuf> = extra_lib_dirs.iter().map(|d| system_root.path(d)).collect();
+    dirs.extend(ld_search_paths(system_root)?);
+    Ok(dirs)
+}
+
+fn ld_search_paths_from(
+    system_root: &SystemRoot,
+    conf_path: &Path,
+) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    let mut visited = HashSet::new();
+    if conf_path.exists() {
+        parse_ld_so_conf(system_root, conf_path, &mut dirs, &mut visited)?;
+    }
+
+    // Files already included are not parsed again
+    let conf_dir = conf_path
+        .parent()
+        .unwrap_or_else(|| Path::new("/"))
+        .join(LD_SO_CONF_DIR_NAME);
+    let pattern = format!(
+        "{}/*.conf",
+        glob::Pattern::escape(&conf_dir.to_string_lossy())
+    );
+    let mut conf_paths = glob(&pattern)?.collect::<Result<Vec<PathBuf>, _>>()?;
+    conf_paths.sort();
+    for conf_path in conf_paths {
+        parse_ld_so_conf(system_root, &conf_path, &mut dirs, &mut visited)?;
+    }
+    dirs.extend(DEFAULT_LIB_DIRS.iter().map(|d| system_root.path(d)));
+
+    let mut seen = HashSet::new();
+    dirs.retain(|d| seen.insert(d.to_owned()));
+    Ok(dirs)
+}
+
+/// Library paths by soname, from the dynamic loader cache
+type LdCache = HashMap<String, Vec<PathBuf>>;
+
+/// Parse `ldconfig -p` output
+fn parse_ldconfig_output(output: &str) -> LdCache {
+    let mut cache = LdCache::new();
+    // Lines look like "\tlibz.so.1 (libc6,x86-64) => /usr/lib/libz.so.1", after a header line
+    for line in output.lines() {
+        let (soname, path) = match line.trim_start().split_once(" => ") {
+            Some((soname_and_flags, path)) => match soname_and_flags.split_once(" (") {
+                Some((soname, _)) => (soname, path),
+                None => continue,
+            },
+            None => continue,
+        };
+        cache
+            .entry(soname.to_string())
+            .or_default()
+            .push(PathBuf::from(path));
+    }
+    cache
+}
+
+/// Dynamic loader cache file
+const LD_SO_CACHE_PATH: &str = "/etc/ld.so.cache";
+
+/// Load the dynamic loader cache of the checked system with ldconfig, listing paths of all
+/// sonames it knows about
+pub fn load_ldconfig_cache(
+    system_root: &SystemRoot,
+) -> anyhow::Result<HashMap<String, Vec<PathBuf>>> {
+    let output = run_with_timeout(
+        Command::new("ldconfig")
+            .arg("-p")
+            .arg("-C")
+            .arg(system_root.path(LD_SO_CACHE_PATH))
+            .env("LANG", "C"),
+        COMMAND_TIMEOUT,
+    )?;
+
+    if !output.status.success() {
+        anyhow::bail!("Failed to read dynamic loader cache with ldconfig");
+    }
+
+    let mut cache = parse_ldconfig_output(&String::from_utf8_lossy(&output.stdout));
+    if system_root.root.is_some() {
+        for paths in cache.values_mut() {
+            for path in paths.iter_mut() {
+                *path = system_root.path(&*path);
+            }
+        }
+    }
+    Ok(cache)
+}
+
+/// Dynamic linking information of an ELF object
+#[derive(Debug)]
+struct ElfDynInfo {
+    /// DT_SONAME of a shared object
+    soname: Option<String>,
+
+    /// DT_NEEDED sonames
+    needed: Vec<String>,
+
+    /// DT_RPATH directories, with $ORIGIN and $LIB expanded
+    rpath: Vec<PathBuf>,
+
+    /// DT_RUNPATH directories, with $ORIGIN and $LIB expanded
+    runpath: Vec<PathBuf>,
+
+    /// ELF class, true for 64-bit objects
+    is_64: bool,
+
+    /// ELF machine type
+    machine: u16,
+
+    /// True for statically linked objects, without any dynamic section
+    is_static: bool,
+}
+
+/// Expand $ORIGIN to the object directory, and $LIB to the architecture library directory name,
+/// in a colon separated RPATH/RUNPATH value
+fn expand_search_path(
+    system_root: &SystemRoot,
+    value: &str,
+    origin: &Path,
+    is_64: bool,
+) -> Vec<PathBuf> {
+    // Directories are paths of the checked system, the origin is on the running one
+    let origin = system_root.logical_path(origin);
+    let origin = origin.to_string_lossy();
+    let lib = if is_64 { LIB_DST_64 } else { LIB_DST_32 };
+    value
+        .split(':')
+        .filter(|d| !d.is_empty())
+        .map(|d| {
+            system_root.path(
+                d.replace("${ORIGIN}", &origin)
+                    .replace("$ORIGIN", &origin)
+                    .replace("${LIB}", lib)
+                    .replace("$LIB", lib),
+            )
+        })
+        .collect()
+}
+
+/// Read dynamic linking information of an ELF file, return None if this is not an ELF file
+fn read_elf_dyn_info(system_root: &SystemRoot, path: &Path) -> anyhow::Result<Option<ElfDynInfo>> {
+    let data = fs::read(path)?;
+    if !data.starts_with(b"\x7fELF") {
+        return Ok(None);
+    }
+    let elf = goblin::elf::Elf::parse(&data)
+        .with_context(|| format!("Failed to parse ELF file {:?}", path))?;
+
+    let origin = path.parent().unwrap_or_else(|| Path::new("/"));
+    Ok(Some(ElfDynInfo {
+        soname: elf.soname.map(|s| s.to_owned()),
+        needed: elf.libraries.iter().map(|l| l.to_string()).collect(),
+        rpath: elf
+            .rpaths
+            .iter()
+            .flat_map(|p| expand_search_path(system_root, p, origin, elf.is_64))
+            .collect(),
+        runpath: elf
+            .runpaths
+            .iter()
+            .flat_map(|p| expand_search_path(system_root, p, origin, elf.is_64))
+            .collect(),
+        is_64: elf.is_64,
+        machine: elf.header.e_machine,
+        is_static: elf.dynamic.is_none(),
+    }))
+}
+
+/// Read the ELF class of a file from its identification header, return None if this is not an ELF file
+fn read_elf_class(path: &Path) -> Option<u8> {
+    let mut ident = [0; 5];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_exact(&mut ident))
+        .ok()
+        .filter(|_| ident.starts_with(b"\x7fELF"))
+        .map(|_| ident[4])
+}
+
+/// Check if a file is an ELF object, without reading more than its header
+fn is_elf_file(path: &Path) -> bool {
+    read_elf_class(path).is_some()
+}
+
+/// Check if a file is an ELF object of the given class
+fn is_elf_of_class(path: &Path, is_64: bool) -> bool {
+    read_elf_class(path) == Some(if is_64 { ELFCLASS64 } else { ELFCLASS32 })
+}
+
+/// Find a soname in directories, like the dynamic loader does, skipping objects of another ELF class
+fn find_library<'a>(
+    system_root: &SystemRoot,
+    soname: &str,
+    dirs: impl Iterator<Item = &'a PathBuf>,
+    is_64: bool,
+) -> Option<PathBuf> {
+    if soname.contains('/') {
+        let path = system_root.path(soname);
+        return if is_elf_of_class(&path, is_64) {
+            Some(path)
+        } else {
+            None
+        };
+    }
+    dirs.map(|d| d.join(soname))
+        .find(|p| is_elf_of_class(p, is_64))
+}
+
+/// Check if a file name is another version of a soname, eg. `libfoo.so.2` for `libfoo.so.1`
+fn is_other_soname_version(file_name: &str, soname: &str) -> bool {
+    let base = soname.split_inclusive(".so").next().unwrap_or(soname);
+    file_name != soname
+        && file_name
+            .strip_prefix(base)
+            .is_some_and(|v| v.is_empty() || v.starts_with('.'))
+}
+
+/// Tell if a missing soname has another version in the loader cache or library directories
+fn classify_missing_library(soname: &str, lib_dirs: &[PathBuf], ld_cache: &LdCache) -> MissingKind {
+    let soname = soname.rsplit('/').next().unwrap_or(soname);
+    let in_cache = ld_cache
+        .keys()
+        .any(|name| is_other_soname_version(name, soname));
+    let in_dirs = || {
+        lib_dirs
+            .iter()
+            .filter_map(|d| fs::read_dir(d).ok())
+            .any(|entries| {
+                entries.flatten().any(|e| {
+                    e.file_name()
+                        .to_str()
+                        .is_some_and(|name| is_other_soname_version(name, soname))
+                })
+            })
+    };
+    if in_cache || in_dirs() {
+        MissingKind::VersionBump
+    } else {
+        MissingKind::Absent
+    }
+}
+
+/// ELF machine types the host can run, native first, 32-bit ones being supported on multilib
+/// systems, empty if the host architecture is unknown
+fn host_machines() -> &'static [u16] {
+    if cfg!(target_arch = "x86_64") {
+        &[EM_X86_64, EM_386]
+    } else if cfg!(target_arch = "x86") {
+        &[EM_386]
+    } else if cfg!(target_arch = "aarch64") {
+        &[EM_AARCH64, EM_ARM]
+    } else if cfg!(target_arch = "arm") {
+        &[EM_ARM]
+    } else if cfg!(target_arch = "riscv64") {
+        &[EM_RISCV]
+    } else if cfg!(target_arch = "powerpc64") {
+        &[EM_PPC64]
+    } else {
+        &[]
+    }
+}
+
+/// Check if the host can run an ELF machine type, assume it can if the host is unknown
+fn is_host_machine(machine: u16) -> bool {
+    let host_machines = host_machines();
+    host_machines.is_empty() || host_machines.contains(&machine)
+}
+
+/// Sonames that can not be resolved for an ELF file
+#[derive(Debug, Default)]
+struct MissingDependencies {
+    /// Missing libraries directly needed by the file
+    direct: Vec<String>,
+    /// Missing libraries only needed by its dependencies
+    transitive: Vec<String>,
+    /// Found libraries directly needed by the file
+    found_direct: Vec<String>,
+    /// Found libraries directly needed by name, that resolve to an object with another DT_SONAME
+    mismatched_direct: Vec<String>,
+    /// Machine type of the file if the host can not run it, its libraries are then not searched
+    foreign_machine: Option<u16>,
+    /// True if the file is statically linked, so no library can be missing
+    is_static: bool,
+}
+
+/// Get all sonames that can not be resolved for an ELF file and its dependencies, without running anything
+fn get_missing_dependencies(
+    system_root: &SystemRoot,
+    exec_file: &Path,
+    lib_dirs: &[PathBuf],
+    ld_cache: &LdCache,
+) -> anyhow::Result<MissingDependencies> {
+    let exec_info = match read_elf_dyn_info(system_root, exec_file)? {
+        Some(exec_info) => exec_info,
+        None => return Ok(MissingDependencies::default()),
+    };
+    if !is_host_machine(exec_info.machine) {
+        return Ok(MissingDependencies {
+            foreign_machine: Some(exec_info.machine),
+            ..Default::default()
+        });
+    }
+    if exec_info.is_static {
+        return Ok(MissingDependencies {
+            is_static: true,
+            ..Default::default()
+        });
+    }
+    let direct_deps: HashSet<String> = exec_info.needed.iter().cloned().collect();
+
+    // Dependencies of 32-bit objects are also searched in multilib directories
+    let is_64 = exec_info.is_64;
+    let lib32_dirs: Vec<PathBuf> = if is_64 {
+        Vec::new()
+    } else {
+        LIB32_DIRS
+            .iter()
+            .map(|d| system_root.path(d))
+            .filter(|d| !lib_dirs.contains(d))
+            .collect()
+    };
+    let lib_dirs: Vec<&PathBuf> = lib_dirs.iter().chain(&lib32_dirs).collect();
+
+    // The executable RPATH applies to all objects of the tree, unless it also has a RUNPATH
+    let exec_rpath = if exec_info.runpath.is_empty() {
+        exec_info.rpath.clone()
+    } else {
+        Vec::new()
+    };
+
+    let mut missing_deps = MissingDependencies::default();
+    let mut visited = HashSet::new();
+    let mut to_visit = vec![exec_info];
+    while let Some(info) = to_visit.pop() {
+        for soname in &info.needed {
+            if !visited.insert(soname.to_owned()) {
+                continue;
+            }
+
+            // Search RPATH or RUNPATH first, then the loader cache, and only then scan directories
+            let object_dirs: Vec<&PathBuf> = if info.runpath.is_empty() {
+                info.rpath.iter().chain(exec_rpath.iter()).collect()
+            } else {
+                info.runpath.iter().collect()
+            };
+            let found = find_library(system_root, soname, object_dirs.into_iter(), is_64)
+                .or_else(|| {
+                    ld_cache
+                        .get(soname)
+                        .and_then(|paths| paths.iter().find(|p| is_elf_of_class(p, is_64)))
+                        .cloned()
+                })
+                .or_else(|| find_library(system_root, soname, lib_dirs.iter().copied(), is_64));
+            match found {
+                Some(lib_path) => {
+                    let is_direct = direct_deps.contains(soname);
+                    if is_direct {
+                        missing_deps.found_direct.push(soname.to_owned());
+                    }
+                    match read_elf_dyn_info(system_root, &lib_path) {
+                        Ok(Some(lib_info)) => {
+                            // Objects without a soname can not be told apart
+                            if is_direct
+                                && !soname.contains('/')
+                                && lib_info.soname.as_ref().is_some_and(|s| s != soname)
+                            {
+                                missing_deps.mismatched_direct.push(soname.to_owned());
+                            }
+                            to_visit.push(lib_info);
+                        }
+                        Ok(None) => {}
+                        Err(err) => debug!("{}", err),
+                    }
+                }
+                None if direct_deps.contains(soname) => missing_deps.direct.push(soname.to_owned()),
+                None => missing_deps.transitive.push(soname.to_owned()),
+            }
+        }
+    }
+
+    Ok(missing_deps)
+}
+
+/// Direct library dependencies of a package file
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileDependencies {
+    /// ELF file
+    pub file: PathBuf,
+
+    /// Needed sonames, in the order of the dynamic section
+    pub needed: Vec<String>,
+
+    /// Needed sonames that can not be found
+    pub missing: Vec<String>,
+}
+
+/// Get direct library dependencies of an ELF file, None if it is not one
+fn get_file_dependencies(
+    system_root: &SystemRoot,
+    file: &Path,
+    lib_dirs: &[PathBuf],
+    ld_cache: &LdCache,
+) -> anyhow::Result<Option<FileDependencies>> {
+    let info = match read_elf_dyn_info(system_root, file)? {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    let missing_deps = get_missing_dependencies(system_root, file, lib_dirs, ld_cache)?;
+    Ok(Some(FileDependencies {
+        file: file.to_owned(),
+        needed: info.needed,
+        missing: missing_deps.direct,
+    }))
+}
+
+/// Get direct library dependencies of all executables and shared objects of a package
+pub fn get_package_dependencies(
+    system_root: &SystemRoot,
+    package: &str,
+    extra_lib_dirs: &[PathBuf],
+) -> anyhow::Result<Vec<FileDependencies>> {
+    let lib_dirs = library_search_paths(system_root, extra_lib_dirs)
+        .context("Unable to get library search paths")?;
+    let ld_cache = load_ldconfig_cache(system_root).unwrap_or_default();
+    let (mut packages_files, unlisted) = list_packages_files(system_root, &[package.to_owned()])
+        .context("Unable to list files of package")?;
+    if let Some(unlisted_package) = unlisted.first() {
+        anyhow::bail!("{}", unlisted_package);
+    }
+    let files = packages_files.remove(package).unwrap_or_default();
+
+    let mut dependencies = Vec::new();
+    for file in filter_linked_files(system_root, files) {
+        match get_file_dependencies(system_root, &file, &lib_dirs, &ld_cache) {
+            Ok(Some(file_dependencies)) => dependencies.push(file_dependencies),
+            Ok(None) => {}
+            Err(err) => debug!("Failed to get dependencies of {:?}: {:#}", file, err),
+        }
+    }
+    dependencies.sort_by(|a, b| a.file.cmp(&b.file));
+    Ok(dependencies)
+}
+
+/// Get systemd enabled service links
+pub fn get_sd_enabled_service_links(system_root: &SystemRoot) -> anyhow::Result<Vec<PathBuf>> {
+    get_sd_enabled_service_links_in(&system_root.path("/etc/systemd"))
+}
+
+/// Directories containing links to enabled units, relative to a system or user unit directory
+const SD_ENABLED_UNIT_DIR_GLOBS: [&str; 3] = ["*.target.*", "*.wants", "*.requires"];
+
+fn get_sd_enabled_service_links_in(sd_conf_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    for unit_dir in ["system", "user"] {
+        for dir_glob in SD_ENABLED_UNIT_DIR_GLOBS {
+            let pattern = sd_conf_dir.join(unit_dir).join(dir_glob);
+            dirs.extend(glob(&pattern.to_string_lossy())?.collect::<Result<Vec<PathBuf>, _>>()?);
+        }
+    }
+
+    let mut service_links: Vec<PathBuf> = dirs
+        .into_iter()
+        .filter_map(|p| fs::read_dir(p.as_path()).ok())
+        .flatten()
+        .flatten()
+        .filter(|f| f.file_type().is_ok_and(|f| f.is_symlink()))
+        .map(|f| f.path())
+        .collect();
+    service_links.sort();
+    service_links.dedup();
+
+    Ok(service_links)
+}
+
+/// Maximum number of symlinks to follow when resolving a link, same as the kernel's MAXSYMLINKS
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Resolve all symlinks of a path, like `fs::canonicalize`, but with absolute link targets in the
+/// checked system instead of the running one
+fn canonicalize_in(system_root: &SystemRoot, path: &Path) -> anyhow::Result<PathBuf> {
+    // Resolved and remaining parts are paths of the checked system
+    let mut resolved = PathBuf::from("/");
+    let mut remaining = system_root.logical_path(path);
+    let mut hops = 0;
+    loop {
+        let mut components = remaining.components();
+        let component = match components.next() {
+            Some(component) => component,
+            None => break,
+        };
+        let rest = components.as_path().to_owned();
+        match component {
+            Component::RootDir => resolved = PathBuf::from("/"),
+            Component::ParentDir => {
+                resolved.pop();
+            }
+            Component::Normal(name) => {
+                let candidate = resolved.join(name);
+                let real_path = system_root.path(&candidate);
+                let metadata = fs::symlink_metadata(&real_path)
+                    .with_context(|| format!("Failed to get metadata of {:?}", real_path))?;
+                if metadata.file_type().is_symlink() {
+                    hops += 1;
+                    if hops > MAX_SYMLINK_HOPS {
+                        anyhow::bail!("Too many levels of symbolic links in {:?}", path);
+                    }
+                    // Relative targets are relative to the directory containing the link, which
+                    // is the resolved part
+                    remaining = fs::read_link(&real_path)?.join(rest);
+                    continue;
+                }
+                resolved = candidate;
+            }
+            Component::CurDir | Component::Prefix(_) => {}
+        }
+        remaining = rest;
+    }
+    Ok(system_root.path(resolved))
+}
+
+/// Follow a symlink and all links it goes through, return the first target that does not exist,
+/// or None if the link points to an existing file
+fn find_missing_link_target(
+    system_root: &SystemRoot,
+    link: &Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    let mut target: PathBuf = link.into();
+    for _ in 0..MAX_SYMLINK_HOPS {
+        let link_target = fs::read_link(&target)?;
+        // Relative targets are relative to the directory containing the link, absolute ones are in
+        // the checked system
+        target = match target.parent() {
+            Some(parent) if link_target.is_relative() => parent.join(link_target),
+            _ => system_root.path(link_target),
+        };
+        let metadata = match fs::symlink_metadata(&target) {
+            Err(_) => {
+                return Ok(Some(target));
+            }
+            Ok(m) => m,
+        };
+
+        let ftype = metadata.file_type();
+        if ftype.is_file() {
+            return Ok(None);
+        } else if ftype.is_symlink() {
+            continue;
+        } else {
+            anyhow::bail!("Unexpected file type for {:?}", target);
+        }
+    }
+
+    // Too many levels of symbolic links, likely a cycle
+    Ok(Some(target))
+}
+
+/// Get details of a symlink if it is broken, links that can not be checked are considered valid
+fn get_broken_link(system_root: &SystemRoot, link: &Path) -> Option<BrokenLink> {
+    let missing = find_missing_link_target(system_root, link).ok().flatten()?;
+    Some(BrokenLink {
+        link: link.to_owned(),
+        target: fs::read_link(link).ok()?,
+        missing,
+    })
+}
+
+/// Missing library found for a package file
+#[derive(Debug, Clone)]
+pub struct MissingDependency {
+    /// Package owning the file
+    #[allow(clippy::rc_buffer)]
+    pub package: Arc<String>,
+
+    /// File missing the library
+    #[allow(clippy::rc_buffer)]
+    pub file: Arc<PathBuf>,
+
+    /// Missing soname
+    pub soname: String,
+
+    /// Repository packages providing the soname, only looked up for direct dependencies
+    pub providers: Vec<String>,
+
+    /// True if the file directly needs the library, false if only one of its dependencies does
+    pub is_direct: bool,
+}
+
+/// Result of the library check
+#[derive(Debug, Default)]
+pub struct LibraryCheck {
+    /// All missing libraries, in the order they were found
+    pub missing_dependencies: Vec<MissingDependency>,
+
+    /// Packages with missing direct library dependencies
+    pub broken_packages: Vec<BrokenPackage>,
+
+    /// Packages only missing libraries through their dependencies, or requiring a broken package,
+    /// directly or not
+    pub transitively_broken_packages: Vec<String>,
+
+    /// Linked libraries resolving to a library with another soname, only if checked
+    pub unprovided_libraries: Vec<UnprovidedLibrary>,
+
+    /// Package files built for another architecture
+    pub foreign_arch_files: Vec<ForeignArchFile>,
+
+    /// Package files that could not be analyzed
+    pub unchecked_files: Vec<UncheckedFile>,
+
+    /// Installed packages requiring broken or transitively broken packages
+    pub reverse_deps: HashMap<String, Vec<String>>,
+
+    /// Problems that did not stop the check, but may make its results less accurate
+    pub warnings: Vec<String>,
+}
+
+/// Directories not checked by default, commonly used for executables also using non standard
+/// library locations, or scripts
+pub const DEFAULT_SKIP_DIRS: [&str; 2] = ["/opt", "/usr/share"];
+
+/// Default capacity of the channels between library check stages
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 4096;
+
+/// Kinds of package files analyzed by the library check
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkedFileKinds {
+    /// Executables and shared objects
+    #[default]
+    All,
+
+    /// Only shared objects, like libraries and plugins
+    SharedObjects,
+
+    /// Only executables
+    Executables,
+}
+
+impl LinkedFileKinds {
+    /// Check if an executable file or shared object is of an analyzed kind, telling them apart by
+    /// file name since executables can also be position independent shared objects
+    fn includes(self, path: &Path) -> bool {
+        match self {
+            LinkedFileKinds::All => true,
+            LinkedFileKinds::SharedObjects => is_shared_object_name(path),
+            LinkedFileKinds::Executables => !is_shared_object_name(path),
+        }
+    }
+}
+
+/// Options of the library check
+#[derive(Debug, Clone)]
+pub struct LibraryCheckOptions {
+    /// Number of worker threads analyzing files
+    pub jobs: usize,
+
+    /// Additional library directories, searched after the dynamic loader ones
+    pub extra_lib_dirs: Vec<PathBuf>,
+
+    /// Directories whose files are not checked
+    pub skip_dirs: Vec<PathBuf>,
+
+    /// Kinds of files to analyze
+    pub file_kinds: LinkedFileKinds,
+
+    /// Also flag linked libraries that are found, but whose DT_SONAME is not the linked one
+    pub check_providers: bool,
+
+    /// Capacity of the channels between stages, producers block when it is reached
+    pub channel_capacity: usize,
+
+    /// Set to stop the check early, results found so far are still returned
+    pub stop: Arc<AtomicBool>,
+
+    /// File saving library providers across runs, None to always query the file databases
+    pub provider_cache_path: Option<PathBuf>,
+
+    /// Checked system
+    pub system_root: SystemRoot,
+
+    /// Packages never reported as transitively broken through their dependencies
+    pub exclude: Vec<glob::Pattern>,
+
+    /// Packages that can be reported as transitively broken through their dependencies, None for
+    /// all installed ones
+    pub scan_scope: Option<HashSet<String>>,
+}
+
+impl LibraryCheckOptions {
+    /// Tell if a package requiring a broken package can be reported as transitively broken
+    fn reports_reverse_dep(&self, package: &str) -> bool {
+        if let Some(scan_scope) = &self.scan_scope {
+            if !scan_scope.contains(package) {
+                return false;
+            }
+        }
+        !self.exclude.iter().any(|e| e.matches(package))
+    }
+}
+
+/// Progress of a check, reported to a callback as it runs
+#[derive(Debug, Clone, Copy)]
+pub enum CheckProgress<'a> {
+    /// Files of a package are being analyzed
+    Package(&'a str),
+
+    /// A package or link was fully checked
+    Done,
+}
+
+impl Default for LibraryCheckOptions {
+    fn default() -> Self {
+        Self {
+            jobs: num_cpus::get(),
+            extra_lib_dirs: Vec::new(),
+            skip_dirs: DEFAULT_SKIP_DIRS.iter().map(PathBuf::from).collect(),
+            file_kinds: LinkedFileKinds::All,
+            check_providers: false,
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            stop: Arc::new(AtomicBool::new(false)),
+            provider_cache_path: None,
+            system_root: SystemRoot::default(),
+            exclude: Vec::new(),
+            scan_scope: None,
+        }
+    }
+}
+
+/// Check packages for missing library dependencies
+///
+/// `packages_files` are the files of the packages, listed by `list_packages_files`. `progress` is
+/// called with each package being analyzed, then once it is done, `on_missing` is called for each
+/// missing library as soon as it is found.
+pub fn check_libraries(
+    packages: &[String],
+    packages_files: &PackagesFiles,
+    options: &LibraryCheckOptions,
+    progress: &(dyn Fn(CheckProgress) + Sync),
+    on_missing: &(dyn Fn(&MissingDependency) + Sync),
+) -> anyhow::Result<LibraryCheck> {
+    let system_root = &options.system_root;
+    let mut warnings = Vec::new();
+
+    // Stages block on bounded channels, so there must be at least one worker to drain them
+    let jobs = options.jobs.max(1);
+
+    // Get library search paths
+    let lib_dirs = library_search_paths(system_root, &options.extra_lib_dirs)
+        .context("Unable to get library search paths")?;
+    let ld_cache = load_ldconfig_cache(system_root).unwrap_or_else(|err| {
+        debug!("Falling back to scanning library directories: {}", err);
+        LdCache::new()
+    });
+    info!("Library search paths: {:?}", lib_dirs);
+
+    // Missing deps channel
+    let (missing_deps_tx, missing_deps_rx) = crossbeam::bounded(options.channel_capacity);
+
+    // Unprovided libraries, foreign architecture and unchecked files channels, only read once all
+    // workers are done, so they must not block
+    let (unprovided_tx, unprovided_rx) = crossbeam::unbounded();
+    let (foreign_tx, foreign_rx) = crossbeam::unbounded();
+    let (unchecked_tx, unchecked_rx) = crossbeam::unbounded();
+
+    // Soname owning packages, shared by all workers, and loaded from the disk cache if it matches
+    // the current file databases
+    let files_db_timestamp =
+        options
+            .provider_cache_path
+            .as_ref()
+            .and(files_databases_timestamp_in(
+                &system_root.db_dir().join(PACMAN_SYNC_DB_DIR),
+            ));
+    let owning_package_cache = match (&options.provider_cache_path, files_db_timestamp) {
+        (Some(path), Some(timestamp)) => {
+            QueryCache::with_entries(load_provider_cache(path, timestamp))
+        }
+        _ => QueryCache::default(),
+    };
+    let provider_lookup = ProviderLookup::default();
+
+    // Files listed by pacman are in the install root
+    let skip_dirs: Vec<PathBuf> = options
+        .skip_dirs
+        .iter()
+        .map(|d| system_root.path(d))
+        .collect();
+
+    let missing_deps = cb_thread::scope(|scope| {
+        // Executable file channel
+        let (exec_files_tx, exec_files_rx): CrossbeamChannel<ExecFileWork> =
+            crossbeam::bounded(options.channel_capacity);
+
+        // Executable files to missing deps workers
+        for _ in 0..jobs {
+            let exec_files_rx = exec_files_rx.clone();
+            let missing_deps_tx = missing_deps_tx.clone();
+            let unprovided_tx = unprovided_tx.clone();
+            let foreign_tx = foreign_tx.clone();
+            let unchecked_tx = unchecked_tx.clone();
+            let owning_package_cache = &owning_package_cache;
+            let provider_lookup = &provider_lookup;
+            let lib_dirs = &lib_dirs;
+            let ld_cache = &ld_cache;
+            let stop = &options.stop;
+            scope.spawn(move |_| {
+                while let Ok(exec_file_work) = exec_files_rx.recv() {
+                    trace!("exec_files_rx => {:?}", &exec_file_work);
+                    if stop.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let missing_deps =
+                        get_missing_dependencies(system_root, &exec_file_work.exec_filepath, lib_dirs, ld_cache);
+                    match missing_deps {
+                        Ok(missing_deps) => {
+                            if missing_deps.is_static {
+                                info!(
+                                    "{:?} is statically linked, nothing to check",
+                                    exec_file_work.exec_filepath
+                                );
+                            }
+                            if let Some(machine) = missing_deps.foreign_machine {
+                                // Receiver outlives workers
+                                foreign_tx
+                                    .send(ForeignArchFile {
+                                        package: exec_file_work.package.to_string(),
+                                        file: exec_file_work.exec_filepath.to_path_buf(),
+                                        arch: machine_to_str(machine).to_lowercase(),
+                                    })
+                                    .unwrap();
+                            }
+                            if options.check_providers {
+                                for soname in missing_deps.mismatched_direct {
+                                    // Receiver outlives workers
+                                    unprovided_tx
+                                        .send((
+                                            Arc::clone(&exec_file_work.package),
+                                            Arc::clone(&exec_file_work.exec_filepath),
+                                            soname,
+                                        ))
+                                        .unwrap();
+                                }
+                            }
+                            let missing_deps = missing_deps
+                                .direct
+                                .into_iter()
+                                .map(|d| (d, true))
+                                .chain(missing_deps.transitive.into_iter().map(|d| (d, false)));
+                            for (missing_dep, is_direct) in missing_deps {
+                                // Only direct dependencies are reported with their owning package
+                                let owning_packages = if is_direct {
+                                    let query = missing_dep
+                                        .split('/')
+                                        .next_back()
+                                        .unwrap()
+                                        .split_inclusive(".so")
+                                        .next()
+                                        .unwrap();
+                                    owning_package_cache
+                                        .get_or_try_insert_with(query, || {
+                                            provider_lookup.get(system_root, query)
+                                        })
+                                        .unwrap_or(vec!["?".to_string()])
+                                } else {
+                                    Vec::new()
+                                };
+                                let to_send = MissingDependency {
+                                    package: Arc::clone(&exec_file_work.package),
+                                    file: Arc::clone(&exec_file_work.exec_filepath),
+                                    soname: missing_dep,
+                                    providers: owning_packages,
+                                    is_direct,
+                                };
+                                trace!("{:?} => missing_deps_tx", &to_send);
+                                if missing_deps_tx.send(to_send).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        Err(err) => {
+                            debug!(
+                                "Failed to get missing dependencies for path {:?}: {:#}",
+                                &exec_file_work.exec_filepath, err
+                            );
+                            // Receiver outlives workers
+                            unchecked_tx
+                                .send(UncheckedFile {
+                                    package: exec_file_work.package.to_string(),
+                                    file: exec_file_work.exec_filepath.to_path_buf(),
+                                    error: format!("{:#}", err),
+                                })
+                                .unwrap();
+                        }
+                    }
+                    if exec_file_work.package_last {
+                        progress(CheckProgress::Done);
+                    }
+                }
+            });
+        }
+
+        // Drop this end of the channels, workers have their own clone
+        drop(missing_deps_tx);
+        drop(unprovided_tx);
+        drop(foreign_tx);
+        drop(unchecked_tx);
+
+        // Collect missing deps as they are found
+        let missing_deps_collector = {
+            let missing_deps_rx = &missing_deps_rx;
+            scope.spawn(move |_| {
+                let mut missing_deps = Vec::new();
+                for missing_dep in missing_deps_rx.iter() {
+                    on_missing(&missing_dep);
+                    missing_deps.push(missing_dep);
+                }
+                missing_deps
+            })
+        };
+
+        cb_thread::scope(|scope| {
+            // Package name and files channel
+            let (package_tx, package_rx): CrossbeamChannel<(Arc<String>, Vec<PathBuf>)> =
+                crossbeam::bounded(options.channel_capacity);
+
+            // Package name to executable files workers
+            let worker_count = cmp::min(jobs, packages.len());
+            for _ in 0..worker_count {
+                let package_rx = package_rx.clone();
+                let exec_files_tx = exec_files_tx.clone();
+                let stop = &options.stop;
+                let skip_dirs = &skip_dirs;
+                let file_kinds = options.file_kinds;
+                scope.spawn(move |_| {
+                    while let Ok((package, files)) = package_rx.recv() {
+                        trace!("package_rx => {:?}", package);
+                        if stop.load(Ordering::Relaxed) {
+                            break;
+                        }
+                        progress(CheckProgress::Package(&package));
+                        // Drop files in skipped directories before reading any of them
+                        let file_count = files.len();
+                        let files: Vec<PathBuf> = files
+                            .into_iter()
+                            .filter(|p| !skip_dirs.iter().any(|d| p.starts_with(d)))
+                            .collect();
+                        let skipped_count = file_count - files.len();
+                        let exec_files: Vec<PathBuf> = filter_linked_files(system_root, files)
+                            .into_iter()
+                            .filter(|p| file_kinds.includes(p) && is_elf_file(p))
+                            .collect();
+                        info!(
+                            "{}: analyzing {} executable(s) and shared object(s), {} file(s) in skipped directories",
+                            package,
+                            exec_files.len(),
+                            skipped_count
+                        );
+                        if exec_files.is_empty() {
+                            progress(CheckProgress::Done);
+                            continue;
+                        }
+
+                        for (i, exec_file) in exec_files.iter().enumerate() {
+                            let to_send = ExecFileWork {
+                                package: Arc::clone(&package),
+                                exec_filepath: Arc::new(exec_file.to_owned()),
+                                package_last: i == exec_files.len() - 1,
+                            };
+                            trace!("{:?} => exec_files_tx", &to_send);
+                            if exec_files_tx.send(to_send).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+
+            // Drop this end of the channel, workers have their own clone
+            drop(exec_files_tx);
+
+            // Send package names and files
+            for package in packages {
+                if options.stop.load(Ordering::Relaxed) {
+                    break;
+                }
+                trace!("{:?} => package_tx", package);
+                let files = packages_files.get(package).cloned().unwrap_or_default();
+                if package_tx
+                    .send((Arc::new(package.to_owned()), files))
+                    .is_err()
+                {
+                    // All workers stopped
+                    break;
+                }
+            }
+        })
+        .unwrap();
+
+        missing_deps_collector.join().unwrap()
+    })
+    .unwrap();
+
+    if let (Some(path), Some(timestamp)) = (&options.provider_cache_path, files_db_timestamp) {
+        if let Err(err) =
+            save_provider_cache(path, timestamp, owning_package_cache.computed_entries())
+        {
+            warnings.push(format!("Failed to save library provider cache: {:#}", err));
+        }
+    }
+
+    // Sorted collections, to report packages, libraries and files in a stable order
+    let mut libmap = HashMap::<String, HashMap<Arc<String>, BTreeSet<Arc<PathBuf>>>>::new();
+    let mut trans2 = HashSet::<String>::new();
+    let mut pacmap = BTreeMap::<String, BTreeSet<String>>::new();
+    let mut pacsourcemap = HashMap::<String, String>::new();
+    for missing_dep in &missing_deps {
+        if missing_dep.is_direct {
+            libmap
+                .entry(missing_dep.soname.clone())
+                .or_default()
+                .entry(missing_dep.package.clone())
+                .or_default()
+                .insert(missing_dep.file.clone());
+            pacmap
+                .entry(missing_dep.package.to_string())
+                .or_default()
+                .insert(missing_dep.soname.clone());
+        } else {
+            trans2.insert(missing_dep.package.to_string());
+        }
+        if let Some(provider) = missing_dep.providers.first() {
+            pacsourcemap.insert(missing_dep.soname.clone(), provider.clone());
+        }
+    }
+    let mut trans = HashSet::<String>::new();
+    for t in trans2 {
+        if !pacmap.contains_key(&t) {
+            trans.insert(t);
+        }
+    }
+
+    // Get packages depending on broken packages, directly or not, which are transitively broken too
+    let broken: Vec<String> = pacmap.keys().chain(trans.iter()).cloned().collect();
+    let revdepmap = reverse_deps_closure(&broken, |packages| {
+        get_reverse_deps(system_root, packages).unwrap_or_else(|err| {
+            warnings.push(format!("Failed to get reverse dependencies: {:#}", err));
+            HashMap::new()
+        })
+    });
+    trans.extend(
+        revdepmap
+            .keys()
+            .filter(|p| !pacmap.contains_key(*p) && options.reports_reverse_dep(p))
+            .cloned(),
+    );
+
+    let mut missing_kinds = HashMap::<&str, MissingKind>::new();
+    for soname in pacmap.values().flatten() {
+        missing_kinds
+            .entry(soname)
+            .or_insert_with(|| classify_missing_library(soname, &lib_dirs, &ld_cache));
+    }
+
+    // Find which providers are installed, to tell if they need to be installed or are outdated
+    let mut providers: Vec<String> = pacsourcemap
+        .values()
+        .filter(|p| *p != "?")
+        .cloned()
+        .collect();
+    providers.sort();
+    providers.dedup();
+    let uninstalled_providers: HashSet<String> = if providers.is_empty() {
+        HashSet::new()
+    } else {
+        get_uninstalled_packages(system_root, &providers)
+            .unwrap_or_else(|err| {
+                warnings.push(format!(
+                    "Failed to check if library providers are installed: {:#}",
+                    err
+                ));
+                providers.clone()
+            })
+            .into_iter()
+            .collect()
+    };
+
+    let mut check = LibraryCheck::default();
+    for (package, missing_deps) in &pacmap {
+        let missing = missing_deps
+            .iter()
+            .map(|missing_dep| MissingLibrary {
+                soname: missing_dep.to_owned(),
+                files: libmap[missing_dep][package]
+                    .iter()
+                    .map(|f| f.to_string_lossy().into_owned())
+                    .collect(),
+                provider: pacsourcemap.get(missing_dep).cloned(),
+                provider_installed: pacsourcemap
+                    .get(missing_dep)
+                    .is_some_and(|p| p != "?" && !uninstalled_providers.contains(p)),
+                kind: missing_kinds[missing_dep.as_str()],
+            })
+            .collect();
+        let mut security_modules: Vec<String> = libmap
+            .values()
+            .filter_map(|packages_files| packages_files.get(package))
+            .flatten()
+            .filter(|f| is_security_module(system_root, f))
+            .map(|f| f.to_string_lossy().into_owned())
+            .collect();
+        security_modules.sort();
+        security_modules.dedup();
+        check.broken_packages.push(BrokenPackage {
+            package: package.to_owned(),
+            missing,
+            required_by: revdepmap.get(package).cloned().unwrap_or_default(),
+            security_modules,
+        });
+    }
+    check.transitively_broken_packages = trans.into_iter().collect();
+    check.transitively_broken_packages.sort();
+    check.missing_dependencies = missing_deps;
+    check.reverse_deps = revdepmap;
+    check.warnings = warnings;
+
+    // Group unprovided libraries by package and soname
+    let mut unprovided = BTreeMap::<(String, String), Vec<String>>::new();
+    for (package, file, soname) in unprovided_rx.try_iter() {
+        unprovided
+            .entry((package.to_string(), soname))
+            .or_default()
+            .push(file.to_string_lossy().into_owned());
+    }
+    check.unprovided_libraries = unprovided
+        .into_iter()
+        .map(|((package, soname), mut files)| {
+            files.sort();
+            UnprovidedLibrary {
+                package,
+                soname,
+                files,
+            }
+        })
+        .collect();
+
+    check.foreign_arch_files = foreign_rx.try_iter().collect();
+    check
+        .foreign_arch_files
+        .sort_by(|a, b| (&a.package, &a.file).cmp(&(&b.package, &b.file)));
+    check.unchecked_files = unchecked_rx.try_iter().collect();
+    check
+        .unchecked_files
+        .sort_by(|a, b| (&a.package, &a.file).cmp(&(&b.package, &b.file)));
+
+    Ok(check)
+}
+
+/// Results of the interpreter directories check
+#[derive(Debug, Default)]
+pub struct RuntimeCheck {
+    /// Packages with files in outdated directories
+    pub broken_packages: Vec<BrokenRuntimePackage>,
+
+    /// Why some interpreters could not be checked
+    pub warnings: Vec<String>,
+}
+
+/// Check all interpreters for packages with files in outdated directories
+pub fn check_runtimes(system_root: &SystemRoot) -> RuntimeCheck {
+    let mut check = RuntimeCheck::default();
+    let mut dirs_owners = HashMap::new();
+    for runtime in &VERSIONED_RUNTIMES {
+        match get_broken_runtime_packages(system_root, runtime, &mut dirs_owners) {
+            Ok(runtime_broken_packages) => check.broken_packages.extend(runtime_broken_packages),
+            Err(err) => check.warnings.push(format!(
+                "Failed to list {} packages: {:#}",
+                runtime.name, err
+            )),
+        }
+    }
+    check
+}
+
+/// Get sorted broken links among systemd enabled service links, checking them with `jobs` worker
+/// threads
+///
+/// `progress` is called with `CheckProgress::Done` once for each link.
+pub fn check_systemd_links(
+    system_root: &SystemRoot,
+    links: &[PathBuf],
+    jobs: usize,
+    progress: &(dyn Fn(CheckProgress) + Sync),
+) -> Vec<BrokenLink> {
+    let (broken_links_tx, broken_links_rx) = crossbeam::unbounded();
+
+    cb_thread::scope(|scope| {
+        // Link channel
+        let (links_tx, links_rx): CrossbeamChannel<&PathBuf> = crossbeam::unbounded();
+
+        // Link to broken link workers
+        let worker_count = cmp::min(jobs, links.len());
+        for _ in 0..worker_count {
+            let links_rx = links_rx.clone();
+            let broken_links_tx = broken_links_tx.clone();
+            scope.spawn(move |_| {
+                while let Ok(link) = links_rx.recv() {
+                    trace!("links_rx => {:?}", link);
+                    if let Some(broken_link) = get_broken_link(system_root, link) {
+                        if broken_links_tx.send(broken_link).is_err() {
+                            break;
+                        }
+                    }
+                    progress(CheckProgress::Done);
+                }
+            });
+        }
+
+        // Send links
+        for link in links {
+            links_tx.send(link).unwrap();
+        }
+    })
+    .unwrap();
+
+    // Drop this end of the channel, workers are done
+    drop(broken_links_tx);
+
+    let mut broken_links: Vec<BrokenLink> = broken_links_rx.iter().collect();
+    broken_links.sort_by(|a, b| a.link.cmp(&b.link));
+    broken_links
+}
+
+/// Get symlinks pointing to missing targets among files
+fn find_broken_symlinks(system_root: &SystemRoot, files: &[PathBuf]) -> Vec<BrokenLink> {
+    files
+        .iter()
+        .filter(|f| fs::symlink_metadata(f).is_ok_and(|m| m.file_type().is_symlink()))
+        .filter_map(|f| get_broken_link(system_root, f))
+        .collect()
+}
+
+/// Check files of packages for symlinks pointing to missing targets
+pub fn check_package_symlinks(
+    system_root: &SystemRoot,
+    packages_files: &PackagesFiles,
+) -> Vec<BrokenSymlink> {
+    let mut broken_symlinks: Vec<BrokenSymlink> = packages_files
+        .iter()
+        .flat_map(|(package, files)| {
+            find_broken_symlinks(system_root, files)
+                .into_iter()
+                .map(move |broken_link| BrokenSymlink {
+                    package: package.clone(),
+                    broken_link,
+                })
+        })
+        .collect();
+    broken_symlinks.sort_by(|a, b| a.broken_link.link.cmp(&b.broken_link.link));
+    broken_symlinks
+}
+
+/// Directory of GObject introspection typelibs
+const TYPELIB_DIR: &str = "/usr/lib/girepository-1.0";
+
+/// Magic bytes starting GObject introspection typelibs
+const TYPELIB_MAGIC: &[u8; 16] = b"GOBJ\nMETADATA\r\n\x1a";
+
+/// Offset of the shared library string offset in the typelib header
+const TYPELIB_SHARED_LIBRARY_OFFSET: usize = 52;
+
+/// Parse libraries loaded by a GObject introspection typelib from its header, None if it is not
+/// a valid typelib
+fn parse_typelib_shared_libraries(data: &[u8]) -> Option<Vec<String>> {
+    if !data.starts_with(TYPELIB_MAGIC) {
+        return None;
+    }
+    let offset_bytes =
+        data.get(TYPELIB_SHARED_LIBRARY_OFFSET..TYPELIB_SHARED_LIBRARY_OFFSET + 4)?;
+    let offset = u32::from_le_bytes([
+        offset_bytes[0],
+        offset_bytes[1],
+        offset_bytes[2],
+        offset_bytes[3],
+    ]) as usize;
+    if offset == 0 {
+        // Typelib without library
+        return Some(Vec::new());
+    }
+    let value = data.get(offset..)?;
+    let value = &value[..value.iter().position(|&b| b == 0)?];
+    Some(
+        String::from_utf8_lossy(value)
+            .split(',')
+            .filter(|l| !l.is_empty())
+            .map(|l| l.to_owned())
+            .collect(),
+    )
+}
+
+/// Directories of udev rules
+const UDEV_RULES_DIRS: [&str; 2] = ["/etc/udev/rules.d", "/usr/lib/udev/rules.d"];
+
+/// Parse absolute paths of programs run by the `RUN` and `PROGRAM` keys of a udev rule line,
+/// leaving out builtin commands, paths with substitutions, and programs searched by name
+fn parse_udev_rule_programs(line: &str) -> Vec<PathBuf> {
+    let mut programs = Vec::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() && !rest.starts_with('#') {
+        let key_end = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let key = &rest[..key_end];
+        rest = &rest[key_end..];
+        let attr = match rest.strip_prefix('{').and_then(|r| r.split_once('}')) {
+            Some((attr, after)) => {
+                rest = after;
+                Some(attr)
+            }
+            None => None,
+        };
+        // Operator then double quoted value
+        let value = match rest.split_once('"').and_then(|(_, r)| r.split_once('"')) {
+            Some((value, after)) => {
+                rest = after.trim_start().trim_start_matches(',').trim_start();
+                value
+            }
+            None => break,
+        };
+        let is_program = match key {
+            "RUN" => attr.is_none_or(|a| a == "program"),
+            "PROGRAM" => true,
+            _ => false,
+        };
+        if let Some(program) = value.split_whitespace().next().filter(|_| is_program) {
+            if program.starts_with('/') && !program.contains(['$', '%']) {
+                programs.push(PathBuf::from(program));
+            }
+        }
+    }
+    programs
+}
+
+/// Get programs of a udev rules file that are missing or not executable, with continued lines joined
+fn find_broken_udev_programs(system_root: &SystemRoot, rules: &str) -> Vec<PathBuf> {
+    let mut broken_programs = Vec::new();
+    for line in rules.replace("\\\n", "").lines() {
+        for program in parse_udev_rule_programs(line) {
+            let is_executable = is_executable_file(&system_root.path(&program));
+            if !is_executable && !broken_programs.contains(&program) {
+                broken_programs.push(program);
+            }
+        }
+    }
+    broken_programs
+}
+
+/// Check udev rules of packages for helper programs that are missing or not executable
+pub fn check_udev_rules(
+    system_root: &SystemRoot,
+    packages_files: &PackagesFiles,
+) -> Vec<BrokenUdevRule> {
+    let mut broken_rules = Vec::new();
+    for (package, files) in packages_files {
+        for rule in files.iter().filter(|f| {
+            UDEV_RULES_DIRS
+                .iter()
+                .any(|d| f.starts_with(system_root.path(d)))
+                && f.extension() == Some(OsStr::new("rules"))
+        }) {
+            let rules = match fs::read_to_string(rule) {
+                Ok(rules) => rules,
+                Err(err) => {
+                    debug!("Failed to read {:?}: {}", rule, err);
+                    continue;
+                }
+            };
+            for program in find_broken_udev_programs(system_root, &rules) {
+                broken_rules.push(BrokenUdevRule {
+                    package: package.clone(),
+                    rule: rule.to_owned(),
+                    program,
+                });
+            }
+        }
+    }
+    broken_rules.sort_by(|a, b| (&a.rule, &a.program).cmp(&(&b.rule, &b.program)));
+    broken_rules
+}
+
+/// Directory of desktop entries shown in application menus
+const DESKTOP_ENTRIES_DIR: &str = "/usr/share/applications";
+
+/// Program search path when `PATH` is not set, as set by pacman for hooks
+const DEFAULT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/bin";
+
+/// Split the value of a desktop entry `Exec` key into arguments, removing double quotes and their
+/// escapes
+fn split_desktop_exec(value: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    let mut arg = String::new();
+    let mut has_arg = false;
+    let mut in_quotes = false;
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                has_arg = true;
+            }
+            '\\' if in_quotes => arg.extend(chars.next()),
+            c if c.is_whitespace() && !in_quotes => {
+                if has_arg {
+                    args.push(std::mem::take(&mut arg));
+                    has_arg = false;
+                }
+            }
+            c => {
+                arg.push(c);
+                has_arg = true;
+            }
+        }
+    }
+    if has_arg {
+        args.push(arg);
+    }
+    args
+}
+
+/// Parse programs launched by the `Exec` keys of a desktop entry file, of the main entry and its
+/// actions, skipping field codes and `env` with its options and variable assignments
+fn parse_desktop_entry_programs(entry: &str) -> Vec<String> {
+    let mut programs = Vec::new();
+    for line in entry.lines() {
+        let value = match line.split_once('=') {
+            Some((key, value)) if key.trim() == "Exec" => value.trim(),
+            _ => continue,
+        };
+        let mut args = split_desktop_exec(value)
+            .into_iter()
+            .filter(|a| !(a.len() == 2 && a.starts_with('%')));
+        let mut program = args.next();
+        if program
+            .as_deref()
+            .is_some_and(|p| Path::new(p).file_name() == Some(OsStr::new("env")))
+        {
+            program = None;
+            while let Some(arg) = args.next() {
+                if arg == "-u" || arg == "--unset" {
+                    // Variable to unset
+                    args.next();
+                } else if !arg.contains('=') && !arg.starts_with('-') {
+                    program = Some(arg);
+                    break;
+                }
+            }
+        }
+        if let Some(program) = program {
+            if !programs.contains(&program) {
+                programs.push(program);
+            }
+        }
+    }
+    programs
+}
+
+/// Check if a desktop entry program exists on the checked system, at its absolute path or in a
+/// directory of a `PATH` like list
+fn desktop_entry_program_exists(system_root: &SystemRoot, program: &str, path_var: &OsStr) -> bool {
+    if program.starts_with('/') {
+        return is_executable_file(&system_root.path(program));
+    }
+    match std::env::join_paths(std::env::split_paths(path_var).map(|d| system_root.path(d))) {
+        Ok(path_var) => find_program_in(program, &path_var).is_some(),
+        Err(_) => true,
+    }
+}
+
+/// Check desktop entries of packages for programs that can not be found
+pub fn check_desktop_entries(
+    system_root: &SystemRoot,
+    packages_files: &PackagesFiles,
+) -> Vec<BrokenDesktopEntry> {
+    let path_var = std::env::var_os("PATH").unwrap_or_else(|| DEFAULT_PATH.into());
+
+    let mut broken_entries = Vec::new();
+    for (package, files) in packages_files {
+        for desktop_file in files.iter().filter(|f| {
+            f.starts_with(system_root.path(DESKTOP_ENTRIES_DIR))
+                && f.extension() == Some(OsStr::new("desktop"))
+        }) {
+            let entry = match fs::read_to_string(desktop_file) {
+                Ok(entry) => entry,
+                Err(err) => {
+                    debug!("Failed to read {:?}: {}", desktop_file, err);
+                    continue;
+                }
+            };
+            for program in parse_desktop_entry_programs(&entry) {
+                if !desktop_entry_program_exists(system_root, &program, &path_var) {
+                    broken_entries.push(BrokenDesktopEntry {
+                        package: package.clone(),
+                        desktop_file: desktop_file.to_owned(),
+                        program,
+                    });
+                }
+            }
+        }
+    }
+    broken_entries
+        .sort_by(|a, b| (&a.desktop_file, &a.program).cmp(&(&b.desktop_file, &b.program)));
+    broken_entries
+}
+
+/// Check typelibs of packages for libraries that can not be found
+pub fn check_typelibs(
+    system_root: &SystemRoot,
+    packages_files: &PackagesFiles,
+    extra_lib_dirs: &[PathBuf],
+) -> anyhow::Result<Vec<BrokenTypelib>> {
+    let lib_dirs = library_search_paths(system_root, extra_lib_dirs)
+        .context("Unable to get library search paths")?;
+    let ld_cache = load_ldconfig_cache(system_root).unwrap_or_default();
+    let is_64 = cfg!(target_pointer_width = "64");
+
+    let mut broken_typelibs = Vec::new();
+    for (package, files) in packages_files {
+        for typelib in files.iter().filter(|f| {
+            f.starts_with(system_root.path(TYPELIB_DIR))
+                && f.extension() == Some(OsStr::new("typelib"))
+        }) {
+            let libraries = match fs::read(typelib) {
+                Ok(data) => parse_typelib_shared_libraries(&data).unwrap_or_default(),
+                Err(err) => {
+                    debug!("Failed to read {:?}: {}", typelib, err);
+                    continue;
+                }
+            };
+            let missing: Vec<String> = libraries
+                .into_iter()
+                .filter(|soname| {
+                    find_library(system_root, soname, lib_dirs.iter(), is_64).is_none()
+                        && !ld_cache.contains_key(soname.as_str())
+                })
+                .collect();
+            if !missing.is_empty() {
+                broken_typelibs.push(BrokenTypelib {
+                    package: package.clone(),
+                    typelib: typelib.to_owned(),
+                    missing,
+                });
+            }
+        }
+    }
+    broken_typelibs.sort_by(|a, b| a.typelib.cmp(&b.typelib));
+    Ok(broken_typelibs)
+}
+
+/// Run all checks on all AUR packages of a system, without reporting progress
+pub fn scan_broken_packages(system_root: &SystemRoot) -> anyhow::Result<BrokenReport> {
+    let start = Instant::now();
+
+    let runtime_system_root = system_root.clone();
+    let runtime_check = thread::Builder::new()
+        .spawn(move || check_runtimes(&runtime_system_root))
+        .context("Failed to start thread")?;
+
+    let packages = get_aur_packages(system_root).context("Unable to get list of AUR packages")?;
+    let (packages_files, unlisted_packages) =
+        list_packages_files(system_root, &packages).context("Unable to list files of packages")?;
+    let libraries = check_libraries(
+        &packages,
+        &packages_files,
+        &LibraryCheckOptions {
+            system_root: system_root.clone(),
+            scan_scope: Some(packages.iter().cloned().collect()),
+            ..Default::default()
+        },
+        &|_| {},
+        &|_| {},
+    )?;
+
+    let links = get_sd_enabled_service_links(system_root)
+        .context("Unable to get Systemd enabled services")?;
+    let broken_systemd_links = check_systemd_links(system_root, &links, num_cpus::get(), &|_| {});
+
+    let runtimes = runtime_check.join().unwrap_or_default();
+
+    let mut report = BrokenReport::new(libraries, runtimes, broken_systemd_links);
+    report.unlisted_packages = unlisted_packages;
+    report.summary = Summary::new(&report, packages.len(), start.elapsed());
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{File, Permissions};
+    use std::io::Write;
+
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_required_by() {
+        let pacman_output = "Name            : libfoo
+Version         : 1.2-1
+Depends On      : glibc
+Required By     : bar  baz
+                  qux
+Optional For    : None
+Conflicts With  : None
+";
+        assert_eq!(parse_required_by(pacman_output), ["bar", "baz", "qux"]);
+
+        let pacman_output = "Name            : leaf
+Required By     : None
+Optional For    : None
+";
+        assert!(parse_required_by(pacman_output).is_empty());
+    }
+
+    #[test]
+    fn test_parse_packages_required_by() {
+        let pacman_output = "Name            : libfoo
+Version         : 1.2-1
+Required By     : bar  baz
+Optional For    : None
+
+Name            : leaf
+Required By     : None
+Optional For    : None
+
+";
+        let reverse_deps = parse_packages_required_by(pacman_output);
+        assert_eq!(reverse_deps.len(), 2);
+        assert_eq!(reverse_deps["libfoo"], ["bar", "baz"]);
+        assert!(reverse_deps["leaf"].is_empty());
+
+        assert!(parse_packages_required_by("").is_empty());
+    }
+
+    #[test]
+    fn test_reverse_deps_closure() {
+        let reverse_deps: HashMap<&str, Vec<&str>> = vec![
+            ("libfoo", vec!["bar", "baz"]),
+            ("bar", vec!["qux"]),
+            ("baz", vec!["qux", "libfoo"]),
+            ("qux", vec![]),
+            ("other", vec!["bar"]),
+        ]
+        .into_iter()
+        .collect();
+        let mut levels = Vec::new();
+        let closure = reverse_deps_closure(&["libfoo".to_string()], |packages| {
+            levels.push(packages.to_vec());
+            packages
+                .iter()
+                .map(|p| {
+                    let deps = reverse_deps[p.as_str()].iter().map(|d| d.to_string());
+                    (p.clone(), deps.collect())
+                })
+                .collect()
+        });
+
+        // Each level is queried once, and cycles are not visited again
+        assert_eq!(levels, [vec!["libfoo"], vec!["bar", "baz"], vec!["qux"]]);
+        let mut packages: Vec<&str> = closure.keys().map(|p| p.as_str()).collect();
+        packages.sort();
+        assert_eq!(packages, ["bar", "baz", "libfoo", "qux"]);
+        assert_eq!(closure["baz"], ["libfoo", "qux"]);
+        assert!(closure["qux"].is_empty());
+    }
+
+    #[test]
+    fn test_reports_reverse_dep() {
+        let mut options = LibraryCheckOptions::default();
+        assert!(options.reports_reverse_dep("foo-git"));
+
+        // Excluded reverse dependencies are never reported
+        options.exclude = vec![glob::Pattern::new("*-git").unwrap()];
+        assert!(!options.reports_reverse_dep("foo-git"));
+        assert!(options.reports_reverse_dep("bar"));
+
+        // Neither are those outside of the scanned packages
+        options.scan_scope = Some(
+            vec!["foo-git".to_string(), "bar".to_string()]
+                .into_iter()
+                .collect(),
+        );
+        assert!(options.reports_reverse_dep("bar"));
+        assert!(!options.reports_reverse_dep("qt5-base"));
+        assert!(!options.reports_reverse_dep("foo-git"));
+    }
+
+    #[test]
+    fn test_find_missing_link_target_cycle() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let link_a = tmp_dir.path().join("a");
+        let link_b = tmp_dir.path().join("b");
+        std::os::unix::fs::symlink(&link_b, &link_a).unwrap();
+        std::os::unix::fs::symlink(&link_a, &link_b).unwrap();
+
+        assert!(find_missing_link_target(&SystemRoot::default(), &link_a)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn test_find_broken_symlinks() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let file_path = tmp_dir.path().join("file");
+        File::create(&file_path).unwrap();
+        let valid_link_path = tmp_dir.path().join("valid");
+        std::os::unix::fs::symlink("file", &valid_link_path).unwrap();
+        let broken_link_path = tmp_dir.path().join("broken");
+        std::os::unix::fs::symlink("removed", &broken_link_path).unwrap();
+
+        assert_eq!(
+            find_broken_symlinks(
+                &SystemRoot::default(),
+                &[
+                    tmp_dir.path().to_path_buf(),
+                    file_path,
+                    valid_link_path,
+                    broken_link_path.clone()
+                ]
+            )
+            .into_iter()
+            .map(|l| l.link)
+            .collect::<Vec<_>>(),
+            [broken_link_path]
+        );
+    }
+
+    #[test]
+    fn test_get_sd_enabled_service_links() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let sd_conf_dir = tmp_dir.path();
+        for dir in [
+            "system/multi-user.target.wants",
+            "system/timers.target.wants",
+            "system/foo.service.requires",
+            "user/sockets.target.wants",
+        ] {
+            fs::create_dir_all(sd_conf_dir.join(dir)).unwrap();
+        }
+        File::create(sd_conf_dir.join("system/foo.service")).unwrap();
+        for (link, target) in [
+            (
+                "system/multi-user.target.wants/foo.service",
+                "../foo.service",
+            ),
+            (
+                "system/timers.target.wants/removed.timer",
+                "/nonexistent/removed.timer",
+            ),
+            ("system/foo.service.requires/bar.socket", "../foo.service"),
+            (
+                "user/sockets.target.wants/baz.socket",
+                "/nonexistent/baz.socket",
+            ),
+        ] {
+            std::os::unix::fs::symlink(target, sd_conf_dir.join(link)).unwrap();
+        }
+        // Not a link to an enabled unit
+        File::create(sd_conf_dir.join("system/multi-user.target.wants/file")).unwrap();
+
+        let links = get_sd_enabled_service_links_in(sd_conf_dir).unwrap();
+        assert_eq!(
+            links,
+            [
+                sd_conf_dir.join("system/foo.service.requires/bar.socket"),
+                sd_conf_dir.join("system/multi-user.target.wants/foo.service"),
+                sd_conf_dir.join("system/timers.target.wants/removed.timer"),
+                sd_conf_dir.join("user/sockets.target.wants/baz.socket"),
+            ]
+        );
+        assert_eq!(
+            check_systemd_links(&SystemRoot::default(), &links, 1, &|_| {})
+                .into_iter()
+                .map(|l| l.link)
+                .collect::<Vec<_>>(),
+            [
+                sd_conf_dir.join("system/timers.target.wants/removed.timer"),
+                sd_conf_dir.join("user/sockets.target.wants/baz.socket"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_systemd_links() {
+        let tmp_dir = TempDir::new("").unwrap();
+        File::create(tmp_dir.path().join("foo.service")).unwrap();
+        let mut links = Vec::new();
+        for (name, target) in [
+            ("c.service", "removed.service"),
+            ("foo.service", "foo.service"),
+            ("a.service", "removed.service"),
+        ] {
+            let link_path = tmp_dir.path().join("multi-user.target.wants").join(name);
+            fs::create_dir_all(link_path.parent().unwrap()).unwrap();
+            std::os::unix::fs::symlink(tmp_dir.path().join(target), &link_path).unwrap();
+            links.push(link_path);
+        }
+
+        let checked_links = AtomicUsize::new(0);
+        let progress = |event: CheckProgress| {
+            if let CheckProgress::Done = event {
+                checked_links.fetch_add(1, Ordering::Relaxed);
+            }
+        };
+        assert_eq!(
+            check_systemd_links(&SystemRoot::default(), &links, 2, &progress)
+                .into_iter()
+                .map(|l| l.link)
+                .collect::<Vec<_>>(),
+            [links[2].clone(), links[0].clone()]
+        );
+        assert_eq!(checked_links.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn test_find_missing_link_target_relative() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let unit_dir = tmp_dir.path().join("multi-user.target.wants");
+        fs::create_dir(&unit_dir).unwrap();
+        File::create(tmp_dir.path().join("foo.service")).unwrap();
+        let link = unit_dir.join("foo.service");
+        std::os::unix::fs::symlink("../foo.service", &link).unwrap();
+        assert!(find_missing_link_target(&SystemRoot::default(), &link)
+            .unwrap()
+            .is_none());
+
+        let broken_link = unit_dir.join("bar.service");
+        std::os::unix::fs::symlink("../bar.service", &broken_link).unwrap();
+        assert_eq!(
+            find_missing_link_target(&SystemRoot::default(), &broken_link).unwrap(),
+            Some(unit_dir.join("../bar.service"))
+        );
+
+        // The chain is followed up to the first missing path
+        let chained_link = unit_dir.join("baz.service");
+        std::os::unix::fs::symlink("bar.service", &chained_link).unwrap();
+        assert_eq!(
+            get_broken_link(&SystemRoot::default(), &chained_link),
+            Some(BrokenLink {
+                link: chained_link.clone(),
+                target: PathBuf::from("bar.service"),
+                missing: unit_dir.join("../bar.service"),
+            })
+        );
+        assert_eq!(
+            get_broken_link(&SystemRoot::default(), &chained_link)
+                .unwrap()
+                .to_string(),
+            format!(
+                "link {} -> bar.service is broken (missing {}/../bar.service)",
+                chained_link.display(),
+                unit_dir.display()
+            )
+        );
+    }
+
+    #[test]
+    fn test_get_package_linked_files_non_utf8() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let exec_filepath = tmp_dir
+            .path()
+            .join(OsStr::from_bytes(b"exec_\xff\xfe_file"));
+        File::create(&exec_filepath)
+            .unwrap()
+            .set_permissions(Permissions::from_mode(0o700))
+            .unwrap();
+
+        let mut pacman_output = b"foo ".to_vec();
+        pacman_output.extend_from_slice(tmp_dir.path().as_os_str().as_bytes());
+        pacman_output.extend_from_slice(b"/\nfoo ");
+        pacman_output.extend_from_slice(exec_filepath.as_os_str().as_bytes());
+        pacman_output.push(b'\n');
+
+        let mut files = parse_package_file_list(&pacman_output);
+        assert_eq!(files.len(), 1);
+        let files = filter_linked_files(&SystemRoot::default(), files.remove("foo").unwrap());
+        assert_eq!(files, [exec_filepath]);
+    }
+
+    #[test]
+    fn test_parse_package_file_list_errors() {
+        assert!(parse_package_file_list_errors("").is_empty());
+        assert_eq!(
+            parse_package_file_list_errors(
+                "error: package 'foo-git' was not found\nwarning: something else\n\
+                 error: package 'bar' was not found\n"
+            ),
+            [
+                UnlistedPackage {
+                    package: "foo-git".to_string(),
+                    error: "package 'foo-git' was not found".to_string(),
+                },
+                UnlistedPackage {
+                    package: "bar".to_string(),
+                    error: "package 'bar' was not found".to_string(),
+                }
+            ]
+        );
+        // Errors not about a package make the whole listing fail
+        assert!(
+            parse_package_file_list_errors("error: failed to initialize alpm library").is_empty()
+        );
+    }
+
+    #[test]
+    fn test_filter_linked_files_shared_objects() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let plugin_dir = tmp_dir.path().join("usr/lib/foo/plugins");
+        fs::create_dir_all(&plugin_dir).unwrap();
+        let mut files = Vec::new();
+        for name in [
+            "libfoo.so.2",
+            "libfoo.so",
+            "libfoo.sock",
+            "libfoo.so.2.txt.gz",
+        ] {
+            let filepath = plugin_dir.join(name);
+            File::create(&filepath)
+                .unwrap()
+                .write_all(b"\x7fELF\x02\x01\x01")
+                .unwrap();
+            files.push(filepath);
+        }
+        // Linker scripts are named like shared objects but are not ELF files
+        let script_filepath = plugin_dir.join("libbar.so");
+        File::create(&script_filepath)
+            .unwrap()
+            .write_all(b"INPUT(libbar.so.1)")
+            .unwrap();
+        files.push(script_filepath);
+
+        assert_eq!(
+            filter_linked_files(&SystemRoot::default(), files),
+            [plugin_dir.join("libfoo.so.2"), plugin_dir.join("libfoo.so")]
+        );
+    }
+
+    #[test]
+    fn test_is_security_module() {
+        assert!(is_security_module(
+            &SystemRoot::default(),
+            Path::new("/usr/lib/security/pam_foo.so")
+        ));
+        assert!(is_security_module(
+            &SystemRoot::default(),
+            Path::new("/usr/lib/libnss_foo.so.2")
+        ));
+        assert!(is_security_module(
+            &SystemRoot::default(),
+            Path::new("/usr/lib32/libnss_foo.so.2")
+        ));
+        assert!(!is_security_module(
+            &SystemRoot::default(),
+            Path::new("/usr/lib/security/pam_foo.conf")
+        ));
+        assert!(!is_security_module(
+            &SystemRoot::default(),
+            Path::new("/usr/lib/pam_foo.so")
+        ));
+        assert!(!is_security_module(
+            &SystemRoot::default(),
+            Path::new("/usr/lib/foo/libnss_foo.so.2")
+        ));
+        assert!(!is_security_module(
+            &SystemRoot::default(),
+            Path::new("/usr/lib/libfoo.so.2")
+        ));
+
+        // Modules are usually not executable, they must still be analyzed
+        let tmp_dir = TempDir::new("").unwrap();
+        let module_dir = tmp_dir.path().join("usr/lib/security");
+        fs::create_dir_all(&module_dir).unwrap();
+        let module_filepath = module_dir.join("pam_foo.so");
+        write_test_elf(&module_filepath, EM_X86_64, &["libfoo.so.1"], "");
+        fs::set_permissions(&module_filepath, Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(
+            filter_linked_files(&SystemRoot::default(), vec![module_filepath.clone()]),
+            [fs::canonicalize(&module_filepath).unwrap()]
+        );
+    }
+
+    #[test]
+    fn test_linked_file_kinds() {
+        let library = Path::new("/usr/lib/libfoo.so.1");
+        let plugin = Path::new("/usr/lib/foo/plugins/bar.so");
+        let executable = Path::new("/usr/bin/foo");
+        assert!(LinkedFileKinds::All.includes(library));
+        assert!(LinkedFileKinds::All.includes(executable));
+        assert!(LinkedFileKinds::SharedObjects.includes(library));
+        assert!(LinkedFileKinds::SharedObjects.includes(plugin));
+        assert!(!LinkedFileKinds::SharedObjects.includes(executable));
+        assert!(!LinkedFileKinds::Executables.includes(library));
+        assert!(!LinkedFileKinds::Executables.includes(plugin));
+        assert!(LinkedFileKinds::Executables.includes(executable));
+    }
+
+    #[test]
+    fn test_filter_linked_files_symlinks() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let tmp_path = fs::canonicalize(tmp_dir.path()).unwrap();
+        let exec_filepath = tmp_path.join("foo-real");
+        File::create(&exec_filepath)
+            .unwrap()
+            .set_permissions(Permissions::from_mode(0o700))
+            .unwrap();
+        std::os::unix::fs::symlink("foo-real", tmp_path.join("foo")).unwrap();
+        std::os::unix::fs::symlink("missing", tmp_path.join("bar")).unwrap();
+
+        let files = vec![
+            tmp_path.join("foo"),
+            exec_filepath.clone(),
+            tmp_path.join("bar"),
+        ];
+        assert_eq!(
+            filter_linked_files(&SystemRoot::default(), files),
+            [exec_filepath]
+        );
+
+        // Absolute targets of a checked root are in that root, not in the running system
+        let root = tmp_path.join("root");
+        let bin_dir = root.join("usr/bin");
+        fs::create_dir_all(&bin_dir).unwrap();
+        std::os::unix::fs::symlink("usr/bin", root.join("bin")).unwrap();
+        let root_exec_filepath = bin_dir.join("baz-real");
+        File::create(&root_exec_filepath)
+            .unwrap()
+            .set_permissions(Permissions::from_mode(0o700))
+            .unwrap();
+        std::os::unix::fs::symlink("/bin/baz-real", bin_dir.join("baz")).unwrap();
+        std::os::unix::fs::symlink("/bin/sh", bin_dir.join("sh")).unwrap();
+        let system_root = SystemRoot {
+            root: Some(root),
+            dbpath: None,
+        };
+        assert_eq!(
+            filter_linked_files(&system_root, vec![bin_dir.join("baz"), bin_dir.join("sh")]),
+            [root_exec_filepath]
+        );
+    }
+
+    #[test]
+    fn test_find_program_in() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let bin_dir = tmp_dir.path().join("bin");
+        fs::create_dir(&bin_dir).unwrap();
+        File::create(bin_dir.join("foo"))
+            .unwrap()
+            .set_permissions(Permissions::from_mode(0o755))
+            .unwrap();
+        File::create(bin_dir.join("bar")).unwrap();
+        let path_var =
+            std::env::join_paths([tmp_dir.path().join("missing"), bin_dir.clone()]).unwrap();
+
+        assert_eq!(find_program_in("foo", &path_var), Some(bin_dir.join("foo")));
+        assert_eq!(find_program_in("bar", &path_var), None);
+        assert_eq!(find_program_in("baz", &path_var), None);
+    }
+
+    #[test]
+    fn test_run_with_timeout() {
+        let output = run_with_timeout(
+            Command::new("sh").args(["-c", "echo out; echo err >&2"]),
+            Duration::from_secs(5),
+        )
+        .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"err\n");
+
+        assert!(
+            run_with_timeout(Command::new("sleep").arg("10"), Duration::from_millis(100)).is_err()
+        );
+    }
+
+    #[test]
+    fn test_system_root() {
+        let running_system = SystemRoot::default();
+        assert_eq!(running_system.path("/usr/lib"), Path::new("/usr/lib"));
+        assert_eq!(
+            running_system.logical_path(Path::new("/usr/lib")),
+            Path::new("/usr/lib")
+        );
+        assert_eq!(running_system.db_dir(), Path::new("/var/lib/pacman"));
+        assert_eq!(
+            running_system.db_lock_path(),
+            Path::new("/var/lib/pacman/db.lck")
+        );
+
+        let chroot = SystemRoot {
+            root: Some(PathBuf::from("/srv/chroot")),
+            dbpath: None,
+        };
+        assert_eq!(chroot.path("/usr/lib"), Path::new("/srv/chroot/usr/lib"));
+        assert_eq!(
+            chroot.logical_path(Path::new("/srv/chroot/usr/lib")),
+            Path::new("/usr/lib")
+        );
+        assert_eq!(
+            chroot.logical_path(Path::new("/srv/chroot")),
+            Path::new("/")
+        );
+        assert_eq!(
+            chroot.logical_path(Path::new("/usr/lib")),
+            Path::new("/usr/lib")
+        );
+        assert_eq!(chroot.db_dir(), Path::new("/srv/chroot/var/lib/pacman"));
+
+        let dbpath_chroot = SystemRoot {
+            dbpath: Some(PathBuf::from("/srv/db")),
+            ..chroot
+        };
+        assert_eq!(dbpath_chroot.db_dir(), Path::new("/srv/db"));
+        assert_eq!(dbpath_chroot.db_lock_path(), Path::new("/srv/db/db.lck"));
+    }
+
+    #[test]
+    fn test_run_with_retries() {
+        let attempts = AtomicUsize::new(0);
+        let cmd = |program: &str, args: &[&str]| {
+            attempts.fetch_add(1, Ordering::Relaxed);
+            let mut cmd = Command::new(program);
+            cmd.args(args);
+            cmd
+        };
+
+        let is_locked =
+            |output: &Output| String::from_utf8_lossy(&output.stderr).contains(PACMAN_LOCKED_ERROR);
+
+        // Completed commands are not retried, even if they fail
+        let output = run_with_retries(
+            || cmd("false", &[]),
+            Duration::from_secs(5),
+            3,
+            Duration::ZERO,
+            is_locked,
+        )
+        .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 1);
+
+        // Transient failures are retried until the last attempt, which is returned
+        let output = run_with_retries(
+            || {
+                cmd(
+                    "sh",
+                    &["-c", "echo 'error: unable to lock database' >&2; exit 1"],
+                )
+            },
+            Duration::from_secs(5),
+            3,
+            Duration::ZERO,
+            is_locked,
+        )
+        .unwrap();
+        assert!(!output.status.success());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 3);
+
+        // Timeouts are not retried
+        assert!(run_with_retries(
+            || cmd("sleep", &["10"]),
+            Duration::from_millis(50),
+            3,
+            Duration::ZERO,
+            is_locked,
+        )
+        .is_err());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 1);
+
+        // Missing programs are not retried
+        assert!(run_with_retries(
+            || cmd("/nonexistent/pacman", &[]),
+            Duration::from_secs(5),
+            3,
+            Duration::ZERO,
+            is_locked,
+        )
+        .is_err());
+        assert_eq!(attempts.swap(0, Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let cache = QueryCache::default();
+        let query_count = Mutex::new(0);
+        cb_thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|_| {
+                    let value = cache
+                        .get_or_try_insert_with("libfoo.so", || {
+                            *query_count.lock().unwrap() += 1;
+                            thread::sleep(Duration::from_millis(50));
+                            Ok(vec!["foo".to_string()])
+                        })
+                        .unwrap();
+                    assert_eq!(value, ["foo"]);
+                });
+            }
+        })
+        .unwrap();
+        assert_eq!(*query_count.lock().unwrap(), 1);
+
+        // Errors are not cached
+        assert!(cache
+            .get_or_try_insert_with("libbar.so", || anyhow::bail!("failure"))
+            .is_err());
+        assert_eq!(
+            cache
+                .get_or_try_insert_with("libbar.so", || Ok(vec!["bar".to_string()]))
+                .unwrap(),
+            ["bar"]
+        );
+    }
+
+    #[test]
+    fn test_ld_search_paths() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let conf_path = tmp_dir.path().join("ld.so.conf");
+        fs::write(
+            &conf_path,
+            "# comment\n/opt/a/lib\ninclude conf.d/*.conf\n/opt/b/lib:/usr/lib # trailing comment\n",
+        )
+        .unwrap();
+        fs::create_dir(tmp_dir.path().join("conf.d")).unwrap();
+        fs::write(tmp_dir.path().join("conf.d/2.conf"), "/opt/d/lib\n").unwrap();
+        fs::write(tmp_dir.path().join("conf.d/1.conf"), "\n/opt/c/lib\n").unwrap();
+        fs::write(tmp_dir.path().join("conf.d/ignored"), "/opt/e/lib\n").unwrap();
+
+        // The standard configuration directory is read even without include
+        fs::create_dir(tmp_dir.path().join("ld.so.conf.d")).unwrap();
+        fs::write(tmp_dir.path().join("ld.so.conf.d/f.conf"), "/opt/f/lib\n").unwrap();
+
+        assert_eq!(
+            ld_search_paths_from(&SystemRoot::default(), &conf_path).unwrap(),
+            [
+                PathBuf::from("/opt/a/lib"),
+                PathBuf::from("/opt/c/lib"),
+                PathBuf::from("/opt/d/lib"),
+                PathBuf::from("/opt/b/lib"),
+                PathBuf::from("/usr/lib"),
+                PathBuf::from("/opt/f/lib"),
+                PathBuf::from("/usr/lib64"),
+            ]
+        );
+
+        assert_eq!(
+            ld_search_paths_from(&SystemRoot::default(), &tmp_dir.path().join("missing.conf"))
+                .unwrap(),
+            [
+                PathBuf::from("/opt/f/lib"),
+                PathBuf::from("/usr/lib"),
+                PathBuf::from("/usr/lib64")
+            ]
+        );
+
+        // Explicitly included files keep their position
+        fs::write(&conf_path, "include ld.so.conf.d/*.conf\n/opt/a/lib\n").unwrap();
+        assert_eq!(
+            ld_search_paths_from(&SystemRoot::default(), &conf_path).unwrap(),
+            [
+                PathBuf::from("/opt/f/lib"),
+                PathBuf::from("/opt/a/lib"),
+                PathBuf::from("/usr/lib"),
+                PathBuf::from("/usr/lib64")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_python_dirs() {
+        assert_eq!(
+            python_dirs(&SystemRoot::default(), &"2.7.18-7".parse().unwrap()).unwrap(),
+            [(
+                "/usr/lib/python2".to_owned(),
+                "/usr/lib/python2.7".to_owned()
+            )]
+        );
+
+        assert_eq!(
+            pypy3_current_dir(
+                &SystemRoot::default(),
+                &[
+                    PathBuf::from("/opt/pypy3/bin/pypy3"),
+                    PathBuf::from("/opt/pypy3/lib/"),
+                    PathBuf::from("/opt/pypy3/lib/libpypy3.10-c.so"),
+                    PathBuf::from("/opt/pypy3/lib/pypy3.10/"),
+                    PathBuf::from("/opt/pypy3/lib/pypy3.10/os.py"),
+                ]
+            ),
+            Some("/opt/pypy3/lib/pypy3.10".to_owned())
+        );
+        assert_eq!(
+            pypy3_current_dir(
+                &SystemRoot::default(),
+                &[PathBuf::from("/opt/pypy3/bin/pypy3")]
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_qt_dirs() {
+        assert_eq!(
+            qt_dirs(&SystemRoot::default(), &"6.7.2-1".parse().unwrap()).unwrap(),
+            [
+                (
+                    "/usr/lib/qt/plugins".to_owned(),
+                    "/usr/lib/qt6/plugins".to_owned()
+                ),
+                (
+                    "/usr/lib/qt6/plugins".to_owned(),
+                    "/usr/lib/qt6/plugins".to_owned()
+                )
+            ]
+        );
+        assert_eq!(
+            qt_dirs(&SystemRoot::default(), &"5.15.14-1".parse().unwrap())
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_gtk_dirs() {
+        assert_eq!(
+            gtk_dirs(&SystemRoot::default(), &"4.14.4-1".parse().unwrap()).unwrap(),
+            [("/usr/lib/gtk-".to_owned(), "/usr/lib/gtk-4.0".to_owned())]
+        );
+        assert_eq!(
+            gtk_dirs(&SystemRoot::default(), &"3.24.43-1".parse().unwrap()).unwrap(),
+            [("/usr/lib/gtk-".to_owned(), "/usr/lib/gtk-3.0".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_parse_path_owners() {
+        let owners = parse_path_owners(
+            "/usr/lib/python3.11/ is owned by python-foo 1.0-1
+/usr/lib/python3.11/ is owned by python-bar 2.1-3
+/usr/lib/python3.10/ is owned by python-foo 1.0-1
+",
+        );
+        assert_eq!(owners.len(), 2);
+        assert_eq!(owners["/usr/lib/python3.11"], ["python-foo", "python-bar"]);
+        assert_eq!(owners["/usr/lib/python3.10"], ["python-foo"]);
+        assert!(parse_path_owners("").is_empty());
+    }
+
+    #[test]
+    fn test_stranded_dirs_packages() {
+        let stranded_dirs = [
+            "/usr/lib/python3.9".to_string(),
+            "/usr/lib/python3.10".to_string(),
+            "/usr/lib/python3.9".to_string(),
+            "/usr/lib/python3.8".to_string(),
+        ];
+        let dirs_owners: HashMap<String, Vec<String>> = vec![
+            (
+                "/usr/lib/python3.10".to_string(),
+                vec!["python-foo".to_string(), "python-bar".to_string()],
+            ),
+            (
+                "/usr/lib/python3.9".to_string(),
+                vec!["python-foo".to_string(), "python-foo".to_string()],
+            ),
+        ]
+        .into_iter()
+        .collect();
+        // Directory order is kept, and each package and directory couple only appears once
+        assert_eq!(
+            stranded_dirs_packages(&stranded_dirs, &dirs_owners),
+            [
+                ("python-foo".to_string(), "/usr/lib/python3.9".to_string()),
+                ("python-foo".to_string(), "/usr/lib/python3.10".to_string()),
+                ("python-bar".to_string(), "/usr/lib/python3.10".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_provider_cache() {
+        let tmp_dir = TempDir::new("").unwrap();
+        assert_eq!(files_databases_timestamp_in(tmp_dir.path()), None);
+        File::create(tmp_dir.path().join("core.files")).unwrap();
+        let timestamp = files_databases_timestamp_in(tmp_dir.path()).unwrap();
+
+        let cache_filepath = tmp_dir.path().join("cache").join("providers.json");
+        assert!(load_provider_cache(&cache_filepath, timestamp).is_empty());
+
+        let cache = QueryCache::with_entries(
+            vec![("libfoo.so".to_string(), vec!["foo".to_string()])]
+                .into_iter()
+                .collect(),
+        );
+        cache
+            .get_or_try_insert_with("libbar.so", || Ok(vec!["bar".to_string()]))
+            .unwrap();
+        assert!(cache
+            .get_or_try_insert_with("libbaz.so", || anyhow::bail!("failure"))
+            .is_err());
+        save_provider_cache(&cache_filepath, timestamp, cache.computed_entries()).unwrap();
+
+        let providers = load_provider_cache(&cache_filepath, timestamp);
+        assert_eq!(providers.len(), 2);
+        assert_eq!(providers["libfoo.so"], ["foo"]);
+        assert_eq!(providers["libbar.so"], ["bar"]);
+
+        // Invalidated when file databases are synced again
+        assert!(load_provider_cache(&cache_filepath, timestamp + 1).is_empty());
+    }
+
+    #[test]
+    fn test_classify_missing_library() {
+        assert!(is_other_soname_version("libfoo.so.2", "libfoo.so.1"));
+        assert!(is_other_soname_version("libfoo.so", "libfoo.so.1"));
+        assert!(!is_other_soname_version("libfoo.so.1", "libfoo.so.1"));
+        assert!(!is_other_soname_version("libfoobar.so.1", "libfoo.so.1"));
+
+        let tmp_dir = TempDir::new("").unwrap();
+        File::create(tmp_dir.path().join("libbar.so.3")).unwrap();
+        let lib_dirs = [tmp_dir.path().to_path_buf()];
+        let mut ld_cache = LdCache::new();
+        ld_cache.insert(
+            "libfoo.so.2".to_string(),
+            vec![PathBuf::from("/usr/lib/libfoo.so.2")],
+        );
+        assert_eq!(
+            classify_missing_library("libfoo.so.1", &lib_dirs, &ld_cache),
+            MissingKind::VersionBump
+        );
+        assert_eq!(
+            classify_missing_library("libbar.so.2", &lib_dirs, &ld_cache),
+            MissingKind::VersionBump
+        );
+        assert_eq!(
+            classify_missing_library("libbaz.so.1", &lib_dirs, &ld_cache),
+            MissingKind::Absent
+        );
+    }
+
+    #[test]
+    fn test_escape_regex() {
+        assert_eq!(escape_regex("libstdc++.so"), "libstdc\\+\\+\\.so");
+        assert_eq!(escape_regex("libfoo-1_2"), "libfoo-1_2");
+    }
+
+    #[test]
+    fn test_soname_versions_regex() {
+        assert_eq!(soname_versions_regex("libfoo.so"), ".*/libfoo\\.so\\..*");
+        assert_eq!(soname_versions_regex("libfoo.so.1"), ".*/libfoo\\.so\\..*");
+        // Another major version finds the same packages
+        assert_eq!(
+            soname_versions_regex("libstdc++.so.6"),
+            soname_versions_regex("libstdc++.so.5")
+        );
+        assert_eq!(
+            soname_versions_regex("libstdc++.so.6"),
+            ".*/libstdc\\+\\+\\.so\\..*"
+        );
+    }
+
+    #[test]
+    fn test_parse_typelib_shared_libraries() {
+        let mut data = TYPELIB_MAGIC.to_vec();
+        data.resize(120, 0);
+        assert_eq!(parse_typelib_shared_libraries(&data), Some(Vec::new()));
+        data[TYPELIB_SHARED_LIBRARY_OFFSET..TYPELIB_SHARED_LIBRARY_OFFSET + 4]
+            .copy_from_slice(&100u32.to_le_bytes());
+        data[100..118].copy_from_slice(b"libfoo.so.1,libbar");
+        assert_eq!(
+            parse_typelib_shared_libraries(&data).unwrap(),
+            ["libfoo.so.1", "libbar"]
+        );
+
+        // Unterminated string
+        data.truncate(110);
+        assert_eq!(parse_typelib_shared_libraries(&data), None);
+        assert_eq!(parse_typelib_shared_libraries(b"not a typelib"), None);
+    }
+
+    #[test]
+    fn test_parse_log_timestamp() {
+        assert_eq!(
+            LogTimestamp::from_str("2024-01-31T18:05:12+0100").unwrap(),
+            LogTimestamp([2024, 1, 31, 18, 5, 12])
+        );
+        assert_eq!(
+            LogTimestamp::from_str("2019-03-01 12:34").unwrap(),
+            LogTimestamp([2019, 3, 1, 12, 34, 0])
+        );
+        assert_eq!(
+            LogTimestamp::from_str("2024-02-01").unwrap(),
+            LogTimestamp([2024, 2, 1, 0, 0, 0])
+        );
+        assert!(
+            LogTimestamp::from_str("2024-02-01").unwrap()
+                > LogTimestamp::from_str("2024-01-31T18:05:12-0500").unwrap()
+        );
+        assert!(LogTimestamp::from_str("yesterday").is_err());
+        assert!(LogTimestamp::from_str("2024-13-01").is_err());
+        assert!(LogTimestamp::from_str("2024-01-01T12").is_err());
+    }
+
+    #[test]
+    fn test_parse_pacman_log_changes() {
+        let log = "[2019-03-01 12:34] [ALPM] upgraded old (1.0-1 -> 1.1-1)
+[2024-01-31T18:05:12+0100] [PACMAN] Running 'pacman -Syu'
+[2024-01-31T18:05:40+0100] [ALPM] upgraded foo (1.0-1 -> 1.1-1)
+[2024-01-31T18:05:41+0100] [ALPM] installed bar (2.0-1)
+[2024-01-31T18:05:41+0100] [ALPM] removed baz (3.0-1)
+[2024-01-31T18:05:42+0100] [ALPM] running 'ldconfig.hook'...
+garbage
+";
+        let mut packages: Vec<String> =
+            parse_pacman_log_changes(log, LogTimestamp::from_str("2024-01-31").unwrap())
+                .into_iter()
+                .collect();
+        packages.sort();
+        assert_eq!(packages, ["bar", "foo"]);
+        assert!(
+            parse_pacman_log_changes(log, LogTimestamp::from_str("2024-02-01").unwrap()).is_empty()
+        );
+    }
+
+    #[test]
+    fn test_get_stranded_pip_packages() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let home = tmp_dir.path();
+        for dir in [
+            ".local/lib/python3.11/site-packages/requests-2.31.0.dist-info",
+            ".local/lib/python3.11/site-packages/numpy-1.26.0.dist-info",
+            ".local/lib/python3.11/site-packages/zope.interface-6.1.dist-info",
+            ".local/lib/python3.11/site-packages/olddist-0.1-py3.11.egg-info",
+            ".local/lib/python3.12/site-packages/rich-13.7.0.dist-info",
+            ".local/share/pipx/venvs/black/lib/python3.11/site-packages",
+            ".local/share/pipx/venvs/httpie/lib/python3.12/site-packages",
+        ] {
+            fs::create_dir_all(home.join(dir)).unwrap();
+        }
+
+        let version = PackageVersion::from_str("3.12.1-1").unwrap();
+        let packages = get_stranded_pip_packages_in(home, &version).unwrap();
+        let stranded: Vec<(&str, String)> = packages
+            .iter()
+            .map(|p| {
+                (
+                    p.package.as_str(),
+                    p.dir
+                        .strip_prefix(&*home.to_string_lossy())
+                        .unwrap()
+                        .to_owned(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            stranded,
+            [
+                (
+                    "black",
+                    "/.local/share/pipx/venvs/black/lib/python3.11".to_owned()
+                ),
+                ("numpy", "/.local/lib/python3.11".to_owned()),
+                ("olddist", "/.local/lib/python3.11".to_owned()),
+                ("requests", "/.local/lib/python3.11".to_owned()),
+                ("zope.interface", "/.local/lib/python3.11".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_provider_index() {
+        let index = ProviderIndex::parse(
+            "zstd usr/bin/zstd
+zstd usr/lib/libzstd.so
+zstd usr/lib/libzstd.so.1
+zstd usr/lib/libzstd.so.1.5.5
+lib32-zstd usr/lib32/libzstd.so.1
+qt6-base usr/lib/libQt6Core.so.6
+qt6-base usr/lib/libQt6Core.so.6.6.1
+qt6-base usr/lib/libQt6CoreE.so.6
+malformed
+",
+        );
+        assert_eq!(index.get("libzstd.so"), ["zstd"]);
+        assert_eq!(index.get("libzstd.so.1"), ["zstd", "lib32-zstd"]);
+        assert_eq!(index.get("libQt6Core.so"), ["qt6-base"]);
+        assert!(index.get("zstd").is_empty());
+        assert!(index.get("libfoo.so").is_empty());
+    }
+
+    #[test]
+    fn test_parse_file_databases_output() {
+        assert_eq!(
+            parse_file_databases_output("extra/zstd\nunexpected\n\ncore/zlib\ncore/zlib\n"),
+            ["zstd", "zlib"]
+        );
+        assert!(parse_file_databases_output("").is_empty());
+    }
+
+    #[test]
+    fn test_wait_for_lock_release() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let lock_path = tmp_dir.path().join("db.lck");
+        let poll_interval = Duration::from_millis(10);
+        let stop = AtomicBool::new(false);
+        assert!(wait_for_lock_release(
+            &lock_path,
+            poll_interval,
+            Duration::ZERO,
+            &stop
+        ));
+
+        File::create(&lock_path).unwrap();
+        assert!(!wait_for_lock_release(
+            &lock_path,
+            poll_interval,
+            Duration::from_millis(30),
+            &stop
+        ));
+        let unlock = {
+            let lock_path = lock_path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(30));
+                fs::remove_file(lock_path).unwrap();
+            })
+        };
+        assert!(wait_for_lock_release(
+            &lock_path,
+            poll_interval,
+            Duration::from_secs(5),
+            &stop
+        ));
+        unlock.join().unwrap();
+    }
+
+    #[test]
+    fn test_wait_for_dir_change() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let db_dir = tmp_dir.path().join("local");
+        let lock_path = tmp_dir.path().join("db.lck");
+        fs::create_dir(&db_dir).unwrap();
+        let stop_check_interval = Duration::from_millis(10);
+        let settle_delay = Duration::from_millis(50);
+
+        // Stopped before any change
+        let stop = AtomicBool::new(true);
+        assert!(!wait_for_dir_change(
+            &db_dir,
+            &lock_path,
+            stop_check_interval,
+            settle_delay,
+            &stop
+        )
+        .unwrap());
+
+        // Changed while locked, only done once unlocked
+        stop.store(false, Ordering::Relaxed);
+        File::create(&lock_path).unwrap();
+        let change = {
+            let db_dir = db_dir.clone();
+            let lock_path = lock_path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(30));
+                fs::create_dir(db_dir.join("foo-1.0-1")).unwrap();
+                thread::sleep(Duration::from_millis(100));
+                fs::remove_file(lock_path).unwrap();
+            })
+        };
+        let start = Instant::now();
+        assert!(wait_for_dir_change(
+            &db_dir,
+            &lock_path,
+            stop_check_interval,
+            settle_delay,
+            &stop
+        )
+        .unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(130));
+        change.join().unwrap();
+
+        // Reading entries is not a change
+        let stop_after = |delay| {
+            let stop = Arc::new(AtomicBool::new(false));
+            let stopper = {
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    thread::sleep(delay);
+                    stop.store(true, Ordering::Relaxed);
+                })
+            };
+            (stop, stopper)
+        };
+        fs::write(db_dir.join("foo-1.0-1").join("desc"), "%NAME%\nfoo\n").unwrap();
+        let (stop, stopper) = stop_after(Duration::from_millis(150));
+        let read = {
+            let db_dir = db_dir.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(30));
+                fs::read_dir(&db_dir).unwrap().for_each(drop);
+                fs::read_to_string(db_dir.join("foo-1.0-1").join("desc")).unwrap();
+            })
+        };
+        assert!(!wait_for_dir_change(
+            &db_dir,
+            &lock_path,
+            stop_check_interval,
+            settle_delay,
+            &stop
+        )
+        .unwrap());
+        read.join().unwrap();
+        stopper.join().unwrap();
+
+        // A missing directory can not be watched
+        assert!(wait_for_dir_change(
+            &tmp_dir.path().join("missing"),
+            &lock_path,
+            stop_check_interval,
+            settle_delay,
+            &AtomicBool::new(false)
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_parse_desktop_entry_programs() {
+        assert_eq!(
+            split_desktop_exec(r#""/opt/Foo App/foo" --name "say \"hi\"" %U"#),
+            ["/opt/Foo App/foo", "--name", "say \"hi\"", "%U"]
+        );
+
+        let entry = "[Desktop Entry]\n\
+                     Name=Foo\n\
+                     TryExec=foo\n\
+                     Exec=foo %F\n\
+                     Exec[fr]=bar\n\
+                     \n\
+                     [Desktop Action new-window]\n\
+                     Exec = env FOO=1 -u BAR /usr/bin/foo --new-window\n\
+                     \n\
+                     [Desktop Action private]\n\
+                     Exec=foo --private\n";
+        assert_eq!(parse_desktop_entry_programs(entry), ["foo", "/usr/bin/foo"]);
+
+        let tmp_dir = TempDir::new("").unwrap();
+        let program_filepath = tmp_dir.path().join("foo");
+        File::create(&program_filepath)
+            .unwrap()
+            .set_permissions(Permissions::from_mode(0o755))
+            .unwrap();
+        let path_var = tmp_dir.path().as_os_str();
+        assert!(desktop_entry_program_exists(
+            &SystemRoot::default(),
+            "foo",
+            path_var
+        ));
+        assert!(desktop_entry_program_exists(
+            &SystemRoot::default(),
+            &program_filepath.to_string_lossy(),
+            path_var
+        ));
+        assert!(!desktop_entry_program_exists(
+            &SystemRoot::default(),
+            "bar",
+            path_var
+        ));
+        assert!(!desktop_entry_program_exists(
+            &SystemRoot::default(),
+            &tmp_dir.path().join("bar").to_string_lossy(),
+            path_var
+        ));
+    }
+
+    #[test]
+    fn test_parse_udev_rule_programs() {
+        assert_eq!(
+            parse_udev_rule_programs(
+                r#"ACTION=="add", SUBSYSTEM=="usb", PROGRAM="/usr/bin/foo-id %k", RUN+="/usr/lib/foo/helper --add""#
+            ),
+            [
+                PathBuf::from("/usr/bin/foo-id"),
+                PathBuf::from("/usr/lib/foo/helper")
+            ]
+        );
+        assert_eq!(
+            parse_udev_rule_programs(
+                r#"RUN{program}+="/usr/bin/bar", RUN{builtin}+="kmod load foo""#
+            ),
+            [PathBuf::from("/usr/bin/bar")]
+        );
+        assert!(parse_udev_rule_programs(r#"RUN+="foo-helper", RUN+="$env{FOO}/bar""#).is_empty());
+        assert!(parse_udev_rule_programs(r#"# RUN+="/usr/bin/foo""#).is_empty());
+        assert!(parse_udev_rule_programs(r#"RUN+="/usr/bin/foo"#).is_empty());
+
+        let tmp_dir = TempDir::new("").unwrap();
+        let helper_filepath = tmp_dir.path().join("helper");
+        File::create(&helper_filepath)
+            .unwrap()
+            .set_permissions(Permissions::from_mode(0o755))
+            .unwrap();
+        let script_filepath = tmp_dir.path().join("script");
+        File::create(&script_filepath).unwrap();
+        let rules = format!(
+            "KERNEL==\"foo*\", \\\n  RUN+=\"{}\"\nRUN+=\"{}\"\nRUN+=\"{}\"\n",
+            helper_filepath.display(),
+            script_filepath.display(),
+            tmp_dir.path().join("missing").display()
+        );
+        assert_eq!(
+            find_broken_udev_programs(&SystemRoot::default(), &rules),
+            [script_filepath, tmp_dir.path().join("missing")]
+        );
+    }
+
+    #[test]
+    fn test_get_file_dependencies() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let machine = host_machines().first().copied().unwrap_or(EM_X86_64);
+        write_test_elf(&tmp_dir.path().join("libfoo.so.1"), machine, &[], "");
+        let exec_file = tmp_dir.path().join("foo");
+        write_test_elf(
+            &exec_file,
+            machine,
+            &["libfoo.so.1", "libbar.so.2"],
+            "$ORIGIN",
+        );
+
+        assert_eq!(
+            get_file_dependencies(&SystemRoot::default(), &exec_file, &[], &LdCache::new())
+                .unwrap(),
+            Some(FileDependencies {
+                file: exec_file.clone(),
+                needed: vec!["libfoo.so.1".to_string(), "libbar.so.2".to_string()],
+                missing: vec!["libbar.so.2".to_string()],
+            })
+        );
+
+        let text_file = tmp_dir.path().join("README");
+        fs::write(&text_file, "foo").unwrap();
+        assert_eq!(
+            get_file_dependencies(&SystemRoot::default(), &text_file, &[], &LdCache::new())
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_package_version() {
+        for (version_str, expected) in [
+            ("3.12.1-2", "3.12.1-2"),
+            ("2:3.12.1-2", "3.12.1-2"),
+            ("3.12-1", "3.12.0-1"),
+            ("3.12", "3.12.0-0"),
+            ("3.13.0rc1-1", "3.13.0-1"),
+            ("3.11.4-1.1", "3.11.4-1"),
+        ] {
+            assert_eq!(
+                version_str.parse::<PackageVersion>().unwrap().to_string(),
+                expected
+            );
+        }
+        assert!("3".parse::<PackageVersion>().is_err());
+        assert!("a.b.c-1".parse::<PackageVersion>().is_err());
+    }
+
+    #[test]
+    fn test_sort_by_dependencies() {
+        let packages: Vec<String> = ["a", "b", "c", "d"].iter().map(|p| p.to_string()).collect();
+        let mut reverse_deps = HashMap::new();
+        // d <- b <- a, c is independent, and x is not in the set
+        reverse_deps.insert("d".to_string(), vec!["b".to_string(), "x".to_string()]);
+        reverse_deps.insert("b".to_string(), vec!["a".to_string()]);
+        assert_eq!(
+            sort_by_dependencies(&packages, &reverse_deps).unwrap(),
+            ["c", "d", "b", "a"]
+        );
+
+        reverse_deps.insert("a".to_string(), vec!["d".to_string()]);
+        assert!(sort_by_dependencies(&packages, &reverse_deps).is_none());
+    }
+
+    #[test]
+    fn test_packages_by_provider() {
+        let missing_library = |soname: &str, provider: Option<&str>| MissingLibrary {
+            soname: soname.to_string(),
+            files: Vec::new(),
+            provider: provider.map(|p| p.to_string()),
+            provider_installed: true,
+            kind: MissingKind::VersionBump,
+        };
+        let mut report = BrokenReport::default();
+        for (package, missing) in [
+            (
+                "mpv-git",
+                vec![
+                    missing_library("libavcodec.so.60", Some("ffmpeg")),
+                    missing_library("libavformat.so.60", Some("ffmpeg")),
+                ],
+            ),
+            (
+                "foo",
+                vec![
+                    missing_library("libavcodec.so.60", Some("ffmpeg")),
+                    missing_library("libicuuc.so.73", Some("icu")),
+                    missing_library("libbar.so.1", Some("?")),
+                    missing_library("libbaz.so.1", None),
+                ],
+            ),
+        ] {
+            report.broken_packages.push(BrokenPackage {
+                package: package.to_string(),
+                missing,
+                required_by: Vec::new(),
+                security_modules: Vec::new(),
+            });
+        }
+
+        let packages_by_provider = report.packages_by_provider();
+        assert_eq!(packages_by_provider.len(), 2);
+        assert_eq!(packages_by_provider["ffmpeg"], ["foo", "mpv-git"]);
+        assert_eq!(packages_by_provider["icu"], ["foo"]);
+
+        report.broken_packages[0]
+            .missing
+            .push(missing_library("libicuuc.so.73", Some("icu")));
+        report.broken_packages[0]
+            .missing
+            .push(missing_library("libqux.so.2", Some("qux")));
+        assert_eq!(
+            report.breaking_providers(),
+            [
+                (
+                    "ffmpeg".to_string(),
+                    vec!["foo".to_string(), "mpv-git".to_string()]
+                ),
+                (
+                    "icu".to_string(),
+                    vec!["foo".to_string(), "mpv-git".to_string()]
+                ),
+                ("qux".to_string(), vec!["mpv-git".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summary_display() {
+        let mut report = BrokenReport::default();
+        assert_eq!(
+            Summary::new(&report, 1, Duration::from_millis(300)).to_string(),
+            "Scanned 1 package in 0.3s: no problem found."
+        );
+
+        report.transitively_broken_packages = vec!["a".to_string()];
+        report.broken_systemd_links = vec![BrokenLink::default(), BrokenLink::default()];
+        assert_eq!(
+            Summary::new(&report, 142, Duration::from_millis(18_300)).to_string(),
+            "Scanned 142 packages in 18.3s: 1 transitively broken, 2 broken systemd links."
+        );
+
+        report = BrokenReport::default();
+        report.unchecked_files.push(UncheckedFile {
+            package: "foo".to_string(),
+            file: PathBuf::from("/usr/bin/foo"),
+            error: "Failed to parse ELF file".to_string(),
+        });
+        assert!(report.is_clean());
+        assert_eq!(
+            Summary::new(&report, 1, Duration::from_millis(300)).to_string(),
+            "Scanned 1 package in 0.3s: no problem found (1 file could not be analyzed)."
+        );
+
+        report.unlisted_packages.push(UnlistedPackage {
+            package: "bar".to_string(),
+            error: "package 'bar' was not found".to_string(),
+        });
+        assert!(report.is_clean());
+        assert_eq!(
+            Summary::new(&report, 2, Duration::from_millis(300)).to_string(),
+            "Scanned 2 packages in 0.3s: no problem found (1 file could not be analyzed, 1 package \
+             could not be listed)."
+        );
+
+        report = BrokenReport::default();
+        report.duplicate_sonames.push(DuplicateSoname {
+            soname: "libfoo.so.1".to_string(),
+            providers: BTreeMap::new(),
+        });
+        assert!(report.is_clean());
+        assert_eq!(
+            Summary::new(&report, 1, Duration::from_millis(300)).to_string(),
+            "Scanned 1 package in 0.3s: no problem found.\n\
+             For information: 1 soname provided by several packages."
+        );
+    }
+
+    #[test]
+    fn test_parse_not_found_packages() {
+        assert_eq!(
+            parse_not_found_packages(
+                "error: package 'foo' was not found\nerror: package 'bar-git' was not found\n"
+            ),
+            ["foo", "bar-git"]
+        );
+        assert!(parse_not_found_packages("").is_empty());
+    }
+
+    #[test]
+    fn test_find_library_elf_class() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let exec_file = env::current_exe().unwrap();
+        let is_64 = read_elf_dyn_info(&SystemRoot::default(), &exec_file)
+            .unwrap()
+            .unwrap()
+            .is_64;
+        std::os::unix::fs::symlink(&exec_file, tmp_dir.path().join("libfoo.so.1")).unwrap();
+        let mut script_file = File::create(tmp_dir.path().join("libbar.so.1")).unwrap();
+        write!(&mut script_file, "not an ELF file").unwrap();
+        drop(script_file);
+
+        let lib_dirs = [tmp_dir.path().to_path_buf()];
+        assert_eq!(
+            find_library(
+                &SystemRoot::default(),
+                "libfoo.so.1",
+                lib_dirs.iter(),
+                is_64
+            ),
+            Some(tmp_dir.path().join("libfoo.so.1"))
+        );
+        // A library of the other class is ignored, like the loader does on multilib systems
+        assert!(find_library(
+            &SystemRoot::default(),
+            "libfoo.so.1",
+            lib_dirs.iter(),
+            !is_64
+        )
+        .is_none());
+        assert!(find_library(
+            &SystemRoot::default(),
+            "libbar.so.1",
+            lib_dirs.iter(),
+            is_64
+        )
+        .is_none());
+        assert!(find_library(
+            &SystemRoot::default(),
+            "libbaz.so.1",
+            lib_dirs.iter(),
+            is_64
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn test_is_elf_file() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let script_filepath = tmp_dir.path().join("script.sh");
+        let mut script_file = File::create(&script_filepath).unwrap();
+        write!(&mut script_file, "#!/bin/sh\necho hello").unwrap();
+        drop(script_file);
+        File::create(tmp_dir.path().join("empty")).unwrap();
+
+        assert!(is_elf_file(&env::current_exe().unwrap()));
+        assert!(!is_elf_file(&script_filepath));
+        assert!(!is_elf_file(&tmp_dir.path().join("empty")));
+        assert!(!is_elf_file(&tmp_dir.path().join("missing")));
+    }
+
+    #[test]
+    fn test_parse_ldconfig_output() {
+        let cache = parse_ldconfig_output(
+            "3 libs found in cache `/etc/ld.so.cache'
+\tlibz.so.1 (libc6,x86-64) => /usr/lib/libz.so.1
+\tlibz.so.1 (libc6) => /usr/lib32/libz.so.1
+\tld-linux-x86-64.so.2 (libc6,x86-64) => /usr/lib64/ld-linux-x86-64.so.2
+",
+        );
+        assert_eq!(cache.len(), 2);
+        assert_eq!(
+            cache["libz.so.1"],
+            [
+                PathBuf::from("/usr/lib/libz.so.1"),
+                PathBuf::from("/usr/lib32/libz.so.1")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_missing_dependencies_ld_cache() {
+        let exec_file = env::current_exe().unwrap();
+        let exec_info = read_elf_dyn_info(&SystemRoot::default(), &exec_file)
+            .unwrap()
+            .unwrap();
+        let ld_cache = load_ldconfig_cache(&SystemRoot::default()).unwrap_or_default();
+        if exec_info.needed.iter().any(|n| !ld_cache.contains_key(n)) {
+            // Not all libraries are in the cache, eg. on a system without ldconfig
+            return;
+        }
+
+        // Direct dependencies are found in the cache without any search directory
+        let missing_deps =
+            get_missing_dependencies(&SystemRoot::default(), &exec_file, &[], &ld_cache).unwrap();
+        assert!(missing_deps.direct.is_empty());
+        assert_eq!(missing_deps.found_direct, exec_info.needed);
+    }
+
+    #[test]
+    fn test_find_duplicate_sonames() {
+        let packages_files: HashMap<String, Vec<PathBuf>> = vec![
+            (
+                "foo".to_string(),
+                vec![
+                    PathBuf::from("/usr/lib/libfoo.so.1"),
+                    PathBuf::from("/usr/lib/libbaz.so.2"),
+                    PathBuf::from("/usr/lib/foo/plugin.so"),
+                ],
+            ),
+            (
+                "foo-bin".to_string(),
+                vec![
+                    PathBuf::from("/opt/foo/lib/libfoo.so.1"),
+                    PathBuf::from("/usr/lib/foo/plugin.so"),
+                ],
+            ),
+            (
+                "lib32-baz".to_string(),
+                vec![PathBuf::from("/usr/lib32/libbaz.so.2")],
+            ),
+        ]
+        .into_iter()
+        .collect();
+        let lib_dirs = [
+            PathBuf::from("/opt/foo/lib"),
+            PathBuf::from("/usr/lib"),
+            PathBuf::from("/usr/lib32"),
+        ];
+        assert_eq!(
+            find_duplicate_sonames(&SystemRoot::default(), &packages_files, &lib_dirs),
+            [DuplicateSoname {
+                soname: "libfoo.so.1".to_string(),
+                providers: vec![
+                    (
+                        "foo".to_string(),
+                        vec![PathBuf::from("/usr/lib/libfoo.so.1")]
+                    ),
+                    (
+                        "foo-bin".to_string(),
+                        vec![PathBuf::from("/opt/foo/lib/libfoo.so.1")]
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_files_databases_synced() {
+        let tmp_dir = TempDir::new("").unwrap();
+        assert!(!files_databases_synced_in(&tmp_dir.path().join("missing")));
+        File::create(tmp_dir.path().join("core.db")).unwrap();
+        File::create(tmp_dir.path().join("core.files")).unwrap();
+        assert!(!files_databases_synced_in(tmp_dir.path()));
+        fs::write(tmp_dir.path().join("extra.files"), b"data").unwrap();
+        assert!(files_databases_synced_in(tmp_dir.path()));
+    }
+
+    #[test]
+    fn test_expand_search_path() {
+        assert_eq!(
+            expand_search_path(
+                &SystemRoot::default(),
+                "$ORIGIN/../lib:/opt/foo/lib::${ORIGIN}",
+                Path::new("/usr/bin"),
+                true
+            ),
+            [
+                PathBuf::from("/usr/bin/../lib"),
+                PathBuf::from("/opt/foo/lib"),
+                PathBuf::from("/usr/bin")
+            ]
+        );
+        assert_eq!(
+            expand_search_path(
+                &SystemRoot::default(),
+                "/opt/foo/$LIB:/usr/${LIB}/foo",
+                Path::new("/"),
+                false
+            ),
+            [
+                PathBuf::from("/opt/foo/lib32"),
+                PathBuf::from("/usr/lib32/foo")
+            ]
+        );
+    }
+
+    /// Write a minimal 64-bit ELF shared object for a machine type, with only a dynamic section
+    fn write_test_elf(path: &Path, machine: u16, needed: &[&str], runpath: &str) {
+        write_test_library(path, machine, "", needed, runpath);
+    }
+
+    /// Write a minimal 64-bit ELF shared object like `write_test_elf`, with a DT_SONAME if not empty
+    fn write_test_library(path: &Path, machine: u16, soname: &str, needed: &[&str], runpath: &str) {
+        const EHDR_SIZE: usize = 64;
+        const PHDR_SIZE: usize = 56;
+        const STRTAB_OFFSET: usize = EHDR_SIZE + 2 * PHDR_SIZE;
+
+        // String table, NUL first
+        let mut strtab = vec![0];
+        let mut dyn_entries = Vec::new();
+        // DT_SONAME, DT_NEEDED entries, then DT_RUNPATH
+        let soname = Some((14, &soname)).filter(|(_, s)| !s.is_empty());
+        let names = soname.into_iter().chain(needed.iter().map(|n| (1, n)));
+        for (tag, name) in names.chain(std::iter::once((29, &runpath))) {
+            dyn_entries.push((tag, strtab.len() as u64));
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+        dyn_entries.push((5, STRTAB_OFFSET as u64)); // DT_STRTAB
+        dyn_entries.push((10, strtab.len() as u64)); // DT_STRSZ
+        dyn_entries.push((0, 0)); // DT_NULL
+        let dyn_offset = (STRTAB_OFFSET + strtab.len()).div_ceil(8) * 8;
+        let dyn_size = dyn_entries.len() * 16;
+        let file_size = (dyn_offset + dyn_size) as u64;
+
+        let mut data = Vec::new();
+        data.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&3u16.to_le_bytes()); // ET_DYN
+        data.extend_from_slice(&machine.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+        data.extend_from_slice(&(EHDR_SIZE as u64).to_le_bytes()); // e_phoff
+        data.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+        data.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+        for half in [EHDR_SIZE, PHDR_SIZE, 2, 64, 0, 0] {
+            data.extend_from_slice(&(half as u16).to_le_bytes());
+        }
+        for (p_type, offset, size) in [
+            (1u32, 0, file_size),
+            (2, dyn_offset as u64, dyn_size as u64),
+        ] {
+            data.extend_from_slice(&p_type.to_le_bytes());
+            data.extend_from_slice(&4u32.to_le_bytes()); // PF_R
+            for field in [offset, offset, offset, size, size, 8] {
+                data.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+        data.extend_from_slice(&strtab);
+        data.resize(dyn_offset, 0);
+        for (tag, val) in dyn_entries {
+            data.extend_from_slice(&(tag as u64).to_le_bytes());
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+        fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_get_missing_dependencies_runpath() {
+        let tmp_dir = TempDir::new("").unwrap();
+        fs::create_dir(tmp_dir.path().join("bin")).unwrap();
+        fs::create_dir(tmp_dir.path().join("lib")).unwrap();
+        let machine = host_machines().first().copied().unwrap_or(EM_X86_64);
+        write_test_elf(&tmp_dir.path().join("lib/libfoo.so.1"), machine, &[], "");
+
+        // Private libraries relative to the binary are found
+        let exec_file = tmp_dir.path().join("bin/foo");
+        write_test_elf(&exec_file, machine, &["libfoo.so.1"], "$ORIGIN/../lib");
+        let missing_deps =
+            get_missing_dependencies(&SystemRoot::default(), &exec_file, &[], &LdCache::new())
+                .unwrap();
+        assert!(missing_deps.direct.is_empty());
+        assert_eq!(missing_deps.found_direct, ["libfoo.so.1"]);
+
+        let exec_file = tmp_dir.path().join("bin/bar");
+        let runpath = format!("{}/$LIB", tmp_dir.path().display());
+        write_test_elf(
+            &exec_file,
+            machine,
+            &["libfoo.so.1", "libbar.so.1"],
+            &runpath,
+        );
+        let missing_deps =
+            get_missing_dependencies(&SystemRoot::default(), &exec_file, &[], &LdCache::new())
+                .unwrap();
+        assert_eq!(missing_deps.direct, ["libbar.so.1"]);
+    }
+
+    #[test]
+    fn test_get_missing_dependencies_mismatched_soname() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let lib_dir = tmp_dir.path().to_path_buf();
+        let machine = host_machines().first().copied().unwrap_or(EM_X86_64);
+        write_test_library(
+            &lib_dir.join("libfoo.so.2.0.0"),
+            machine,
+            "libfoo.so.2",
+            &[],
+            "",
+        );
+        std::os::unix::fs::symlink("libfoo.so.2.0.0", lib_dir.join("libfoo.so.2")).unwrap();
+        write_test_library(
+            &lib_dir.join("libbar.so.1"),
+            machine,
+            "libbar.so.1",
+            &[],
+            "",
+        );
+        write_test_elf(&lib_dir.join("libbaz.so"), machine, &[], "");
+
+        // A symlink to another version of the library is found, but does not provide the soname
+        std::os::unix::fs::symlink("libfoo.so.2.0.0", lib_dir.join("libfoo.so.1")).unwrap();
+        let exec_file = tmp_dir.path().join("foo");
+        write_test_elf(
+            &exec_file,
+            machine,
+            &["libfoo.so.1", "libfoo.so.2", "libbar.so.1", "libbaz.so"],
+            "",
+        );
+        let missing_deps = get_missing_dependencies(
+            &SystemRoot::default(),
+            &exec_file,
+            &[lib_dir],
+            &LdCache::new(),
+        )
+        .unwrap();
+        assert!(missing_deps.direct.is_empty());
+        assert_eq!(missing_deps.found_direct.len(), 4);
+        assert_eq!(missing_deps.mismatched_direct, ["libfoo.so.1"]);
+    }
+
+    #[test]
+    fn test_get_missing_dependencies_static() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let exec_file = tmp_dir.path().join("foo");
+        let machine = host_machines().first().copied().unwrap_or(EM_X86_64);
+        write_test_elf(&exec_file, machine, &["libfoo.so.1"], "");
+        assert!(
+            !read_elf_dyn_info(&SystemRoot::default(), &exec_file)
+                .unwrap()
+                .unwrap()
+                .is_static
+        );
+
+        // Keep only the PT_LOAD program header, dropping the dynamic section
+        let mut data = fs::read(&exec_file).unwrap();
+        data[56] = 1;
+        fs::write(&exec_file, data).unwrap();
+        let missing_deps =
+            get_missing_dependencies(&SystemRoot::default(), &exec_file, &[], &LdCache::new())
+                .unwrap();
+        assert!(missing_deps.is_static);
+        assert!(missing_deps.direct.is_empty() && missing_deps.transitive.is_empty());
+    }
+
+    #[test]
+    fn test_get_missing_dependencies_foreign_machine() {
+        let exec_file = env::current_exe().unwrap();
+        let exec_info = read_elf_dyn_info(&SystemRoot::default(), &exec_file)
+            .unwrap()
+            .unwrap();
+        assert!(is_host_machine(exec_info.machine));
+
+        // Libraries of a binary the host can not run are not searched
+        let tmp_dir = TempDir::new("").unwrap();
+        let exec_file = tmp_dir.path().join("foo");
+        let machine = [EM_X86_64, EM_AARCH64]
+            .iter()
+            .copied()
+            .find(|m| !is_host_machine(*m));
+        if let Some(machine) = machine {
+            write_test_elf(&exec_file, machine, &["libfoo.so.1"], "");
+            let missing_deps =
+                get_missing_dependencies(&SystemRoot::default(), &exec_file, &[], &LdCache::new())
+                    .unwrap();
+            assert!(missing_deps.direct.is_empty());
+            assert_eq!(missing_deps.foreign_machine, Some(machine));
+        }
+    }
+
+    #[test]
+    fn test_get_missing_dependencies() {
+        let exec_file = env::current_exe().unwrap();
+        let exec_info = read_elf_dyn_info(&SystemRoot::default(), &exec_file)
+            .unwrap()
+            .unwrap();
+        assert!(!exec_info.needed.is_empty());
+
+        // Nothing can be found in an empty search path
+        let missing_deps =
+            get_missing_dependencies(&SystemRoot::default(), &exec_file, &[], &LdCache::new())
+                .unwrap();
+        assert_eq!(missing_deps.direct, exec_info.needed);
+        assert!(missing_deps.transitive.is_empty());
+
+        // Everything can be found when searching in the directories where the loader found the libraries
+        let tmp_dir = TempDir::new("").unwrap();
+        for soname in &exec_info.needed {
+            let lib_path = [
+                "/usr/lib",
+                "/lib",
+                "/usr/lib64",
+                "/lib64",
+                "/lib/x86_64-linux-gnu",
+            ]
+            .iter()
+            .map(|d| Path::new(d).join(soname))
+            .find(|p| p.is_file());
+            if let Some(lib_path) = lib_path {
+                std::os::unix::fs::symlink(lib_path, tmp_dir.path().join(soname)).unwrap();
+            }
+        }
+        let lib_dirs = [tmp_dir.path().to_path_buf()];
+        let missing_deps = get_missing_dependencies(
+            &SystemRoot::default(),
+            &exec_file,
+            &lib_dirs,
+            &LdCache::new(),
+        )
+        .unwrap();
+        assert!(missing_deps.direct.is_empty());
+    }
+
+    #[test]
+    fn test_get_missing_dependencies_not_elf() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let script_filepath = tmp_dir.path().join("script.sh");
+        let mut script_file = File::create(&script_filepath).unwrap();
+        write!(&mut script_file, "#!/bin/sh\necho hello").unwrap();
+        script_file
+            .set_permissions(Permissions::from_mode(0o700))
+            .unwrap();
+        drop(script_file);
+
+        let missing_deps = get_missing_dependencies(
+            &SystemRoot::default(),
+            &script_filepath,
+            &[],
+            &LdCache::new(),
+        )
+        .unwrap();
+        assert!(missing_deps.direct.is_empty() && missing_deps.transitive.is_empty());
+    }
+}
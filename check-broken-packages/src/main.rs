@@ -1,666 +1,3753 @@
-use std::cmp;
-use std::env;
-use std::collections::BinaryHeap;
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashSet;
-use std::fmt;
+use std::env;
+use std::fmt::Write as _;
 use std::fs;
-use std::io::BufRead;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{IsTerminal, Read, Seek, Write};
+use std::num::NonZeroUsize;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::CommandExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::process::{Command, ExitCode};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
-use ansi_term::Colour::*;
+use ansi_term::Colour::{self, *};
+use ansi_term::{ANSIString, Style};
 use anyhow::Context;
-use crossbeam::thread as cb_thread;
-use glob::glob;
+use check_broken_packages::*;
+use clap::{CommandFactory, Parser, ValueEnum};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
-use log::debug;
+use log::{debug, info, LevelFilter};
+use serde::{Deserialize, Serialize};
 use simple_logger::SimpleLogger;
 
-type CrossbeamChannel<T> = (
-    crossbeam::channel::Sender<T>,
-    crossbeam::channel::Receiver<T>,
-);
-
-/// Executable file work unit for a worker thread to process
-#[derive(Debug)]
-struct ExecFileWork {
-    /// AUR package name
-    #[allow(clippy::rc_buffer)]
-    package: Arc<String>,
+/// Build desktop notification text summarizing problem counts, None if there is nothing to notify
+fn notification_text(report: &BrokenReport, official_packages: &HashSet<String>) -> Option<String> {
+    let mut lines = Vec::new();
+    let packages_to_rebuild = report.packages_to_rebuild();
+    let (official, aur) = partition_official(&packages_to_rebuild, official_packages);
+    if !aur.is_empty() {
+        lines.push(format!(
+            "{} AUR package{} need{} rebuilding",
+            aur.len(),
+            if aur.len() > 1 { "s" } else { "" },
+            if aur.len() > 1 { "" } else { "s" }
+        ));
+    }
+    if !official.is_empty() {
+        lines.push(format!(
+            "{} official package{} broken until {} update",
+            official.len(),
+            if official.len() > 1 { "s are" } else { " is" },
+            if official.len() > 1 { "their" } else { "its" }
+        ));
+    }
+    let security_module_count: usize = report
+        .broken_packages
+        .iter()
+        .map(|p| p.security_modules.len())
+        .sum();
+    if security_module_count > 0 {
+        lines.push(format!(
+            "{} broken PAM module{} or NSS plugin{}, login may fail",
+            security_module_count,
+            if security_module_count > 1 { "s" } else { "" },
+            if security_module_count > 1 { "s" } else { "" }
+        ));
+    }
+    let link_count = report.broken_systemd_links.len();
+    if link_count > 0 {
+        lines.push(format!(
+            "{} broken systemd link{}",
+            link_count,
+            if link_count > 1 { "s" } else { "" }
+        ));
+    }
+    if lines.is_empty() {
+        None
+    } else {
+        Some(lines.join("\n"))
+    }
+}
 
-    // Executable filepath
-    #[allow(clippy::rc_buffer)]
-    exec_filepath: Arc<String>,
+/// Render a Markdown table, with a header row
+fn markdown_table(out: &mut String, header: &[&str], rows: &[Vec<String>]) {
+    let _ = writeln!(out, "| {} |", header.join(" | "));
+    let _ = writeln!(out, "|{}", " --- |".repeat(header.len()));
+    for row in rows {
+        let _ = writeln!(out, "| {} |", row.join(" | "));
+    }
+    out.push('\n');
+}
 
-    /// True if this is the last executable filepath for the package (used to report progress)
-    package_last: bool,
+/// Format text as inline Markdown code
+fn markdown_code<T: std::fmt::Display>(text: T) -> String {
+    format!("`{}`", text)
 }
 
-struct PythonPackageVersion {
-    major: u8,
-    minor: u8,
-    release: u8,
-    package: u8,
+/// Quote a Graphviz DOT identifier
+fn dot_id(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
-impl fmt::Display for PythonPackageVersion {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}.{}.{}-{}",
-            self.major, self.minor, self.release, self.package
-        )
+/// Render direct library dependencies of package files as a Graphviz DOT graph, with missing
+/// libraries and the edges to them in red
+fn dependency_graph_dot(package: &str, dependencies: &[FileDependencies]) -> String {
+    let mut out = format!("digraph {} {{\n", dot_id(package));
+    let _ = writeln!(out, "    node [shape=box];");
+    let mut missing_sonames = BTreeSet::new();
+    for file_dependencies in dependencies {
+        let file = dot_id(&file_dependencies.file.to_string_lossy());
+        let _ = writeln!(out, "    {} [style=bold];", file);
+        for soname in &file_dependencies.needed {
+            if file_dependencies.missing.contains(soname) {
+                missing_sonames.insert(soname.as_str());
+                let _ = writeln!(out, "    {} -> {} [color=red];", file, dot_id(soname));
+            } else {
+                let _ = writeln!(out, "    {} -> {};", file, dot_id(soname));
+            }
+        }
+    }
+    for soname in missing_sonames {
+        let _ = writeln!(out, "    {} [color=red, fontcolor=red];", dot_id(soname));
     }
+    out.push_str("}\n");
+    out
 }
 
-fn get_python_version() -> anyhow::Result<PythonPackageVersion> {
-    let output = Command::new("pacman")
-        .args(&["-Qi", "python"])
-        .env("LANG", "C")
-        .output()?;
+/// Render a report as Markdown, with a section for each kind of problem found
+fn markdown_report(report: &BrokenReport) -> String {
+    let mut out = String::from("# Broken packages report\n\n");
 
-    if !output.status.success() {
-        anyhow::bail!("Failed to query Python version with pacman",);
+    if !report.broken_packages.is_empty() {
+        out.push_str("## Packages needing rebuild\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_packages
+            .iter()
+            .flat_map(|p| {
+                p.missing.iter().map(move |m| {
+                    vec![
+                        markdown_code(&p.package),
+                        markdown_code(&m.soname),
+                        m.provider.as_ref().map(markdown_code).unwrap_or_default(),
+                        m.kind.to_string(),
+                        m.files
+                            .iter()
+                            .map(markdown_code)
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    ]
+                })
+            })
+            .collect();
+        markdown_table(
+            &mut out,
+            &["Package", "Missing library", "Provider", "Cause", "Files"],
+            &rows,
+        );
     }
 
-    let version_line = output
-        .stdout
-        .lines()
-        .filter_map(Result::ok)
-        .find(|l| l.starts_with("Version"))
-        .ok_or_else(|| anyhow::anyhow!("Unexpected pacman output: unable to find version line"))?;
-    let version_str = version_line
-        .split(':')
-        .nth(1)
-        .ok_or_else(|| anyhow::anyhow!("Unexpected pacman output: unable to parse version line"))?
-        .trim_start();
-
-    let mut dot_iter = version_str.split('.');
-    let major = u8::from_str(dot_iter.next().ok_or_else(|| {
-        anyhow::anyhow!("Unexpected pacman output: unable to parse Python version major part")
-    })?)?;
-    let minor = u8::from_str(dot_iter.next().ok_or_else(|| {
-        anyhow::anyhow!("Unexpected pacman output: unable to parse Python version minor part")
-    })?)?;
-    let mut dash_iter = dot_iter
-        .next()
-        .ok_or_else(|| {
-            anyhow::anyhow!(
-                "Unexpected pacman output: unable to parse Python version release/package part",
-            )
-        })?
-        .split('-');
-    let release = u8::from_str(dash_iter.next().ok_or_else(|| {
-        anyhow::anyhow!("Unexpected pacman output: unable to parse Python version release part")
-    })?)?;
-    let package = u8::from_str(dash_iter.next().ok_or_else(|| {
-        anyhow::anyhow!("Unexpected pacman output: unable to parse Python version package part")
-    })?)?;
-
-    Ok(PythonPackageVersion {
-        major,
-        minor,
-        release,
-        package,
-    })
-}
+    if !report.transitively_broken_packages.is_empty() {
+        out.push_str("## Transitively broken packages\n\n");
+        for package in &report.transitively_broken_packages {
+            let _ = writeln!(out, "- {}", markdown_code(package));
+        }
+        out.push('\n');
+    }
 
-fn get_package_owning_path(path: &str) -> anyhow::Result<Vec<String>> {
-    let output = Command::new("pacman")
-        .args(&["-Fq", path])
-        .env("LANG", "C")
-        .output()?;
+    if !report.broken_runtime_packages.is_empty() {
+        out.push_str("## Broken interpreter packages\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_runtime_packages
+            .iter()
+            .map(|p| {
+                vec![
+                    markdown_code(&p.package),
+                    p.runtime.to_owned(),
+                    markdown_code(&p.dir),
+                ]
+            })
+            .collect();
+        markdown_table(
+            &mut out,
+            &["Package", "Interpreter", "Ignored directory"],
+            &rows,
+        );
+    }
 
-    Ok(output
-        .stdout
-        .lines().map(|l| l.map(|i| i.split_once('/').expect("no file").1.to_string()))
-        .collect::<Result<Vec<String>, std::io::Error>>()?)
-}
+    if !report.broken_pip_packages.is_empty() {
+        out.push_str("## Stranded pip packages\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_pip_packages
+            .iter()
+            .map(|p| vec![markdown_code(&p.package), markdown_code(&p.dir)])
+            .collect();
+        markdown_table(&mut out, &["Package", "Ignored directory"], &rows);
+    }
 
-fn get_broken_python_packages(
-    current_python_version: &PythonPackageVersion,
-) -> anyhow::Result<Vec<(String, String)>> {
-    let mut packages = Vec::new();
+    if !report.broken_systemd_links.is_empty() {
+        out.push_str("## Broken systemd links\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_systemd_links
+            .iter()
+            .map(|l| {
+                vec![
+                    markdown_code(l.link.display()),
+                    markdown_code(l.target.display()),
+                    markdown_code(l.missing.display()),
+                ]
+            })
+            .collect();
+        markdown_table(&mut out, &["Link", "Target", "Missing"], &rows);
+    }
 
-    let current_python_dir = format!(
-        "/usr/lib/python{}.{}",
-        current_python_version.major, current_python_version.minor
-    );
+    if !report.broken_symlinks.is_empty() {
+        out.push_str("## Broken symlinks\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_symlinks
+            .iter()
+            .map(|s| {
+                vec![
+                    markdown_code(&s.package),
+                    markdown_code(s.broken_link.link.display()),
+                    markdown_code(s.broken_link.target.display()),
+                    markdown_code(s.broken_link.missing.display()),
+                ]
+            })
+            .collect();
+        markdown_table(&mut out, &["Package", "Link", "Target", "Missing"], &rows);
+    }
 
-    for python_dir_entry in glob(&format!("/usr/lib/python{}*", current_python_version.major))? {
-        let python_dir = python_dir_entry?
-            .into_os_string()
-            .into_string()
-            .map_err(|_| anyhow::anyhow!("Failed to convert OS string to native string"))?;
-
-        if python_dir != current_python_dir {
-            let dir_packages = get_package_owning_path(&python_dir)?;
-            for package in dir_packages {
-                let couple = (package, python_dir.clone());
-                if !packages.contains(&couple) {
-                    packages.push(couple);
-                }
-            }
-        }
+    if !report.unprovided_libraries.is_empty() {
+        out.push_str("## Libraries resolving to another soname\n\n");
+        let rows: Vec<Vec<String>> = report
+            .unprovided_libraries
+            .iter()
+            .map(|u| vec![markdown_code(&u.package), markdown_code(&u.soname)])
+            .collect();
+        markdown_table(&mut out, &["Package", "Library"], &rows);
     }
 
-    Ok(packages)
-}
+    if !report.broken_typelibs.is_empty() {
+        out.push_str("## Broken typelibs\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_typelibs
+            .iter()
+            .map(|t| {
+                vec![
+                    markdown_code(&t.package),
+                    markdown_code(t.typelib.display()),
+                    t.missing
+                        .iter()
+                        .map(markdown_code)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                ]
+            })
+            .collect();
+        markdown_table(
+            &mut out,
+            &["Package", "Typelib", "Missing libraries"],
+            &rows,
+        );
+    }
 
-fn get_aur_packages() -> anyhow::Result<Vec<String>> {
-    let output = Command::new("pacman")
-        .args(&["-Qqm"])
-        .env("LANG", "C")
-        .output()?;
+    if !report.broken_udev_rules.is_empty() {
+        out.push_str("## Broken udev rules\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_udev_rules
+            .iter()
+            .map(|r| {
+                vec![
+                    markdown_code(&r.package),
+                    markdown_code(r.rule.display()),
+                    markdown_code(r.program.display()),
+                ]
+            })
+            .collect();
+        markdown_table(&mut out, &["Package", "Rule", "Missing program"], &rows);
+    }
 
-    Ok(output
-        .stdout
-        .lines()
-        .collect::<Result<Vec<String>, std::io::Error>>()?)
-}
+    if !report.broken_desktop_entries.is_empty() {
+        out.push_str("## Broken desktop entries\n\n");
+        let rows: Vec<Vec<String>> = report
+            .broken_desktop_entries
+            .iter()
+            .map(|e| {
+                vec![
+                    markdown_code(&e.package),
+                    markdown_code(e.desktop_file.display()),
+                    markdown_code(&e.program),
+                ]
+            })
+            .collect();
+        markdown_table(
+            &mut out,
+            &["Package", "Desktop entry", "Missing program"],
+            &rows,
+        );
+    }
 
-fn get_package_linked_files(package: &str) -> anyhow::Result<Vec<String>> {
-    let output = Command::new("pacman")
-        .args(&["-Ql", package])
-        .env("LANG", "C")
-        .output()?;
+    if !report.duplicate_sonames.is_empty() {
+        out.push_str("## Sonames provided by several packages\n\n");
+        let rows: Vec<Vec<String>> = report
+            .duplicate_sonames
+            .iter()
+            .flat_map(|d| {
+                d.providers.iter().map(move |(package, files)| {
+                    vec![
+                        markdown_code(&d.soname),
+                        markdown_code(package),
+                        files
+                            .iter()
+                            .map(|f| markdown_code(f.display()))
+                            .collect::<Vec<_>>()
+                            .join(", "),
+                    ]
+                })
+            })
+            .collect();
+        markdown_table(&mut out, &["Soname", "Package", "Files"], &rows);
+    }
 
-    if !output.status.success() {
-        anyhow::bail!("Failed to list files for package {:?} with pacman", package);
+    if !report.foreign_arch_files.is_empty() {
+        out.push_str("## Files built for another architecture\n\n");
+        let rows: Vec<Vec<String>> = report
+            .foreign_arch_files
+            .iter()
+            .map(|f| {
+                vec![
+                    markdown_code(&f.package),
+                    markdown_code(f.file.display()),
+                    f.arch.to_owned(),
+                ]
+            })
+            .collect();
+        markdown_table(&mut out, &["Package", "File", "Architecture"], &rows);
     }
 
-    let files = output
-        .stdout
-        .lines()
-        .collect::<Result<Vec<String>, _>>()?
-        .into_iter()
-        .filter_map(|l| l.split(' ').nth(1).map(|p| p.to_string()))
-        .map(|s| {
-            fs::read_link(&s)
-                .map(|p| p.to_str().unwrap().to_string())
-                .unwrap_or(s)
-        })
-        .filter(|p| {
-            fs::metadata(&p)
-                .map(|m| m.file_type().is_file() && ((m.permissions().mode() & 0o111) != 0 || (p.chars().filter(|&c|c=='/').count() == 3 && p.ends_with(".so"))))
-                .unwrap_or(false)
-        })
-        .collect();
+    if let Some(rebuild_command) = &report.rebuild_command {
+        let _ = writeln!(out, "Rebuild command: {}\n", markdown_code(rebuild_command));
+    }
 
-    Ok(files)
+    let _ = writeln!(out, "{}", report.summary);
+    out
 }
 
-fn is_direct_dep(exec_file: &str, dep: &str) -> anyhow::Result<bool> {
-    return Ok(Command::new("patchelf")
-        .args(&["--print-needed", exec_file])
-        .output()?.stdout.lines().any(|d| d.unwrap() == dep));
+/// Render a report as CSV rows of package, missing soname, file, providing package and category,
+/// with a header row
+fn csv_report(report: &BrokenReport) -> anyhow::Result<String> {
+    let mut rows: Vec<[String; 5]> = Vec::new();
+    for broken_package in &report.broken_packages {
+        for missing in &broken_package.missing {
+            for file in &missing.files {
+                rows.push([
+                    broken_package.package.to_owned(),
+                    missing.soname.to_owned(),
+                    file.to_owned(),
+                    missing.provider.clone().unwrap_or_default(),
+                    "missing".to_owned(),
+                ]);
+            }
+        }
+    }
+    for package in &report.transitively_broken_packages {
+        rows.push([
+            package.to_owned(),
+            String::new(),
+            String::new(),
+            String::new(),
+            "transitive".to_owned(),
+        ]);
+    }
+    for unprovided in &report.unprovided_libraries {
+        for file in &unprovided.files {
+            rows.push([
+                unprovided.package.to_owned(),
+                unprovided.soname.to_owned(),
+                file.to_owned(),
+                String::new(),
+                "unprovided".to_owned(),
+            ]);
+        }
+    }
+    for broken_typelib in &report.broken_typelibs {
+        for soname in &broken_typelib.missing {
+            rows.push([
+                broken_typelib.package.to_owned(),
+                soname.to_owned(),
+                broken_typelib.typelib.to_string_lossy().into_owned(),
+                String::new(),
+                "typelib".to_owned(),
+            ]);
+        }
+    }
+    for broken_udev_rule in &report.broken_udev_rules {
+        rows.push([
+            broken_udev_rule.package.to_owned(),
+            String::new(),
+            broken_udev_rule.rule.to_string_lossy().into_owned(),
+            String::new(),
+            "udev".to_owned(),
+        ]);
+    }
+    for broken_desktop_entry in &report.broken_desktop_entries {
+        rows.push([
+            broken_desktop_entry.package.to_owned(),
+            String::new(),
+            broken_desktop_entry
+                .desktop_file
+                .to_string_lossy()
+                .into_owned(),
+            String::new(),
+            "desktop".to_owned(),
+        ]);
+    }
+    for duplicate_soname in &report.duplicate_sonames {
+        for (package, files) in &duplicate_soname.providers {
+            for file in files {
+                rows.push([
+                    package.to_owned(),
+                    duplicate_soname.soname.to_owned(),
+                    file.to_string_lossy().into_owned(),
+                    String::new(),
+                    "duplicate".to_owned(),
+                ]);
+            }
+        }
+    }
+    for foreign_arch_file in &report.foreign_arch_files {
+        rows.push([
+            foreign_arch_file.package.to_owned(),
+            String::new(),
+            foreign_arch_file.file.to_string_lossy().into_owned(),
+            String::new(),
+            "foreign-arch".to_owned(),
+        ]);
+    }
+    for broken_runtime_package in &report.broken_runtime_packages {
+        // Category is the interpreter or toolkit, like python
+        rows.push([
+            broken_runtime_package.package.to_owned(),
+            String::new(),
+            broken_runtime_package.dir.to_owned(),
+            String::new(),
+            broken_runtime_package.runtime.to_lowercase(),
+        ]);
+    }
+    for broken_pip_package in &report.broken_pip_packages {
+        rows.push([
+            broken_pip_package.package.to_owned(),
+            String::new(),
+            broken_pip_package.dir.to_owned(),
+            String::new(),
+            "pip".to_owned(),
+        ]);
+    }
+    for broken_symlink in &report.broken_symlinks {
+        rows.push([
+            broken_symlink.package.to_owned(),
+            String::new(),
+            broken_symlink
+                .broken_link
+                .link
+                .to_string_lossy()
+                .into_owned(),
+            String::new(),
+            "symlink".to_owned(),
+        ]);
+    }
+    for broken_link in &report.broken_systemd_links {
+        rows.push([
+            String::new(),
+            String::new(),
+            broken_link.link.to_string_lossy().into_owned(),
+            String::new(),
+            "systemd".to_owned(),
+        ]);
+    }
+
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record([
+        "package",
+        "missing_soname",
+        "file",
+        "providing_package",
+        "category",
+    ])?;
+    for row in rows {
+        writer.write_record(&row)?;
+    }
+    Ok(String::from_utf8(writer.into_inner()?)?)
 }
 
-fn get_missing_dependencies(exec_file: &str) -> anyhow::Result<Vec<String>> {
-    let output = Command::new("ldd")
-        .args(&[exec_file])
-        .env("LANG", "C")
-        .output()?;
+/// Append a TAP test point, with diagnostic lines if it failed
+fn tap_test_point(out: &mut String, number: usize, description: &str, failures: &[String]) {
+    let _ = writeln!(
+        out,
+        "{} {} - {}",
+        if failures.is_empty() { "ok" } else { "not ok" },
+        number,
+        description
+    );
+    for failure in failures {
+        let _ = writeln!(out, "# {}", failure);
+    }
+}
 
-    let missing_deps = if output.status.success() {
-        output
-            .stdout
-            .lines()
-            .collect::<Result<Vec<String>, _>>()?
-            .into_iter()
-            .filter(|l| l.ends_with("=> not found"))
-            .filter_map(|l| l.split(' ').next().map(|s| s.to_owned()))
-            .map(|l| l.trim_start().to_string())
-            //.filter(|l| !output.status.success() || direct_deps.contains(l))
-            .collect()
-    } else {
-        Vec::new()
-    };
+/// Render a report as a Test Anything Protocol stream, with a test point for each scanned
+/// package, then for interpreter, pip and systemd checks if enabled, and a trailing plan line
+fn tap_report(
+    report: &BrokenReport,
+    packages: &[String],
+    runtimes_checked: bool,
+    pip_checked: bool,
+    systemd_checked: bool,
+) -> String {
+    let mut failures = BTreeMap::<&str, Vec<String>>::new();
+    for broken_package in &report.broken_packages {
+        for missing in &broken_package.missing {
+            failures
+                .entry(&broken_package.package)
+                .or_default()
+                .push(format!("missing {} ({})", missing.soname, missing.kind));
+        }
+    }
+    for package in &report.transitively_broken_packages {
+        failures
+            .entry(package)
+            .or_default()
+            .push("missing libraries through its dependencies".to_string());
+    }
+    for unprovided in &report.unprovided_libraries {
+        failures
+            .entry(&unprovided.package)
+            .or_default()
+            .push(format!(
+                "{} resolves to a library with another soname",
+                unprovided.soname
+            ));
+    }
+    for broken_typelib in &report.broken_typelibs {
+        failures
+            .entry(&broken_typelib.package)
+            .or_default()
+            .push(format!(
+                "typelib {} loads missing {}",
+                broken_typelib.typelib.display(),
+                broken_typelib.missing.join(", ")
+            ));
+    }
+    for broken_udev_rule in &report.broken_udev_rules {
+        failures
+            .entry(&broken_udev_rule.package)
+            .or_default()
+            .push(format!(
+                "udev rule {} runs missing {}",
+                broken_udev_rule.rule.display(),
+                broken_udev_rule.program.display()
+            ));
+    }
+    for broken_desktop_entry in &report.broken_desktop_entries {
+        failures
+            .entry(&broken_desktop_entry.package)
+            .or_default()
+            .push(format!(
+                "desktop entry {} launches missing {}",
+                broken_desktop_entry.desktop_file.display(),
+                broken_desktop_entry.program
+            ));
+    }
+    // Duplicate sonames are informational, only reported as diagnostics of passing test points
+    let mut notes = BTreeMap::<&str, Vec<String>>::new();
+    for duplicate_soname in &report.duplicate_sonames {
+        for package in duplicate_soname.providers.keys() {
+            let others: Vec<&str> = duplicate_soname
+                .providers
+                .keys()
+                .filter(|p| *p != package)
+                .map(String::as_str)
+                .collect();
+            notes.entry(package).or_default().push(format!(
+                "{} also provided by {}",
+                duplicate_soname.soname,
+                others.join(", ")
+            ));
+        }
+    }
+    for broken_symlink in &report.broken_symlinks {
+        failures
+            .entry(&broken_symlink.package)
+            .or_default()
+            .push(format!(
+                "broken symlink {}",
+                broken_symlink.broken_link.link.display()
+            ));
+    }
+    for foreign_arch_file in &report.foreign_arch_files {
+        failures
+            .entry(&foreign_arch_file.package)
+            .or_default()
+            .push(format!(
+                "{} built for {}, host is {}",
+                foreign_arch_file.file.display(),
+                foreign_arch_file.arch,
+                std::env::consts::ARCH
+            ));
+    }
 
-    Ok(missing_deps)
+    let mut out = String::new();
+    let mut count = 0;
+    for package in packages {
+        count += 1;
+        let package_failures = failures
+            .get(package.as_str())
+            .map_or(&[][..], Vec::as_slice);
+        tap_test_point(&mut out, count, package, package_failures);
+        for note in notes.get(package.as_str()).into_iter().flatten() {
+            let _ = writeln!(out, "# {}", note);
+        }
+    }
+    if runtimes_checked {
+        count += 1;
+        let runtime_failures: Vec<String> = report
+            .broken_runtime_packages
+            .iter()
+            .map(|p| {
+                format!(
+                    "{} has files in {} ignored by the current {} interpreter",
+                    p.package, p.dir, p.runtime
+                )
+            })
+            .collect();
+        tap_test_point(
+            &mut out,
+            count,
+            "interpreter and toolkit directories",
+            &runtime_failures,
+        );
+    }
+    if pip_checked {
+        count += 1;
+        let pip_failures: Vec<String> = report
+            .broken_pip_packages
+            .iter()
+            .map(|p| {
+                format!(
+                    "{} is installed in {} for an older Python",
+                    p.package, p.dir
+                )
+            })
+            .collect();
+        tap_test_point(&mut out, count, "pip and pipx packages", &pip_failures);
+    }
+    if systemd_checked {
+        count += 1;
+        let link_failures: Vec<String> = report
+            .broken_systemd_links
+            .iter()
+            .map(|l| format!("broken link {}", l.link.display()))
+            .collect();
+        tap_test_point(&mut out, count, "systemd links", &link_failures);
+    }
+    let _ = writeln!(out, "1..{}", count);
+    out
 }
 
-fn get_sd_enabled_service_links() -> anyhow::Result<Vec<PathBuf>> {
-    let mut dirs_content = [
-        glob("/etc/systemd/system/*.target.*"),
-        glob("/etc/systemd/user/*.target.*"),
+/// Render scan metrics in the Prometheus text exposition format, all gauges are always present
+fn prometheus_metrics(summary: &Summary) -> String {
+    let gauges: [(&str, &str, f64); 6] = [
+        (
+            "arch_scanned_packages",
+            "Number of scanned packages",
+            summary.scanned_packages as f64,
+        ),
+        (
+            "arch_broken_aur_packages",
+            "Number of packages needing rebuild, directly or transitively broken",
+            (summary.broken_packages + summary.transitively_broken_packages) as f64,
+        ),
+        (
+            "arch_broken_python_dirs",
+            "Number of packages with files in outdated interpreter or toolkit directories",
+            summary.broken_runtime_packages as f64,
+        ),
+        (
+            "arch_broken_systemd_links",
+            "Number of broken systemd enabled unit links",
+            summary.broken_systemd_links as f64,
+        ),
+        (
+            "arch_broken_symlinks",
+            "Number of broken package symlinks",
+            summary.broken_symlinks as f64,
+        ),
+        (
+            "arch_scan_duration_seconds",
+            "Duration of the scan",
+            summary.duration_secs,
+        ),
     ];
+    let mut out = String::new();
+    for (name, help, value) in &gauges {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} gauge", name);
+        let _ = writeln!(out, "{} {}", name, value);
+    }
+    out
+}
 
-    let service_links: Vec<PathBuf> = dirs_content
-        .iter_mut()
-        .flatten()
-        .flatten()
-        .collect::<Result<Vec<PathBuf>, _>>()?
+/// Write a file atomically, by writing a temporary file in the same directory and renaming it
+fn write_file_atomic(path: &Path, contents: &str) -> anyhow::Result<()> {
+    let mut tmp_filename = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid file path {:?}", path))?
+        .to_owned();
+    tmp_filename.push(".tmp");
+    let tmp_filepath = path.with_file_name(tmp_filename);
+    fs::write(&tmp_filepath, contents)
+        .with_context(|| format!("Failed to write file {:?}", tmp_filepath))?;
+    fs::rename(&tmp_filepath, path)
+        .with_context(|| format!("Failed to rename {:?} to {:?}", tmp_filepath, path))
+}
+
+/// Write package names, one per line, to a file descriptor inherited from the parent process
+fn write_rebuild_list(fd: u32, packages: &[String]) -> anyhow::Result<()> {
+    let fd_path = format!("/dev/fd/{}", fd);
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(&fd_path)
+        .with_context(|| format!("Failed to open file descriptor {}", fd))?;
+    let list: String = packages.iter().map(|p| format!("{}\n", p)).collect();
+    file.write_all(list.as_bytes())
+        .with_context(|| format!("Failed to write to file descriptor {}", fd))
+}
+
+/// Parent directory of user runtime directories, holding the session bus socket of logged in users
+const USER_RUNTIME_DIRS_PATH: &str = "/run/user";
+
+/// Get runtime directories of logged in users with a session bus, with their user and group ids
+fn user_sessions() -> Vec<(PathBuf, u32, u32)> {
+    let mut sessions: Vec<(PathBuf, u32, u32)> = fs::read_dir(USER_RUNTIME_DIRS_PATH)
         .into_iter()
-        .filter_map(|p| fs::read_dir(p.as_path()).ok())
         .flatten()
-        .flatten()
-        .filter(|f| f.file_type().map_or(false, |f| f.is_symlink()))
-        .map(|f| f.path())
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = fs::metadata(entry.path().join("bus")).ok()?;
+            Some((entry.path(), metadata.uid(), metadata.gid()))
+        })
+        .filter(|(_, uid, _)| *uid != 0)
         .collect();
+    sessions.sort();
+    sessions
+}
+
+/// Run notify-send, with environment and credentials already set
+fn run_notify_send(mut cmd: Command, body: &str) -> anyhow::Result<()> {
+    let output = run_with_timeout(
+        cmd.args([
+            "--app-name=check-broken-packages",
+            "Broken packages found",
+            body,
+        ]),
+        COMMAND_TIMEOUT,
+    )?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "notify-send failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
 
-    Ok(service_links)
+    Ok(())
 }
 
-fn is_valid_link(link: &Path) -> anyhow::Result<bool> {
-    let mut target: PathBuf = link.into();
-    loop {
-        target = fs::read_link(target)?;
-        let metadata = match fs::metadata(&target) {
-            Err(_) => {
-                return Ok(false);
-            }
-            Ok(m) => m,
-        };
+/// Send a desktop notification with notify-send to the current session or, when running as root
+/// without one like from the pacman hook, to the session of each logged in user
+fn send_notification(body: &str) -> anyhow::Result<()> {
+    if env::var_os("DBUS_SESSION_BUS_ADDRESS").is_some() {
+        return run_notify_send(Command::new("notify-send"), body);
+    }
+    if !fs::metadata("/proc/self").is_ok_and(|m| m.uid() == 0) {
+        debug!("No D-Bus session available, not sending notification");
+        return Ok(());
+    }
 
-        let ftype = metadata.file_type();
-        if ftype.is_file() {
-            return Ok(true);
-        } else if ftype.is_symlink() {
-            continue;
-        } else {
-            anyhow::bail!("Unexpected file type for {:?}", target);
+    let sessions = user_sessions();
+    if sessions.is_empty() {
+        debug!("No logged in user with a D-Bus session, not sending notification");
+    }
+    let mut result = Ok(());
+    for (runtime_dir, uid, gid) in sessions {
+        let mut cmd = Command::new("notify-send");
+        cmd.uid(uid)
+            .gid(gid)
+            .env("XDG_RUNTIME_DIR", &runtime_dir)
+            .env(
+                "DBUS_SESSION_BUS_ADDRESS",
+                format!("unix:path={}", runtime_dir.join("bus").display()),
+            );
+        if let Err(err) = run_notify_send(cmd, body) {
+            result = Err(err.context(format!("Failed to notify user {}", uid)));
         }
     }
+    result
 }
 
-fn main() -> anyhow::Result<()> {
-    // Init logger
-    SimpleLogger::new()
-        .init()
-        .context("Failed to init logger")?;
+/// Tool used to rebuild AUR packages
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum RebuildHelper {
+    /// paru AUR helper
+    Paru,
+    /// yay AUR helper
+    Yay,
+    /// Manual rebuild with makepkg
+    Makepkg,
+}
 
-    // Python broken packages channel
-    let (python_broken_packages_tx, python_broken_packages_rx) = crossbeam::unbounded();
-    thread::Builder::new()
-        .spawn(move || {
-            let to_send = match get_python_version() {
-                Ok(current_python_version) => {
-                    debug!("Python version: {}", current_python_version);
-                    let broken_python_packages =
-                        get_broken_python_packages(&current_python_version);
-                    match broken_python_packages {
-                        Ok(broken_python_packages) => broken_python_packages,
-                        Err(err) => {
-                            eprintln!("Failed to list Python packages: {}", err);
-                            Vec::<(String, String)>::new()
-                        }
-                    }
-                }
-                Err(err) => {
-                    eprintln!("Failed to get Python version: {}", err);
-                    Vec::<(String, String)>::new()
-                }
-            };
-            python_broken_packages_tx.send(to_send).unwrap();
-        })
-        .context("Failed to start thread")?;
+impl RebuildHelper {
+    /// Get suggested command to rebuild packages
+    fn command(self, packages: &[String]) -> String {
+        let packages = packages.join(" ");
+        match self {
+            RebuildHelper::Paru => format!("paru -S --rebuild {}", packages),
+            RebuildHelper::Yay => format!("yay -S --rebuild {}", packages),
+            RebuildHelper::Makepkg => format!(
+                "makepkg -fsi # in the PKGBUILD directory of each package: {}",
+                packages
+            ),
+        }
+    }
 
-    // Get usable core count
-    let cpu_count = num_cpus::get();
+    /// Get program rebuilding packages by name, None if the tool can not do it
+    fn program(self) -> Option<&'static str> {
+        match self {
+            RebuildHelper::Paru => Some("paru"),
+            RebuildHelper::Yay => Some("yay"),
+            RebuildHelper::Makepkg => None,
+        }
+    }
 
-    // Get package names
-    let aur_packages = get_aur_packages().context("Unable to get list of AUR packages")?;
+    /// Get command rebuilding packages, None if the tool can not rebuild them by name
+    fn rebuild_command(self, packages: &[&String]) -> Option<Command> {
+        let mut command = Command::new(self.program()?);
+        command.args(["-S", "--rebuild"]).args(packages);
+        Some(command)
+    }
+}
 
-    // Get systemd enabled services
-    let enabled_sd_service_links =
-        get_sd_enabled_service_links().context("Unable to Systemd enabled services")?;
-    let mut broken_sd_service_links: Vec<PathBuf> = Vec::new();
+/// Get external programs needed by the enabled checks and actions, with how to install them
+fn required_programs(cli: &Cli) -> Vec<(&'static str, &'static str)> {
+    let mut programs = Vec::new();
+    // Every check of packages, and reading the pacman database, log or file databases of the
+    // checked system needs pacman
+    let pacman_checks = [
+        !cli.no_libs,
+        !cli.no_runtimes,
+        cli.check_pip,
+        cli.check_duplicate_sonames,
+        cli.check_symlinks,
+        cli.check_typelibs,
+        cli.check_udev,
+        cli.check_desktop_files,
+        cli.since.is_some(),
+        cli.sync_files,
+        cli.root.is_some() || cli.dbpath.is_some(),
+        cli.graph.is_some(),
+    ];
+    if pacman_checks.contains(&true) {
+        programs.push(("pacman", "it is part of the pacman package"));
+    }
+    if cli.notify {
+        programs.push(("notify-send", "install it with pacman -S libnotify"));
+    }
+    if let Some(program) = cli
+        .rebuild_cmd
+        .filter(|_| cli.fix)
+        .and_then(|h| h.program())
+    {
+        programs.push((program, "install this AUR helper, or pick another one"));
+    }
+    programs
+}
 
-    // Init progressbar
-    let progress = ProgressBar::with_draw_target(
-        (aur_packages.len() + enabled_sd_service_links.len()) as u64,
-        ProgressDrawTarget::stderr(),
-    );
-    progress.set_style(ProgressStyle::default_bar().template("Analyzing {wide_bar} {pos}/{len}"));
-
-    // Missing deps channel
-    let (missing_deps_tx, missing_deps_rx) = crossbeam::unbounded();
-
-    cb_thread::scope(|scope| {
-        // Executable file channel
-        let (exec_files_tx, exec_files_rx): CrossbeamChannel<ExecFileWork> = crossbeam::unbounded();
-
-        // Executable files to missing deps workers
-        for _ in 0..cpu_count {
-            let exec_files_rx = exec_files_rx.clone();
-            let missing_deps_tx = missing_deps_tx.clone();
-            let progress = progress.clone();
-            scope.spawn(move |_| {
-                while let Ok(exec_file_work) = exec_files_rx.recv() {
-                    debug!("exec_files_rx => {:?}", &exec_file_work);
-                    let missing_deps = get_missing_dependencies(&exec_file_work.exec_filepath);
-                    match missing_deps {
-                        Ok(missing_deps) => {
-                            for missing_dep in missing_deps {
-                                let to_send = (
-                                    Arc::clone(&exec_file_work.package),
-                                    Arc::clone(&exec_file_work.exec_filepath),
-                                    missing_dep.clone(),
-                                    get_package_owning_path(missing_dep.split("/").last().unwrap().split_inclusive(".so").next().unwrap()).unwrap_or(vec!["?".to_string()])
-                                );
-                                debug!("{:?} => missing_deps_tx", &to_send);
-                                if missing_deps_tx.send(to_send).is_err() {
-                                    break;
-                                }
-                            }
-                        }
-                        Err(err) => {
-                            eprintln!(
-                                "Failed to get missing dependencies for path {:?}: {}",
-                                &exec_file_work.exec_filepath, err
-                            );
-                        }
-                    }
-                    if exec_file_work.package_last {
-                        progress.inc(1);
-                    }
-                }
-            });
-        }
-
-        // Drop this end of the channel, workers have their own clone
-        drop(missing_deps_tx);
-
-        cb_thread::scope(|scope| {
-            // Package name channel
-            let (package_tx, package_rx): CrossbeamChannel<Arc<String>> = crossbeam::unbounded();
-
-            // Package name to executable files workers
-            let worker_count = cmp::min(cpu_count, aur_packages.len());
-            for _ in 0..worker_count {
-                let package_rx = package_rx.clone();
-                let exec_files_tx = exec_files_tx.clone();
-                let progress = progress.clone();
-                scope.spawn(move |_| {
-                    while let Ok(package) = package_rx.recv() {
-                        debug!("package_rx => {:?}", package);
-                        let exec_files = match get_package_linked_files(&package) {
-                            Ok(exec_files) => exec_files,
-                            Err(err) => {
-                                eprintln!(
-                                    "Failed to get executable files of package {:?}: {}",
-                                    &package, err
-                                );
-                                progress.inc(1);
-                                continue;
-                            }
-                        };
-                        if exec_files.is_empty() {
-                            progress.inc(1);
-                            continue;
-                        }
+/// Run a function, also getting how long it took
+fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
 
-                        // Exclude executables in commonly used non standard directories,
-                        // likely to also use non standard library locations
-                        const BLACKLISTED_EXE_DIRS: [&str; 2] = ["/opt/", "/usr/share/"];
-                        for (i, exec_file) in exec_files
-                            .iter()
-                            .filter(|p| !BLACKLISTED_EXE_DIRS.iter().any(|d| p.starts_with(d)))
-                            .enumerate()
-                        {
-                            let to_send = ExecFileWork {
-                                package: Arc::clone(&package),
-                                exec_filepath: Arc::new(exec_file.to_string()),
-                                package_last: i == exec_files.len() - 1,
-                            };
-                            debug!("{:?} => exec_files_tx", &to_send);
-                            if exec_files_tx.send(to_send).is_err() {
-                                break;
-                            }
-                        }
-                    }
-                });
-            }
+/// Render durations of scan stages as a table
+fn timings_table(timings: &[(&str, Duration)]) -> String {
+    let width = timings
+        .iter()
+        .map(|(stage, _)| stage.len())
+        .max()
+        .unwrap_or(0);
+    let mut out = String::from("Timings:\n");
+    for (stage, duration) in timings {
+        let _ = writeln!(
+            out,
+            "  {:<width$}  {:>7.2}s",
+            stage,
+            duration.as_secs_f64(),
+            width = width
+        );
+    }
+    out
+}
 
-            // Drop this end of the channel, workers have their own clone
-            drop(exec_files_tx);
+/// Describe what a scan would do, without doing it
+fn plan_text(
+    checks: &[&str],
+    lib_dirs: &[PathBuf],
+    skip_dirs: &[PathBuf],
+    packages: &[String],
+) -> String {
+    let mut out = format!("Checks: {}\n", checks.join(", "));
+    for (title, dirs) in [
+        ("Library search paths", lib_dirs),
+        ("Skipped directories", skip_dirs),
+    ] {
+        let _ = writeln!(out, "{}:", title);
+        for dir in dirs {
+            let _ = writeln!(out, "  {}", dir.display());
+        }
+    }
+    let _ = writeln!(out, "Packages to scan ({}):", packages.len());
+    for package in packages {
+        let _ = writeln!(out, "  {}", package);
+    }
+    out
+}
 
-            // Send package names
-            for aur_package in aur_packages {
-                debug!("{:?} => package_tx", aur_package);
-                package_tx.send(Arc::new(aur_package)).unwrap();
-            }
-        })
-        .unwrap();
+/// Parse an answer to a yes/no question, anything but yes is a no
+fn parse_confirmation(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
 
-        // We don't bother to use a worker thread for this, the overhead is not worth it
-        broken_sd_service_links = enabled_sd_service_links
-            .iter()
-            .filter(|s| !is_valid_link(s).unwrap_or(true))
-            .map(|l| l.to_owned())
-            .collect();
-        progress.inc(enabled_sd_service_links.len() as u64);
-    })
-    .unwrap();
+/// Ask a yes/no question on the terminal, answer no if stdin is not a terminal
+fn confirm(question: &str) -> anyhow::Result<bool> {
+    let stdin = std::io::stdin();
+    if !stdin.is_terminal() {
+        eprintln!("Not a terminal, unable to ask for confirmation, pass --yes to skip it");
+        return Ok(false);
+    }
+    eprint!("{} [y/N] ", question);
+    std::io::stderr().flush()?;
+    let mut answer = String::new();
+    stdin.read_line(&mut answer)?;
+    Ok(parse_confirmation(&answer))
+}
 
-    progress.finish_and_clear();
+/// Split packages into official and AUR ones, keeping their order
+fn partition_official<'a>(
+    packages: &'a [String],
+    official_packages: &HashSet<String>,
+) -> (Vec<&'a String>, Vec<&'a String>) {
+    packages
+        .iter()
+        .partition(|p| official_packages.contains(*p))
+}
 
-    let mut libmap = HashMap::<String, HashMap<Arc<String>, BinaryHeap<Arc<String>>>>::new();
-    let mut trans2 = HashSet::<String>::new();
-    let mut pacmap = HashMap::<String, HashSet<String>>::new();
-    let mut pacsourcemap = HashMap::<String, String>::new();
-    for (package, file, missing_dep, pkg) in missing_deps_rx.iter() {
-        println!("{} {} {} {}", pkg.join(" "), missing_dep.clone(), package.clone(), file.clone());
-        if is_direct_dep(file.as_str(), &missing_dep.clone()).unwrap_or(true) {
-            libmap.entry(missing_dep.clone()).or_default().entry(package.clone()).or_default().push(file);
-            //pacmap.entry(package.to_string()).or_default().insert(pkg.join(", "));
-            pacmap.entry(package.to_string()).or_default().insert(missing_dep.clone());
-        } else {
-            trans2.insert(package.to_string());
-        }
-        if !pkg.is_empty() {
-            pacsourcemap.insert(missing_dep.clone(), pkg[0].clone());
-        }
+/// Rebuild AUR packages with a helper, official packages are only listed
+fn rebuild_packages(
+    helper: RebuildHelper,
+    packages: &[String],
+    official_packages: &HashSet<String>,
+    assume_yes: bool,
+) -> anyhow::Result<()> {
+    let (official, aur) = partition_official(packages, official_packages);
+    if !official.is_empty() {
+        eprintln!(
+            "{}",
+            paint(
+                Yellow,
+                format!(
+                    "Not rebuilding official packages, wait for their update in the repositories: {}",
+                    official
+                        .iter()
+                        .map(|p| p.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            )
+        );
     }
-    let mut trans = HashSet::<String>::new();
-    for t in trans2 {
-        if ! pacmap.contains_key(&t) {
-            trans.insert(t);
-        }
+    if aur.is_empty() {
+        println!("Nothing to do");
+        return Ok(());
     }
 
-    for missing_dep in libmap.keys() {
-        //if libmap[missing_dep].keys().len() == 1 { continue }
-        print!("package{} need rebuild because of missing {}:", if libmap[missing_dep].keys().len() > 1 { "s" } else { "" }, Yellow.paint(missing_dep));
-        for package in libmap[missing_dep].keys() {
-            print!(" {}", Red.paint(package.to_string()));
+    let mut command = match helper.rebuild_command(&aur) {
+        Some(command) => command,
+        None => {
+            eprintln!("makepkg can only rebuild packages from their PKGBUILD directory, rebuild them manually");
+            return Ok(());
         }
-        println!();
+    };
+    let command_str = format!(
+        "{} {}",
+        command.get_program().to_string_lossy(),
+        command
+            .get_args()
+            .map(|a| a.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    if !assume_yes && !confirm(&format!("Run {}?", command_str))? {
+        return Ok(());
     }
 
-    for pkg in pacmap.keys() {
-        print!("package {} misses ", Red.paint(pkg));
-        for (i, file) in pacmap[pkg].iter().enumerate() {
-            print!("{}", Yellow.paint(file));
-            if pacsourcemap.contains_key(file) {
-                print!(" from {}", Cyan.paint(pacsourcemap[file].clone()));
-            }
-            if i+1 < pacmap[pkg].len() {
-                print!(";");
-            }
-        }
-        println!();
+    let status = command
+        .status()
+        .with_context(|| format!("Failed to run {:?}", command_str))?;
+    if !status.success() {
+        anyhow::bail!("Rebuild command {:?} failed: {}", command_str, status);
     }
+    Ok(())
+}
+
+/// Parse package targets as passed by pacman to hooks, one per line, either as `name` or `name version`
+fn parse_targets(input: &str) -> Vec<String> {
+    input
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .map(|p| p.to_string())
+        .collect()
+}
 
-    if !trans.is_empty() {
-        let t3 = trans.clone();
-        let mut x = t3.iter().map(|t| Yellow.paint(t));
-        x.next().and_then(|t| { print!("{}", t); Some(t) } );
-        x.for_each(|t| print!(", {}", t));
-        println!();
+/// Read package names from a file, one per line, or from stdin if the path is `-`
+fn read_package_list(path: &Path) -> anyhow::Result<Vec<String>> {
+    let input = if path == Path::new("-") {
+        let mut input = String::new();
+        std::io::stdin()
+            .read_to_string(&mut input)
+            .context("Failed to read stdin")?;
+        input
+    } else {
+        fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?
+    };
+    Ok(parse_targets(&input))
+}
 
-        trans.clone().iter().map(|t| Yellow.paint(t)).take(1).for_each(|t| print!("{}", t));
-        trans.clone().iter().map(|t| Yellow.paint(t)).skip(1).for_each(|t| print!(", {}", t));
-        println!();
+/// Read package targets piped on stdin, return None if stdin is a terminal or empty
+fn read_stdin_targets() -> anyhow::Result<Option<Vec<String>>> {
+    let mut stdin = std::io::stdin();
+    if stdin.is_terminal() {
+        return Ok(None);
+    }
 
-        trans.clone().iter().map(|t| Yellow.paint(t)).scan("", |sep,t|{ print!("{}{}", *sep, t); *sep=", "; Some(0) }).for_each(drop);
-        println!();
+    let mut input = String::new();
+    stdin.read_to_string(&mut input)?;
+    let targets = parse_targets(&input);
 
-        for (t, i) in trans.clone().iter().zip(std::iter::once("").chain(std::iter::repeat(", "))) {
-            print!("{}{}", i, Yellow.paint(t));
-        }
-        println!();
+    Ok(if targets.is_empty() {
+        None
+    } else {
+        Some(targets)
+    })
+}
 
-        std::iter::once("").chain(std::iter::repeat(", ")).zip(trans.clone().iter().map(|t| Yellow.paint(t))).for_each(|t| print!("{}{}", t.0, t.1));
-        println!();
+/// Check for AUR packages with missing library dependencies, broken interpreter packages, and broken systemd links
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    /// Increase verbosity, up to 3 times (info, debug, trace)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print only the problems found, nothing if there is none, and no progress bar
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Report output format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    format: OutputFormat,
+
+    /// Print a single line for each broken package instead of the grouped text report
+    #[arg(long, conflicts_with = "format")]
+    compact: bool,
+
+    /// Print transitively broken packages as a tree below the broken packages they depend on
+    #[arg(long, conflicts_with = "format")]
+    tree: bool,
+
+    /// Order of broken packages in the report
+    #[arg(long, value_enum, default_value_t = SortOrder::Name)]
+    sort: SortOrder,
+
+    /// Exit with code 0 even if problems were found
+    #[arg(long)]
+    exit_zero: bool,
+
+    /// Only fail on problems of these categories, separated by commas, still reporting the others
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "CATEGORIES",
+        conflicts_with = "exit_zero"
+    )]
+    fail_on: Vec<FailCategory>,
+
+    /// Check all AUR packages, ignoring targets piped on stdin
+    #[arg(long)]
+    all: bool,
+
+    /// Do not check for Python, Perl, Ruby and Lua packages in outdated directories
+    #[arg(long, alias = "no-python", overrides_with = "runtimes")]
+    no_runtimes: bool,
+
+    /// Check for packages in outdated directories, even if disabled in the configuration file
+    #[arg(long, alias = "python", overrides_with = "no_runtimes")]
+    runtimes: bool,
+
+    /// Do not check for broken systemd enabled service links
+    #[arg(long, overrides_with = "systemd")]
+    no_systemd: bool,
+
+    /// Check for broken systemd enabled service links, even if disabled in the configuration file
+    #[arg(long, overrides_with = "no_systemd")]
+    systemd: bool,
+
+    /// Do not check for missing library dependencies
+    #[arg(long, overrides_with = "libs")]
+    no_libs: bool,
+
+    /// Check for missing library dependencies, even if disabled in the configuration file
+    #[arg(long, overrides_with = "no_libs")]
+    libs: bool,
+
+    /// Print a command to rebuild broken packages with the given tool
+    #[arg(long, value_enum, value_name = "HELPER")]
+    rebuild_cmd: Option<RebuildHelper>,
+
+    /// Rebuild broken AUR packages with the tool given by --rebuild-cmd, after confirmation
+    #[arg(long, requires = "rebuild_cmd", conflicts_with = "format")]
+    fix: bool,
+
+    /// Do not ask for confirmation before rebuilding packages
+    #[arg(short, long, requires = "fix")]
+    yes: bool,
+
+    /// Print each missing library as soon as it is found, before the summary
+    #[arg(long)]
+    stream: bool,
+
+    /// Send a desktop notification if broken packages or links are found, to logged in users if
+    /// running as root without a session
+    #[arg(long)]
+    notify: bool,
+
+    /// Always query file databases for library providers, instead of using the ones cached by
+    /// previous runs
+    #[arg(long)]
+    no_cache: bool,
+
+    /// Also write the report to this file, without colors
+    #[arg(long, value_name = "PATH")]
+    output_file: Option<PathBuf>,
+
+    /// Append the report to the output file instead of overwriting it
+    #[arg(long, requires = "output_file")]
+    append: bool,
+
+    /// Write scan metrics to this file, in Prometheus textfile collector format
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Write names of packages needing rebuild to this already open file descriptor, one per line
+    #[arg(long, value_name = "FD")]
+    rebuild_list_fd: Option<u32>,
+
+    /// Do not check packages matching this glob pattern, can be repeated. Patterns are also read
+    /// from $XDG_CONFIG_HOME/check-broken-packages/exclude, one per line, and from the exclude
+    /// setting of $XDG_CONFIG_HOME/check-broken-packages/config.toml
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// When to use colors in output [default: auto]
+    #[arg(long, value_enum)]
+    color: Option<ColorMode>,
+
+    /// Do not show a progress bar, it is also hidden when stderr is not a terminal
+    #[arg(long)]
+    no_progress: bool,
+
+    /// Download pacman file databases if they are missing, to find packages providing missing
+    /// libraries (requires root)
+    #[arg(long)]
+    sync_files: bool,
+
+    /// Also check files of packages for symlinks pointing to missing targets
+    #[arg(long)]
+    check_symlinks: bool,
+
+    /// Also flag linked libraries that exist on disk but resolve to a library with another soname
+    #[arg(long)]
+    check_providers: bool,
+
+    /// Only analyze shared objects, like libraries and plugins, not executables
+    #[arg(long, conflicts_with = "exec_only")]
+    libs_only: bool,
+
+    /// Only analyze executables, not shared objects
+    #[arg(long)]
+    exec_only: bool,
+
+    /// Do not check files in this directory, in addition to /opt and /usr/share (can be repeated)
+    #[arg(long, value_name = "DIR")]
+    skip_dir: Vec<PathBuf>,
+
+    /// Also look for libraries in this directory, before the dynamic loader ones, like with
+    /// LD_LIBRARY_PATH (can be repeated)
+    #[arg(long = "extra-libdir", value_name = "DIR")]
+    extra_lib_dirs: Vec<PathBuf>,
+
+    /// Also check files in /opt, skipped by default
+    #[arg(long, overrides_with = "no_include_opt")]
+    include_opt: bool,
+
+    /// Skip files in /opt, even if included in the configuration file
+    #[arg(long, overrides_with = "include_opt")]
+    no_include_opt: bool,
+
+    /// Print how long each stage of the scan took on stderr, stages running concurrently overlap
+    #[arg(long)]
+    timings: bool,
+
+    /// Print packages that would be scanned, enabled checks and library search paths, then exit
+    #[arg(long, conflicts_with = "fix")]
+    plan: bool,
+
+    /// Wait for another pacman process to release the database lock, instead of failing
+    #[arg(long)]
+    wait_lock: bool,
+
+    /// Run as a pacman hook, whose transaction holds the database lock, checking all packages if a
+    /// target was removed
+    #[arg(long, conflicts_with = "wait_lock")]
+    hook: bool,
+
+    /// Keep running, and scan again each time a pacman transaction changes installed packages
+    #[arg(long, conflicts_with_all = ["fix", "plan"])]
+    watch: bool,
+
+    /// Check the system installed in this root directory, like a chroot, instead of the running one
+    #[arg(long, value_name = "PATH")]
+    root: Option<PathBuf>,
+
+    /// Pacman database directory of the checked system, defaults to /var/lib/pacman in its root
+    #[arg(long, value_name = "PATH")]
+    dbpath: Option<PathBuf>,
+
+    /// Only print direct library dependencies of the files of this package as a Graphviz DOT
+    /// graph, missing libraries in red, instead of scanning
+    #[arg(
+        long,
+        value_name = "PACKAGE",
+        conflicts_with_all = ["packages", "packages_from_file", "watch", "fix", "plan"]
+    )]
+    graph: Option<String>,
+
+    /// Number of worker threads, also bounding concurrent subprocesses [default: CPU count]
+    #[arg(short, long, value_name = "N")]
+    jobs: Option<NonZeroUsize>,
+
+    /// Capacity of the queues between analysis stages, lower it to cap memory use on huge systems
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_CHANNEL_CAPACITY)]
+    channel_capacity: usize,
+
+    /// Also check GObject introspection typelibs of packages for missing libraries
+    #[arg(long)]
+    check_typelibs: bool,
+
+    /// Also check udev rules of packages for helper programs that are missing or not executable
+    #[arg(long)]
+    check_udev: bool,
+
+    /// Also check desktop entries of packages for programs that can not be found
+    #[arg(long)]
+    check_desktop_files: bool,
+
+    /// Also report sonames provided by several installed packages, of which the dynamic loader
+    /// may load the wrong one
+    #[arg(long)]
+    check_duplicate_sonames: bool,
+
+    /// Also check packages installed with pip --user or pipx for an older Python version
+    #[arg(long)]
+    check_pip: bool,
+
+    /// Also check packages from the official repositories, this is much slower
+    #[arg(long)]
+    include_official: bool,
+
+    /// Only check packages installed or upgraded since this local time, according to the pacman
+    /// log, like 2024-01-31 or "2024-01-31 18:05"
+    #[arg(
+        long,
+        value_name = "TIMESTAMP",
+        conflicts_with_all = ["packages", "packages_from_file"]
+    )]
+    since: Option<LogTimestamp>,
+
+    /// Also check packages listed in this file, one per line, `-` to read them from stdin
+    #[arg(long, value_name = "PATH")]
+    packages_from_file: Option<PathBuf>,
+
+    /// Check only these packages, instead of all AUR packages
+    #[arg(value_name = "PACKAGE")]
+    packages: Vec<String>,
+}
 
-        std::iter::once("").chain(std::iter::repeat(", ")).zip(trans.clone()).for_each(|t| print!("{}{}", t.0, Yellow.paint(t.1)));
-        println!();
+/// Color output mode
+#[derive(Clone, Copy, Debug, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum ColorMode {
+    /// Use colors if stdout is a terminal and NO_COLOR is not set
+    Auto,
+    /// Always use colors
+    Always,
+    /// Never use colors
+    Never,
+}
 
-        for (i, t) in trans.iter().map(|t| Yellow.paint(t)).enumerate() {
-            match i {
-                0 => print!("{}", t),
-                _ => print!(", {}", t)
+impl ColorMode {
+    fn enabled(self) -> bool {
+        match self {
+            ColorMode::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+                    && std::io::stdout().is_terminal()
             }
+            ColorMode::Always => true,
+            ColorMode::Never => false,
         }
-        println!();
+    }
+}
 
-        for (i, t) in trans.iter().enumerate() {
-            print!("{}{}", if i > 0 {", "} else {""}, Yellow.paint(t));
-        }
-        println!();
+/// Report output format
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// Human readable text
+    Text,
+    /// JSON document, for scripts
+    Json,
+    /// Markdown headings and tables, for bug reports
+    Markdown,
+    /// One JSON object per line for each finding, missing libraries being printed as found
+    Jsonl,
+    /// Test Anything Protocol stream, for CI harnesses
+    Tap,
+    /// CSV rows, one for each finding, for spreadsheets
+    Csv,
+}
 
-        for (i, t) in trans.iter().enumerate() {
-            print!("{}{}", ["", ", "][(i>0) as usize], Yellow.paint(t));
-        }
-        println!();
+/// Order of broken packages in the report
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum SortOrder {
+    /// Alphabetical
+    Name,
+    /// Most missing libraries first
+    Severity,
+    /// Grouped by repository package providing the missing libraries
+    Provider,
+}
 
-        for (d, p) in [", ", ""].iter().zip(trans.iter().collect::<Vec<_>>().chunks(trans.len()-1)) {
-            for e in p {
-                print!("{}{}", Yellow.paint(*e), d);
-            }
-        }
-        println!();
+/// Category of problems that can make the exit code non-zero
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum FailCategory {
+    /// Missing direct library dependencies, libraries provided by no package, and files for
+    /// another architecture
+    Libs,
+    /// Libraries only missing through dependencies
+    Transitive,
+    /// Packages in outdated interpreter and toolkit directories
+    #[value(alias = "python")]
+    Runtimes,
+    /// User pip and pipx packages installed for an older Python version
+    Pip,
+    /// Broken systemd enabled service links
+    Systemd,
+    /// Broken symbolic links in packages
+    Symlinks,
+    /// GObject introspection typelibs loading missing libraries
+    Typelibs,
+    /// udev rules running missing programs
+    Udev,
+    /// Desktop entries launching missing programs
+    Desktop,
+    /// Sonames provided by several installed packages
+    Duplicates,
+}
 
-        let mut it = trans.iter().map(|t| Yellow.paint(t));
-        if let Some(first) = it.next() {
-            print!("{}", first);
-            for e in it {
-                print!(", {}", e);
+impl FailCategory {
+    /// Check if a report has problems of this category
+    fn found_in(self, report: &BrokenReport) -> bool {
+        match self {
+            FailCategory::Libs => {
+                !report.broken_packages.is_empty()
+                    || !report.unprovided_libraries.is_empty()
+                    || !report.foreign_arch_files.is_empty()
             }
+            FailCategory::Transitive => !report.transitively_broken_packages.is_empty(),
+            FailCategory::Runtimes => !report.broken_runtime_packages.is_empty(),
+            FailCategory::Pip => !report.broken_pip_packages.is_empty(),
+            FailCategory::Systemd => !report.broken_systemd_links.is_empty(),
+            FailCategory::Symlinks => !report.broken_symlinks.is_empty(),
+            FailCategory::Typelibs => !report.broken_typelibs.is_empty(),
+            FailCategory::Udev => !report.broken_udev_rules.is_empty(),
+            FailCategory::Desktop => !report.broken_desktop_entries.is_empty(),
+            FailCategory::Duplicates => !report.duplicate_sonames.is_empty(),
         }
-        println!();
-
-        let mut sep = "transitively broken packages: "; for t in trans { print!("{}{}", sep, Yellow.paint(t)); sep = ", "; }
-        println!();
     }
+}
 
-    if env::args().any(|x| x.starts_with("-v") || x.starts_with("--v")) {
-        println!("{:#?}", libmap);
-        println!("{:#?}", pacmap);
+/// Check if a report has problems that should make the exit code non-zero, all categories count
+/// if none are given
+fn report_fails(report: &BrokenReport, fail_on: &[FailCategory]) -> bool {
+    if fail_on.is_empty() {
+        !report.is_clean()
+    } else {
+        fail_on.iter().any(|c| c.found_in(report))
     }
+}
 
-    if let Ok(broken_python_packages) = python_broken_packages_rx.recv() {
-        for (broken_python_package, dir) in broken_python_packages {
-            println!(
-                "{}",
-                Yellow.paint(format!(
-                    "Package {:?} has files in directory {:?} that are ignored by the current Python interpreter",
-                    broken_python_package, dir
-                ))
-            );
+impl SortOrder {
+    /// Sort broken packages, by name if they are otherwise equal so the order is reproducible
+    fn sort(self, broken_packages: &mut [BrokenPackage]) {
+        match self {
+            SortOrder::Name => broken_packages.sort_by(|a, b| a.package.cmp(&b.package)),
+            SortOrder::Severity => broken_packages.sort_by(|a, b| {
+                b.missing
+                    .len()
+                    .cmp(&a.missing.len())
+                    .then_with(|| a.package.cmp(&b.package))
+            }),
+            SortOrder::Provider => {
+                // Packages missing libraries of unknown providers come last
+                let provider = |p: &BrokenPackage| {
+                    p.missing
+                        .iter()
+                        .filter_map(|m| m.provider.clone())
+                        .filter(|p| p != "?")
+                        .min()
+                };
+                broken_packages.sort_by(|a, b| {
+                    match (provider(a), provider(b)) {
+                        (Some(a), Some(b)) => a.cmp(&b),
+                        (a, b) => b.is_some().cmp(&a.is_some()),
+                    }
+                    .then_with(|| a.package.cmp(&b.package))
+                })
+            }
         }
     }
+}
 
-    for broken_sd_service_link in broken_sd_service_links {
-        println!(
-            "{}",
-            Yellow.paint(format!(
-                "Systemd enabled service has broken link in {:?}",
-                &broken_sd_service_link,
-            ))
-        );
+/// Self contained finding printed as a JSON line
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum JsonlRecord<'a> {
+    /// Library missing for a package file, printed as soon as it is found
+    MissingLibrary {
+        package: &'a str,
+        file: &'a Path,
+        soname: &'a str,
+        providers: &'a [String],
+        is_direct: bool,
+    },
+    /// Package with files in an outdated interpreter or toolkit directory
+    BrokenRuntimePackage(&'a BrokenRuntimePackage),
+    /// User pip or pipx package installed for an older Python version
+    BrokenPipPackage(&'a BrokenRuntimePackage),
+    /// Broken systemd enabled unit link
+    BrokenSystemdLink(&'a BrokenLink),
+    /// Broken package symlink
+    BrokenSymlink(&'a BrokenSymlink),
+    /// Linked library resolving to a library with another soname
+    UnprovidedLibrary(&'a UnprovidedLibrary),
+    /// Typelib loading missing libraries
+    BrokenTypelib(&'a BrokenTypelib),
+    /// Udev rule running a missing program
+    BrokenUdevRule(&'a BrokenUdevRule),
+    /// Desktop entry launching a missing program
+    BrokenDesktopEntry(&'a BrokenDesktopEntry),
+    /// Soname provided by several installed packages
+    DuplicateSoname(&'a DuplicateSoname),
+    /// File built for another architecture
+    ForeignArchFile(&'a ForeignArchFile),
+    /// Problem counts, always the last record
+    Summary(&'a Summary),
+}
+
+impl<'a> From<&'a MissingDependency> for JsonlRecord<'a> {
+    fn from(missing_dep: &'a MissingDependency) -> Self {
+        JsonlRecord::MissingLibrary {
+            package: &missing_dep.package,
+            file: &missing_dep.file,
+            soname: &missing_dep.soname,
+            providers: &missing_dep.providers,
+            is_direct: missing_dep.is_direct,
+        }
+    }
+}
+
+/// Serialize a record as a JSON line, with its line feed
+fn jsonl_line(record: &JsonlRecord) -> Option<String> {
+    match serde_json::to_string(record) {
+        Ok(line) => Some(line + "\n"),
+        Err(err) => {
+            eprintln!("Failed to serialize record: {}", err);
+            None
+        }
+    }
+}
+
+/// Remove ANSI color escape sequences from text
+fn strip_ansi_codes(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // Skip the control sequence up to its final letter
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+/// Report destination, stdout and optionally a file getting a copy without colors
+struct ReportOutput {
+    file: Option<Mutex<fs::File>>,
+}
+
+impl ReportOutput {
+    /// Open the report copy file if any, truncating it unless appending
+    fn open(path: Option<&Path>, append: bool) -> anyhow::Result<Self> {
+        let file = path
+            .map(|path| {
+                fs::OpenOptions::new()
+                    .create(true)
+                    .write(true)
+                    .append(append)
+                    .truncate(!append)
+                    .open(path)
+                    .with_context(|| format!("Failed to open file {:?}", path))
+            })
+            .transpose()?;
+        Ok(Self {
+            file: file.map(Mutex::new),
+        })
+    }
+
+    /// Empty the report copy file if any, for a new report to replace the previous one
+    fn truncate(&self) -> anyhow::Result<()> {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            file.set_len(0)?;
+            file.rewind()?;
+        }
+        Ok(())
+    }
+
+    /// Print report text, and copy it to the file
+    fn print(&self, text: &str) {
+        print!("{}", text);
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap();
+            if let Err(err) = file.write_all(strip_ansi_codes(text).as_bytes()) {
+                eprintln!("Failed to write report file: {}", err);
+            }
+        }
+    }
+}
+
+/// Whether output is colorized
+static COLOR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Paint text with a color, or leave it untouched if colors are disabled
+fn paint<'a, I: Into<Cow<'a, str>>>(colour: Colour, text: I) -> ANSIString<'a> {
+    if COLOR_ENABLED.load(Ordering::Relaxed) {
+        colour.paint(text)
+    } else {
+        Style::new().paint(text)
+    }
+}
+
+/// Get user configuration directory for this program
+fn config_dir() -> Option<PathBuf> {
+    config_home().map(|d| d.join("check-broken-packages"))
+}
+
+/// Get user configuration base directory
+fn config_home() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".config")))
+}
+
+/// Get user cache base directory
+fn cache_home() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME")
+        .filter(|d| !d.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| Path::new(&h).join(".cache")))
+}
+
+/// Persistent settings read from the configuration file, overridden by command line options
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct Config {
+    /// Do not check for packages in outdated interpreter or toolkit directories
+    no_runtimes: bool,
+
+    /// Do not check for broken systemd enabled service links
+    no_systemd: bool,
+
+    /// Do not check for missing library dependencies
+    no_libs: bool,
+
+    /// When to use colors in output
+    color: Option<ColorMode>,
+
+    /// Additional library directories
+    lib_dirs: Vec<PathBuf>,
+
+    /// Additional directories whose files are not checked
+    skip_dirs: Vec<PathBuf>,
+
+    /// Also check files in /opt
+    include_opt: bool,
+
+    /// Glob patterns of packages not to check
+    exclude: Option<Vec<String>>,
+}
+
+/// Read the configuration file if it exists
+fn read_config(path: &Path) -> anyhow::Result<Config> {
+    if !path.is_file() {
+        return Ok(Config::default());
+    }
+    let config = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    toml::from_str(&config).with_context(|| format!("Failed to parse {:?}", path))
+}
+
+impl Cli {
+    /// Apply configuration file settings not set on the command line
+    fn merge_config(&mut self, config: Config) {
+        if !self.runtimes {
+            self.no_runtimes |= config.no_runtimes;
+        }
+        if !self.systemd {
+            self.no_systemd |= config.no_systemd;
+        }
+        if !self.libs {
+            self.no_libs |= config.no_libs;
+        }
+        self.color = self.color.or(config.color);
+        self.skip_dir.extend(config.skip_dirs);
+        self.extra_lib_dirs.extend(config.lib_dirs);
+        if !self.no_include_opt {
+            self.include_opt |= config.include_opt;
+        }
+        self.exclude.extend(config.exclude.unwrap_or_default());
+    }
+
+    /// Get names of enabled checks
+    fn enabled_checks(&self) -> Vec<&'static str> {
+        [
+            (!self.no_libs, "libraries"),
+            (!self.no_runtimes, "interpreter and toolkit directories"),
+            (!self.no_systemd, "systemd links"),
+            (self.check_symlinks, "package symlinks"),
+            (self.check_providers, "library providers"),
+            (self.check_typelibs, "typelibs"),
+            (self.check_udev, "udev rules"),
+            (self.check_desktop_files, "desktop entries"),
+            (self.check_duplicate_sonames, "duplicate sonames"),
+            (self.check_pip, "pip and pipx packages"),
+        ]
+        .iter()
+        .filter(|(enabled, _)| *enabled)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+
+    /// Check if packages to check were given, instead of checking all AUR packages
+    fn has_package_list(&self) -> bool {
+        !self.packages.is_empty() || self.packages_from_file.is_some()
+    }
+
+    /// Get the checked system
+    fn system_root(&self) -> SystemRoot {
+        SystemRoot {
+            root: self.root.clone(),
+            dbpath: self.dbpath.clone(),
+        }
+    }
+
+    /// Get directories whose files are not checked
+    fn skip_dirs(&self) -> Vec<PathBuf> {
+        DEFAULT_SKIP_DIRS
+            .iter()
+            .map(PathBuf::from)
+            .chain(self.skip_dir.iter().cloned())
+            .filter(|d| !(self.include_opt && d == Path::new("/opt")))
+            .collect()
+    }
+}
+
+/// Parse package exclusion patterns, one per line, ignoring empty lines and comments
+fn parse_exclude_patterns(s: &str) -> anyhow::Result<Vec<glob::Pattern>> {
+    s.lines()
+        .map(|l| l.split('#').next().unwrap().trim())
+        .filter(|l| !l.is_empty())
+        .map(|l| glob::Pattern::new(l).with_context(|| format!("Invalid pattern {:?}", l)))
+        .collect()
+}
+
+/// Get package exclusion patterns from the command line and the user configuration file
+fn get_exclude_patterns(cli_patterns: &[String]) -> anyhow::Result<Vec<glob::Pattern>> {
+    let mut patterns = parse_exclude_patterns(&cli_patterns.join("\n"))?;
+    if let Some(exclude_filepath) = config_dir().map(|d| d.join("exclude")) {
+        if exclude_filepath.is_file() {
+            let exclude_file = fs::read_to_string(&exclude_filepath)
+                .with_context(|| format!("Failed to read {:?}", exclude_filepath))?;
+            patterns.extend(
+                parse_exclude_patterns(&exclude_file)
+                    .with_context(|| format!("Failed to parse {:?}", exclude_filepath))?,
+            );
+        }
+    }
+    Ok(patterns)
+}
+
+/// Get log level from the number of verbose flags, warnings and errors only by default
+fn log_level(verbose: u8) -> LevelFilter {
+    match verbose {
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+/// Create the progress bar showing the package being analyzed, or a spinner with elapsed time and
+/// the current stage if the total work count is not known
+fn new_progress_bar(total: Option<u64>, draw_target: ProgressDrawTarget) -> ProgressBar {
+    match total {
+        Some(total) => {
+            let progress = ProgressBar::with_draw_target(total, draw_target);
+            progress.set_style(
+                // Package names are padded and truncated, so the bar width does not change
+                ProgressStyle::default_bar().template("Analyzing {wide_bar} {pos}/{len} {msg:24!}"),
+            );
+            progress
+        }
+        None => {
+            let progress = ProgressBar::new_spinner();
+            progress.set_draw_target(draw_target);
+            progress.set_style(
+                ProgressStyle::default_spinner().template("{spinner} [{elapsed}] {msg}"),
+            );
+            progress.enable_steady_tick(100);
+            progress
+        }
+    }
+}
+
+/// Severity of a finding in the text report, each with its own tag and color
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Severity {
+    /// Something fails to run now
+    Error,
+    /// Something needs a rebuild or a cleanup
+    Warning,
+    /// Nothing to fix directly
+    Info,
+}
+
+impl Severity {
+    /// Get severity of a broken package, libraries with no installed version at all are errors
+    fn of_package(broken_package: &BrokenPackage) -> Self {
+        if broken_package
+            .missing
+            .iter()
+            .any(|m| m.kind == MissingKind::Absent)
+        {
+            Severity::Error
+        } else {
+            Severity::Warning
+        }
+    }
+
+    fn colour(self) -> Colour {
+        match self {
+            Severity::Error => Red,
+            Severity::Warning => Yellow,
+            Severity::Info => Blue,
+        }
+    }
+
+    /// Get colored tag starting finding lines
+    fn tag(self) -> ANSIString<'static> {
+        let tag = match self {
+            Severity::Error => "[ERROR]",
+            Severity::Warning => "[WARNING]",
+            Severity::Info => "[INFO]",
+        };
+        paint(self.colour(), tag)
+    }
+}
+
+/// Write a finding line of the text report, colored and tagged with its severity
+fn write_finding(out: &mut String, severity: Severity, text: String) -> std::fmt::Result {
+    writeln!(out, "{} {}", severity.tag(), paint(severity.colour(), text))
+}
+
+/// Write transitively broken packages depending on a package below it, each package being written
+/// only once, also to break dependency cycles
+fn write_transitive_branch<'a>(
+    out: &mut String,
+    report: &'a BrokenReport,
+    package: &str,
+    prefix: &str,
+    written: &mut HashSet<&'a str>,
+) -> std::fmt::Result {
+    let children: Vec<&str> = report
+        .reverse_deps
+        .get(package)
+        .into_iter()
+        .flatten()
+        .map(String::as_str)
+        .filter(|p| report.transitively_broken_packages.iter().any(|t| t == p))
+        .filter(|p| !written.contains(p))
+        .collect();
+    written.extend(children.iter().copied());
+    for (i, child) in children.iter().enumerate() {
+        let is_last = i + 1 == children.len();
+        writeln!(
+            out,
+            "{}{}{}",
+            prefix,
+            if is_last { "└─ " } else { "├─ " },
+            paint(Yellow, *child)
+        )?;
+        let child_prefix = format!("{}{}", prefix, if is_last { "   " } else { "│  " });
+        write_transitive_branch(out, report, child, &child_prefix, written)?;
+    }
+    Ok(())
+}
+
+/// Write transitively broken packages as trees rooted at the broken packages they depend on,
+/// from reverse dependencies, and get the ones that could not be placed in a tree
+fn write_transitive_tree<'a>(
+    out: &mut String,
+    report: &'a BrokenReport,
+) -> Result<Vec<&'a str>, std::fmt::Error> {
+    let mut written = HashSet::new();
+    let mut trees = String::new();
+    for broken_package in &report.broken_packages {
+        let mut branch = String::new();
+        write_transitive_branch(
+            &mut branch,
+            report,
+            &broken_package.package,
+            "",
+            &mut written,
+        )?;
+        if !branch.is_empty() {
+            writeln!(trees, "{}", paint(Red, &broken_package.package))?;
+            trees.push_str(&branch);
+        }
+    }
+    if !trees.is_empty() {
+        writeln!(
+            out,
+            "{} transitively broken packages, below the broken packages they depend on:",
+            Severity::Info.tag()
+        )?;
+        out.push_str(&trees);
+    }
+
+    Ok(report
+        .transitively_broken_packages
+        .iter()
+        .map(String::as_str)
+        .filter(|p| !written.contains(p))
+        .collect())
+}
+
+/// Write a report as human readable text, with colors if enabled
+fn write_text_report(
+    out: &mut String,
+    report: &BrokenReport,
+    official_packages: &HashSet<String>,
+    verbose: bool,
+    quiet: bool,
+    compact: bool,
+    tree: bool,
+) -> std::fmt::Result {
+    // Group packages by missing soname, groups following the sort order of their first package
+    let mut libmap = Vec::<(&str, Vec<&str>)>::new();
+    for broken_package in &report.broken_packages {
+        for missing in &broken_package.missing {
+            let group = match libmap
+                .iter()
+                .position(|(soname, _)| *soname == missing.soname)
+            {
+                Some(group) => group,
+                None => {
+                    libmap.push((&missing.soname, Vec::new()));
+                    libmap.len() - 1
+                }
+            };
+            libmap[group].1.push(&broken_package.package);
+        }
+    }
+
+    // Broken authentication modules come first, they can lock users out
+    for broken_package in &report.broken_packages {
+        for security_module in &broken_package.security_modules {
+            write_finding(
+                out,
+                Severity::Error,
+                format!(
+                    "package {:?} has PAM module or NSS plugin {} missing libraries, login may fail",
+                    broken_package.package, security_module
+                ),
+            )?;
+        }
+    }
+
+    // Grouped views, left out in quiet and compact modes
+    if !quiet && !compact {
+        // Root causes first, a single rebuild batch often fixes most packages
+        for (provider, packages) in report.breaking_providers() {
+            write!(
+                out,
+                "Rebuilding after {} bump fixes {} package{}: ",
+                paint(Cyan, &provider),
+                packages.len(),
+                if packages.len() > 1 { "s" } else { "" }
+            )?;
+            for (i, package) in packages.iter().enumerate() {
+                write!(out, "{}", paint(Red, package))?;
+                if i + 1 < packages.len() {
+                    write!(out, ", ")?;
+                }
+            }
+            writeln!(out)?;
+        }
+
+        for (missing_dep, packages) in &libmap {
+            write!(
+                out,
+                "package{} need rebuild because of missing {}:",
+                if packages.len() > 1 { "s" } else { "" },
+                paint(Yellow, *missing_dep)
+            )?;
+            for package in packages {
+                write!(out, " {}", paint(Red, *package))?;
+            }
+            writeln!(out)?;
+        }
+    }
+
+    if compact {
+        for broken_package in &report.broken_packages {
+            let sonames: Vec<&str> = broken_package
+                .missing
+                .iter()
+                .map(|m| m.soname.as_str())
+                .collect();
+            let action = if broken_package
+                .missing
+                .iter()
+                .any(|m| m.kind == MissingKind::VersionBump)
+            {
+                "rebuild"
+            } else {
+                "install provider"
+            };
+            writeln!(
+                out,
+                "{} {}: missing {} ({})",
+                Severity::of_package(broken_package).tag(),
+                paint(Red, &broken_package.package),
+                paint(Yellow, sonames.join(", ")),
+                action
+            )?;
+        }
+    } else {
+        for broken_package in &report.broken_packages {
+            write!(
+                out,
+                "{} package {} ",
+                Severity::of_package(broken_package).tag(),
+                paint(Red, &broken_package.package)
+            )?;
+            if verbose && official_packages.contains(&broken_package.package) {
+                write!(out, "(official) ")?;
+            }
+            write!(out, "misses ")?;
+            for (i, missing) in broken_package.missing.iter().enumerate() {
+                write!(out, "{}", paint(Yellow, &missing.soname))?;
+                match &missing.provider {
+                    Some(provider) if provider != "?" => {
+                        write!(out, " from {}", paint(Cyan, provider))?;
+                        if !missing.provider_installed {
+                            write!(
+                                out,
+                                " (not installed, run {})",
+                                paint(Cyan, format!("pacman -S {}", provider))
+                            )?;
+                        }
+                    }
+                    _ => write!(out, " from an unknown package")?,
+                }
+                write!(out, " ({})", missing.kind)?;
+                if i + 1 < broken_package.missing.len() {
+                    write!(out, ";")?;
+                }
+            }
+            writeln!(out)?;
+            if !broken_package.required_by.is_empty() {
+                write!(out, "  required by:")?;
+                for reverse_dep in &broken_package.required_by {
+                    write!(out, " {}", paint(Red, reverse_dep))?;
+                }
+                writeln!(out)?;
+            }
+        }
+    }
+
+    let flat_transitive_packages: Vec<&str> = if tree {
+        write_transitive_tree(out, report)?
+    } else {
+        report
+            .transitively_broken_packages
+            .iter()
+            .map(String::as_str)
+            .collect()
+    };
+    if !flat_transitive_packages.is_empty() {
+        write!(out, "{} ", Severity::Info.tag())?;
+        let mut sep = "transitively broken packages: ";
+        for package in flat_transitive_packages {
+            write!(out, "{}{}", sep, paint(Yellow, package))?;
+            sep = ", ";
+        }
+        writeln!(out)?;
+    }
+
+    debug!("Missing libraries: {:#?}", libmap);
+    debug!("Broken packages: {:#?}", report.broken_packages);
+
+    for broken_runtime_package in &report.broken_runtime_packages {
+        write_finding(
+            out,
+            Severity::Warning,
+            format!(
+                "Package {:?} has files in directory {:?} that are ignored by the installed {}",
+                broken_runtime_package.package,
+                broken_runtime_package.dir,
+                broken_runtime_package.runtime
+            ),
+        )?;
+    }
+
+    for broken_pip_package in &report.broken_pip_packages {
+        write_finding(
+            out,
+            Severity::Warning,
+            format!(
+                "pip package {:?} is installed in directory {:?} for an older Python version",
+                broken_pip_package.package, broken_pip_package.dir
+            ),
+        )?;
+    }
+
+    for broken_sd_service_link in &report.broken_systemd_links {
+        write_finding(
+            out,
+            Severity::Error,
+            format!("Systemd enabled service {}", broken_sd_service_link),
+        )?;
+    }
+
+    for broken_symlink in &report.broken_symlinks {
+        write_finding(
+            out,
+            Severity::Warning,
+            format!(
+                "Package {:?}: {}",
+                broken_symlink.package, broken_symlink.broken_link
+            ),
+        )?;
+    }
+
+    for broken_typelib in &report.broken_typelibs {
+        write_finding(
+            out,
+            Severity::Error,
+            format!(
+                "Package {:?} has typelib {} loading missing {}",
+                broken_typelib.package,
+                broken_typelib.typelib.display(),
+                broken_typelib.missing.join(", ")
+            ),
+        )?;
+    }
+
+    for broken_udev_rule in &report.broken_udev_rules {
+        write_finding(
+            out,
+            Severity::Warning,
+            format!(
+                "Package {:?} has udev rule {} running missing or non executable {}",
+                broken_udev_rule.package,
+                broken_udev_rule.rule.display(),
+                broken_udev_rule.program.display()
+            ),
+        )?;
+    }
+
+    for broken_desktop_entry in &report.broken_desktop_entries {
+        write_finding(
+            out,
+            Severity::Warning,
+            format!(
+                "Package {:?} has desktop entry {} launching missing {}",
+                broken_desktop_entry.package,
+                broken_desktop_entry.desktop_file.display(),
+                broken_desktop_entry.program
+            ),
+        )?;
+    }
+
+    for duplicate_soname in &report.duplicate_sonames {
+        write_finding(out, Severity::Info, duplicate_soname.to_string())?;
+    }
+
+    for unprovided_library in &report.unprovided_libraries {
+        write_finding(
+            out,
+            Severity::Warning,
+            format!(
+                "Package {:?} links {} which resolves to a library with another soname",
+                unprovided_library.package, unprovided_library.soname
+            ),
+        )?;
+    }
+
+    for foreign_arch_file in &report.foreign_arch_files {
+        write_finding(out, Severity::Warning, foreign_arch_file.to_string())?;
+    }
+
+    // A package that could not be listed is entirely unchecked
+    for unlisted_package in &report.unlisted_packages {
+        write_finding(out, Severity::Warning, unlisted_package.to_string())?;
+    }
+
+    if verbose {
+        for unchecked_file in &report.unchecked_files {
+            write_finding(out, Severity::Info, unchecked_file.to_string())?;
+        }
+    }
+
+    if !quiet && !report.is_clean() && COLOR_ENABLED.load(Ordering::Relaxed) {
+        writeln!(
+            out,
+            "{} breaks programs now, {} needs a rebuild or cleanup, {} is for information",
+            Severity::Error.tag(),
+            Severity::Warning.tag(),
+            Severity::Info.tag()
+        )?;
+    }
+
+    if let Some(rebuild_command) = &report.rebuild_command {
+        writeln!(
+            out,
+            "To rebuild broken packages, run: {}",
+            paint(Cyan, rebuild_command)
+        )?;
+    }
+
+    if !quiet {
+        writeln!(out, "{}", report.summary)?;
+    }
+
+    Ok(())
+}
+
+/// Exit code when problems were found
+const EXIT_CODE_PROBLEMS_FOUND: u8 = 1;
+
+/// Exit code when the scan was interrupted, as for shells on SIGINT
+const EXIT_CODE_INTERRUPTED: u8 = 130;
+
+fn main() -> anyhow::Result<ExitCode> {
+    // Parse command line and configuration file
+    let mut cli = Cli::parse();
+    let config_filepath = config_dir().map(|d| d.join("config.toml"));
+    let (config, config_err) = match config_filepath.as_deref().map(read_config) {
+        Some(Ok(config)) => (config, None),
+        Some(Err(err)) => (Config::default(), Some(err)),
+        None => (Config::default(), None),
+    };
+    cli.merge_config(config);
+    let extra_lib_dirs = cli.extra_lib_dirs.clone();
+    if cli.no_runtimes && cli.no_systemd && cli.no_libs {
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "at least one check must be enabled",
+            )
+            .exit();
+    }
+    COLOR_ENABLED.store(
+        cli.color.unwrap_or(ColorMode::Auto).enabled(),
+        Ordering::Relaxed,
+    );
+    if let Some(err) = config_err {
+        eprintln!(
+            "{}",
+            paint(Yellow, format!("{:#}, using default settings", err))
+        );
+    }
+
+    // Init logger
+    SimpleLogger::new()
+        .with_level(log_level(cli.verbose))
+        .init()
+        .context("Failed to init logger")?;
+
+    let system_root = cli.system_root();
+
+    // Fail once before doing anything, rather than for every file or package
+    for (program, install_hint) in required_programs(&cli) {
+        if find_program(program).is_none() {
+            anyhow::bail!(
+                "Required program {} not found in PATH, {}",
+                program,
+                install_hint
+            );
+        }
+    }
+    // The dynamic loader cache is only an optimization, library directories are scanned without it
+    if find_program("ldconfig").is_none() {
+        info!("ldconfig not found in PATH, falling back to scanning library directories");
+    }
+
+    if let Some(package) = &cli.graph {
+        let uninstalled_packages =
+            get_uninstalled_packages(&system_root, std::slice::from_ref(package))
+                .context("Unable to check if package is installed")?;
+        if !uninstalled_packages.is_empty() {
+            anyhow::bail!("Package not installed: {}", package);
+        }
+        let dependencies = get_package_dependencies(&system_root, package, &extra_lib_dirs)
+            .context("Unable to get library dependencies of package")?;
+        print!("{}", dependency_graph_dot(package, &dependencies));
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Stop the scan on first Ctrl-C or SIGTERM to print partial results, exit right away on the
+    // second one
+    let stop = Arc::new(AtomicBool::new(false));
+    {
+        let stop = Arc::clone(&stop);
+        ctrlc::set_handler(move || {
+            if stop.swap(true, Ordering::Relaxed) {
+                std::process::exit(EXIT_CODE_INTERRUPTED.into());
+            }
+        })
+        .context("Failed to set interrupt handler")?;
+    }
+
+    // Open the report copy file early, not to find out it can not be written after the scan
+    let report_output =
+        ReportOutput::open(cli.output_file.as_deref().filter(|_| !cli.plan), cli.append)
+            .context("Unable to open report file")?;
+
+    if !cli.watch {
+        return scan(&cli, &extra_lib_dirs, &stop, &report_output);
+    }
+
+    // Scan again each time a pacman transaction changes installed packages, until interrupted,
+    // each report replacing the previous one in the copy file unless appending
+    loop {
+        if let Err(err) = scan(&cli, &extra_lib_dirs, &stop, &report_output) {
+            eprintln!("{:#}", err);
+        }
+        if stop.load(Ordering::Relaxed)
+            || !wait_for_local_database_change(&system_root, &stop)
+                .context("Unable to watch the local package database")?
+        {
+            return Ok(ExitCode::SUCCESS);
+        }
+        debug!("Local package database changed, scanning again");
+        if !cli.append {
+            report_output
+                .truncate()
+                .context("Unable to truncate report file")?;
+        }
+    }
+}
+
+/// Run enabled checks and print the report, return the exit code telling if problems were found
+fn scan(
+    cli: &Cli,
+    extra_lib_dirs: &[PathBuf],
+    stop: &Arc<AtomicBool>,
+    report_output: &ReportOutput,
+) -> anyhow::Result<ExitCode> {
+    let start = Instant::now();
+    let system_root = cli.system_root();
+
+    // Packages can change while they are analyzed during another transaction, unless it is the one
+    // running this as a hook
+    if pacman_database_locked(&system_root) && !cli.hook {
+        if !cli.wait_lock {
+            anyhow::bail!(
+                "Pacman database is locked, another pacman process is running. Retry once it is \
+                 done, or pass --wait-lock to wait for it. If no pacman process is running, \
+                 remove {}",
+                system_root.db_lock_path().display()
+            );
+        }
+        eprintln!("Waiting for another pacman process to release the database lock...");
+        if !wait_for_database_unlock(&system_root, LOCK_WAIT_TIMEOUT, stop) {
+            anyhow::bail!("Pacman database is still locked, giving up");
+        }
+    }
+
+    // Check interpreter packages in the background
+    let runtime_system_root = system_root.clone();
+    let runtime_check = if cli.no_runtimes || cli.plan {
+        None
+    } else {
+        Some(
+            thread::Builder::new()
+                .spawn(move || timed(|| check_runtimes(&runtime_system_root)))
+                .context("Failed to start thread")?,
+        )
+    };
+
+    // Get worker thread count, also bounding concurrent subprocesses
+    let jobs = cli.jobs.map_or_else(num_cpus::get, NonZeroUsize::get);
+
+    // Get package names
+    let packages_start = Instant::now();
+    // Package names, also needed by the optional checks of package files when libraries are not
+    // checked
+    let mut aur_packages = if cli.has_package_list() {
+        let mut packages = cli.packages.clone();
+        if let Some(list_filepath) = &cli.packages_from_file {
+            let mut listed_packages =
+                read_package_list(list_filepath).context("Unable to read package list")?;
+            listed_packages.retain(|p| !packages.contains(p));
+            packages.extend(listed_packages);
+        }
+        // Check all packages at once, failing for those of the command line, only skipping those
+        // of the file
+        let uninstalled_packages = if packages.is_empty() {
+            Vec::new()
+        } else {
+            get_uninstalled_packages(&system_root, &packages)
+                .context("Unable to check if packages are installed")?
+        };
+        let (uninstalled_args, uninstalled_listed): (Vec<&String>, Vec<&String>) =
+            uninstalled_packages
+                .iter()
+                .partition(|p| cli.packages.contains(*p));
+        if !uninstalled_args.is_empty() {
+            anyhow::bail!(
+                "Package(s) not installed: {}",
+                uninstalled_args
+                    .iter()
+                    .map(|p| p.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        if !uninstalled_listed.is_empty() {
+            eprintln!(
+                "{}",
+                paint(
+                    Yellow,
+                    format!(
+                        "Ignoring package(s) not installed: {}",
+                        uninstalled_listed
+                            .iter()
+                            .map(|p| p.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                )
+            );
+        }
+        packages.retain(|p| !uninstalled_packages.contains(p));
+        packages
+    } else {
+        get_aur_packages(&system_root).context("Unable to get list of AUR packages")?
+    };
+
+    // Add official packages if requested, also needed to never rebuild them, and to tell them
+    // apart in notifications
+    let include_official = cli.include_official && !cli.has_package_list();
+    let mut official_packages = HashSet::new();
+    if include_official || cli.fix || cli.notify {
+        official_packages.extend(
+            get_official_packages(&system_root)
+                .context("Unable to get list of official packages")?,
+        );
+    }
+    if include_official {
+        aur_packages.extend(official_packages.iter().cloned());
+    }
+
+    // Filter out excluded packages
+    let exclude_patterns =
+        get_exclude_patterns(&cli.exclude).context("Unable to get package exclusion patterns")?;
+    aur_packages.retain(|p| {
+        let excluded = exclude_patterns.iter().any(|e| e.matches(p));
+        if excluded {
+            debug!("Excluding package {:?}", p);
+        }
+        !excluded
+    });
+
+    // Packages requiring broken ones are only reported if they could have been checked
+    let scan_scope: HashSet<String> = aur_packages.iter().cloned().collect();
+
+    // Restrict to packages changed recently if requested
+    if let Some(since) = cli.since {
+        let changed_packages =
+            get_packages_changed_since(&system_root, since).context("Unable to read pacman log")?;
+        debug!("Packages changed since {:?}: {:?}", since, changed_packages);
+        aur_packages.retain(|p| changed_packages.contains(p));
+    }
+
+    // Restrict to upgraded packages and their reverse dependencies if we got targets from pacman
+    if !cli.all && !cli.watch && !cli.has_package_list() {
+        if let Some(targets) = read_stdin_targets().context("Failed to read targets from stdin")? {
+            debug!("Targets: {:?}", targets);
+            // Targets removed by the transaction running this hook have no reverse dependencies
+            // left to query, anything could have needed their files
+            let removed_targets = if cli.hook {
+                get_uninstalled_packages(&system_root, &targets).unwrap_or_else(|err| {
+                    debug!("Failed to check if targets are installed: {:#}", err);
+                    targets.clone()
+                })
+            } else {
+                Vec::new()
+            };
+            if removed_targets.is_empty() {
+                let scope = get_reverse_deps_closure(&system_root, &targets);
+                aur_packages.retain(|p| scope.contains(p));
+            } else {
+                info!(
+                    "Target(s) not installed anymore: {}, checking all packages",
+                    removed_targets.join(", ")
+                );
+            }
+        }
+    }
+    let packages_duration = packages_start.elapsed();
+    info!("Checking {} package(s)", aur_packages.len());
+
+    if cli.plan {
+        let lib_dirs = library_search_paths(&system_root, extra_lib_dirs)
+            .context("Unable to get library search paths")?;
+        print!(
+            "{}",
+            plan_text(
+                &cli.enabled_checks(),
+                &lib_dirs,
+                &cli.skip_dirs(),
+                &aur_packages
+            )
+        );
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // Providers of missing libraries are found with the file databases
+    if !cli.no_libs && !files_databases_synced(&system_root) {
+        if cli.sync_files {
+            sync_files_databases(&system_root).context("Unable to sync file databases")?;
+        } else if !cli.quiet {
+            eprintln!(
+                "{}",
+                paint(
+                    Yellow,
+                    "Pacman file databases are not synced, packages providing missing libraries \
+                     can not be found. Run `pacman -Fy` or pass --sync-files to fix this."
+                )
+            );
+        }
+    }
+
+    // Hide progress if disabled or not drawing to a terminal, to not garble logs
+    let progress_draw_target = || {
+        if cli.no_progress || cli.quiet || !std::io::stderr().is_terminal() {
+            ProgressDrawTarget::hidden()
+        } else {
+            ProgressDrawTarget::stderr()
+        }
+    };
+
+    // Get package files, once for all checks, pacman not telling how far it got
+    let files_listing_start = Instant::now();
+    let files_listing_progress = new_progress_bar(None, progress_draw_target());
+    files_listing_progress.set_message("Listing package files");
+    let list_files = !cli.no_libs
+        || cli.check_symlinks
+        || cli.check_typelibs
+        || cli.check_udev
+        || cli.check_desktop_files;
+    let packages_files = if list_files {
+        list_packages_files(&system_root, &aur_packages)
+    } else {
+        Ok(Default::default())
+    };
+    files_listing_progress.finish_and_clear();
+    let (packages_files, unlisted_packages) =
+        packages_files.context("Unable to list files of packages")?;
+    // Only the ELF file analysis is skipped without the library check
+    let checked_packages: &[String] = if list_files { &aur_packages } else { &[] };
+    let lib_packages: &[String] = if cli.no_libs { &[] } else { &aur_packages };
+    let files_listing_duration = files_listing_start.elapsed();
+
+    // Get systemd enabled services
+    let sd_links_listing_start = Instant::now();
+    let enabled_sd_service_links = if cli.no_systemd {
+        Vec::new()
+    } else {
+        get_sd_enabled_service_links(&system_root).context("Unable to Systemd enabled services")?
+    };
+    let sd_links_listing_duration = sd_links_listing_start.elapsed();
+
+    // Init progressbar
+    let progress = new_progress_bar(
+        Some((lib_packages.len() + enabled_sd_service_links.len()) as u64),
+        progress_draw_target(),
+    );
+
+    // Print missing libraries as they are found if requested
+    let stream = cli.stream && cli.format == OutputFormat::Text;
+    let print_missing = |missing_dep: &MissingDependency| {
+        if cli.format == OutputFormat::Jsonl {
+            if let Some(line) = jsonl_line(&missing_dep.into()) {
+                report_output.print(&line);
+            }
+            return;
+        }
+        if !stream {
+            return;
+        }
+        let line = format!(
+            "{}: missing {} in {}",
+            paint(Red, missing_dep.package.as_str()),
+            paint(Yellow, missing_dep.soname.as_str()),
+            missing_dep.file.display()
+        );
+        // Printing through a hidden progress bar is a no-op
+        if progress.is_hidden() {
+            println!("{}", line);
+        } else {
+            progress.println(line);
+        }
+    };
+    let library_check_options = LibraryCheckOptions {
+        jobs,
+        extra_lib_dirs: extra_lib_dirs.to_vec(),
+        skip_dirs: cli.skip_dirs(),
+        file_kinds: if cli.libs_only {
+            LinkedFileKinds::SharedObjects
+        } else if cli.exec_only {
+            LinkedFileKinds::Executables
+        } else {
+            LinkedFileKinds::All
+        },
+        check_providers: cli.check_providers,
+        channel_capacity: cli.channel_capacity,
+        stop: Arc::clone(stop),
+        provider_cache_path: if cli.no_cache {
+            None
+        } else {
+            cache_home().map(|d| d.join("check-broken-packages").join("providers.json"))
+        },
+        system_root: system_root.clone(),
+        exclude: exclude_patterns,
+        scan_scope: Some(scan_scope),
+    };
+    let report_progress = |event: CheckProgress| match event {
+        CheckProgress::Package(package) => progress.set_message(package),
+        CheckProgress::Done => progress.inc(1),
+    };
+    let run_library_check = || {
+        timed(|| {
+            check_libraries(
+                lib_packages,
+                &packages_files,
+                &library_check_options,
+                &report_progress,
+                &print_missing,
+            )
+        })
+    };
+    let run_systemd_check = || {
+        timed(|| {
+            check_systemd_links(
+                &system_root,
+                &enabled_sd_service_links,
+                jobs,
+                &report_progress,
+            )
+        })
+    };
+    let ((libraries, libraries_duration), (broken_sd_service_links, sd_check_duration)) =
+        if jobs == 1 {
+            // Fully serial run
+            (run_library_check(), run_systemd_check())
+        } else {
+            thread::scope(|scope| {
+                // Check systemd links while packages are analyzed
+                let sd_check = scope.spawn(run_systemd_check);
+                (run_library_check(), sd_check.join().unwrap())
+            })
+        };
+    let libraries = libraries?;
+
+    let optional_checks_start = Instant::now();
+    let broken_symlinks = if cli.check_symlinks {
+        check_package_symlinks(&system_root, &packages_files)
+    } else {
+        Vec::new()
+    };
+
+    let broken_typelibs = if cli.check_typelibs {
+        check_typelibs(
+            &system_root,
+            &packages_files,
+            &library_check_options.extra_lib_dirs,
+        )
+        .context("Unable to check typelibs")?
+    } else {
+        Vec::new()
+    };
+
+    let broken_udev_rules = if cli.check_udev {
+        check_udev_rules(&system_root, &packages_files)
+    } else {
+        Vec::new()
+    };
+
+    let broken_desktop_entries = if cli.check_desktop_files {
+        check_desktop_entries(&system_root, &packages_files)
+    } else {
+        Vec::new()
+    };
+
+    let duplicate_sonames = if cli.check_duplicate_sonames {
+        check_duplicate_sonames(&system_root, &library_check_options.extra_lib_dirs)
+            .context("Unable to check for duplicate sonames")?
+    } else {
+        Vec::new()
+    };
+
+    let broken_pip_packages = match env::var_os("HOME") {
+        Some(home) if cli.check_pip => check_pip_packages(&system_root, Path::new(&home))
+            .context("Unable to check pip packages")?,
+        _ => Vec::new(),
+    };
+    let optional_checks_duration = optional_checks_start.elapsed();
+
+    progress.finish_and_clear();
+
+    // Build report
+    let (runtimes, runtime_duration) = match runtime_check {
+        Some(t) => {
+            let (runtimes, duration) = t.join().unwrap_or_default();
+            (runtimes, Some(duration))
+        }
+        None => (RuntimeCheck::default(), None),
+    };
+    let mut report = BrokenReport::new(libraries, runtimes, broken_sd_service_links);
+    for warning in &report.warnings {
+        eprintln!("{}", warning);
+    }
+    cli.sort.sort(&mut report.broken_packages);
+    report.broken_symlinks = broken_symlinks;
+    report.broken_typelibs = broken_typelibs;
+    report.broken_udev_rules = broken_udev_rules;
+    report.broken_desktop_entries = broken_desktop_entries;
+    report.broken_pip_packages = broken_pip_packages;
+    report.duplicate_sonames = duplicate_sonames;
+    report.unlisted_packages = unlisted_packages;
+    let packages_to_rebuild = if cli.rebuild_cmd.is_some() {
+        report.rebuild_order().unwrap_or_else(|| {
+            eprintln!(
+                "{}",
+                paint(
+                    Yellow,
+                    "Dependency cycle between packages to rebuild, using alphabetical order"
+                )
+            );
+            report.packages_to_rebuild()
+        })
+    } else {
+        Vec::new()
+    };
+    if let Some(rebuild_helper) = cli.rebuild_cmd {
+        if !packages_to_rebuild.is_empty() {
+            report.rebuild_command = Some(rebuild_helper.command(&packages_to_rebuild));
+        }
     }
 
-    Ok(())
+    report.summary = Summary::new(&report, checked_packages.len(), start.elapsed());
+
+    if let Some(metrics_filepath) = &cli.metrics_file {
+        write_file_atomic(metrics_filepath, &prometheus_metrics(&report.summary))
+            .context("Unable to write metrics file")?;
+    }
+
+    if cli.notify {
+        if let Some(body) = notification_text(&report, &official_packages) {
+            if let Err(err) = send_notification(&body) {
+                eprintln!("Failed to send desktop notification: {}", err);
+            }
+        }
+    }
+
+    let interrupted = stop.load(Ordering::Relaxed);
+    if interrupted {
+        eprintln!(
+            "{}",
+            paint(Yellow, "Interrupted, only partial results are reported")
+        );
+    }
+
+    let exit_code = if interrupted {
+        ExitCode::from(EXIT_CODE_INTERRUPTED)
+    } else if !report_fails(&report, &cli.fail_on) || cli.exit_zero {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::from(EXIT_CODE_PROBLEMS_FOUND)
+    };
+
+    let report_text = match cli.format {
+        OutputFormat::Text => {
+            let mut out = String::new();
+            let _ = write_text_report(
+                &mut out,
+                &report,
+                &official_packages,
+                cli.verbose > 0,
+                cli.quiet,
+                cli.compact,
+                cli.tree,
+            );
+            out
+        }
+        OutputFormat::Json => {
+            serde_json::to_string_pretty(&report).context("Failed to serialize report")? + "\n"
+        }
+        OutputFormat::Markdown => markdown_report(&report),
+        OutputFormat::Csv => csv_report(&report).context("Failed to serialize report")?,
+        OutputFormat::Tap => tap_report(
+            &report,
+            checked_packages,
+            !cli.no_runtimes,
+            cli.check_pip,
+            !cli.no_systemd,
+        ),
+        OutputFormat::Jsonl => {
+            let records = report
+                .broken_runtime_packages
+                .iter()
+                .map(JsonlRecord::BrokenRuntimePackage)
+                .chain(
+                    report
+                        .broken_pip_packages
+                        .iter()
+                        .map(JsonlRecord::BrokenPipPackage),
+                )
+                .chain(
+                    report
+                        .broken_systemd_links
+                        .iter()
+                        .map(JsonlRecord::BrokenSystemdLink),
+                )
+                .chain(
+                    report
+                        .broken_symlinks
+                        .iter()
+                        .map(JsonlRecord::BrokenSymlink),
+                )
+                .chain(
+                    report
+                        .unprovided_libraries
+                        .iter()
+                        .map(JsonlRecord::UnprovidedLibrary),
+                )
+                .chain(
+                    report
+                        .broken_typelibs
+                        .iter()
+                        .map(JsonlRecord::BrokenTypelib),
+                )
+                .chain(
+                    report
+                        .broken_udev_rules
+                        .iter()
+                        .map(JsonlRecord::BrokenUdevRule),
+                )
+                .chain(
+                    report
+                        .broken_desktop_entries
+                        .iter()
+                        .map(JsonlRecord::BrokenDesktopEntry),
+                )
+                .chain(
+                    report
+                        .duplicate_sonames
+                        .iter()
+                        .map(JsonlRecord::DuplicateSoname),
+                )
+                .chain(
+                    report
+                        .foreign_arch_files
+                        .iter()
+                        .map(JsonlRecord::ForeignArchFile),
+                )
+                .chain(std::iter::once(JsonlRecord::Summary(&report.summary)));
+            records.filter_map(|r| jsonl_line(&r)).collect()
+        }
+    };
+    report_output.print(&report_text);
+
+    if cli.timings && !cli.quiet {
+        let mut timings = vec![("AUR packages", packages_duration)];
+        if !cli.no_libs {
+            timings.push(("Package files", files_listing_duration));
+            timings.push(("Missing libraries", libraries_duration));
+        }
+        if let Some(runtime_duration) = runtime_duration {
+            timings.push(("Interpreter directories", runtime_duration));
+        }
+        if !cli.no_systemd {
+            timings.push((
+                "Systemd links",
+                sd_links_listing_duration + sd_check_duration,
+            ));
+        }
+        if cli.check_symlinks
+            || cli.check_typelibs
+            || cli.check_udev
+            || cli.check_desktop_files
+            || cli.check_duplicate_sonames
+            || cli.check_pip
+        {
+            timings.push(("Optional checks", optional_checks_duration));
+        }
+        timings.push(("Total", start.elapsed()));
+        eprint!("{}", timings_table(&timings));
+    }
+
+    if let Some(fd) = cli.rebuild_list_fd {
+        write_rebuild_list(fd, &report.packages_to_rebuild())?;
+    }
+
+    if cli.fix {
+        if let Some(rebuild_helper) = cli.rebuild_cmd {
+            rebuild_packages(
+                rebuild_helper,
+                &packages_to_rebuild,
+                &official_packages,
+                cli.yes,
+            )?;
+        }
+    }
+
+    Ok(exit_code)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env;
-    use std::fs::{File, Permissions};
-    use std::io::Write;
-    use std::path::PathBuf;
-
     use tempdir::TempDir;
 
     use super::*;
 
-    fn update_path(dir: &str) -> std::ffi::OsString {
-        let path_orig = env::var_os("PATH").unwrap();
+    #[test]
+    fn test_parse_targets() {
+        assert_eq!(
+            parse_targets("python\nffmpeg 2:4.4-1  \n\n  \nzstd\t\n"),
+            ["python", "ffmpeg", "zstd"]
+        );
+        assert!(parse_targets("").is_empty());
+    }
+
+    #[test]
+    fn test_log_level() {
+        assert_eq!(log_level(0), LevelFilter::Warn);
+        assert_eq!(log_level(1), LevelFilter::Info);
+        assert_eq!(log_level(2), LevelFilter::Debug);
+        assert_eq!(log_level(3), LevelFilter::Trace);
+        assert_eq!(log_level(8), LevelFilter::Trace);
+    }
+
+    #[test]
+    fn test_notification_text() {
+        let mut report = BrokenReport::default();
+        let mut official_packages = HashSet::new();
+        assert!(notification_text(&report, &official_packages).is_none());
+
+        report.transitively_broken_packages = vec!["a".to_string(), "b".to_string()];
+        report.broken_systemd_links.push(BrokenLink {
+            link: PathBuf::from("/etc/systemd/system/multi-user.target.wants/foo.service"),
+            ..Default::default()
+        });
+        assert_eq!(
+            notification_text(&report, &official_packages).unwrap(),
+            "2 AUR packages need rebuilding\n1 broken systemd link"
+        );
+
+        report.broken_packages.push(BrokenPackage {
+            package: "pam-foo".to_string(),
+            missing: Vec::new(),
+            required_by: Vec::new(),
+            security_modules: vec!["/usr/lib/security/pam_foo.so".to_string()],
+        });
+        assert_eq!(
+            notification_text(&report, &official_packages).unwrap(),
+            "3 AUR packages need rebuilding\n\
+             1 broken PAM module or NSS plugin, login may fail\n\
+             1 broken systemd link"
+        );
+
+        // Official packages are told apart, they can not be rebuilt
+        official_packages.insert("a".to_string());
+        assert_eq!(
+            notification_text(&report, &official_packages).unwrap(),
+            "2 AUR packages need rebuilding\n\
+             1 official package is broken until its update\n\
+             1 broken PAM module or NSS plugin, login may fail\n\
+             1 broken systemd link"
+        );
+    }
+
+    #[test]
+    fn test_partition_official() {
+        let packages = vec![
+            "foo-git".to_string(),
+            "qt5-base".to_string(),
+            "bar".to_string(),
+            "ffmpeg".to_string(),
+        ];
+        let official_packages: HashSet<String> = vec!["ffmpeg".to_string(), "qt5-base".to_string()]
+            .into_iter()
+            .collect();
+        let (official, aur) = partition_official(&packages, &official_packages);
+        assert_eq!(official, ["qt5-base", "ffmpeg"]);
+        assert_eq!(aur, ["foo-git", "bar"]);
+
+        // Without official packages, all are rebuilt
+        let (official, aur) = partition_official(&packages, &HashSet::new());
+        assert!(official.is_empty());
+        assert_eq!(aur.len(), 4);
+    }
+
+    #[test]
+    fn test_markdown_report() {
+        let mut report = BrokenReport::default();
+        report.broken_packages.push(BrokenPackage {
+            package: "foo".to_string(),
+            missing: vec![MissingLibrary {
+                soname: "libbar.so.1".to_string(),
+                files: vec!["/usr/bin/foo".to_string()],
+                provider: Some("bar".to_string()),
+                provider_installed: true,
+                kind: MissingKind::VersionBump,
+            }],
+            required_by: Vec::new(),
+            security_modules: Vec::new(),
+        });
+        report.transitively_broken_packages = vec!["baz".to_string()];
+        assert_eq!(
+            markdown_report(&report),
+            "# Broken packages report\n\n\
+             ## Packages needing rebuild\n\n\
+             | Package | Missing library | Provider | Cause | Files |\n\
+             | --- | --- | --- | --- | --- |\n\
+             | `foo` | `libbar.so.1` | `bar` | version bump, rebuild | `/usr/bin/foo` |\n\n\
+             ## Transitively broken packages\n\n\
+             - `baz`\n\n\
+             Scanned 0 packages in 0.0s: no problem found.\n"
+        );
+    }
+
+    #[test]
+    fn test_csv_report() {
+        let mut report = BrokenReport::default();
+        assert_eq!(
+            csv_report(&report).unwrap(),
+            "package,missing_soname,file,providing_package,category\n"
+        );
+
+        report.broken_packages.push(BrokenPackage {
+            package: "foo".to_string(),
+            missing: vec![MissingLibrary {
+                soname: "libbar.so.1".to_string(),
+                files: vec!["/usr/bin/foo".to_string(), "/usr/bin/foo,\"2\"".to_string()],
+                provider: Some("bar".to_string()),
+                provider_installed: true,
+                kind: MissingKind::VersionBump,
+            }],
+            required_by: Vec::new(),
+            security_modules: Vec::new(),
+        });
+        report.transitively_broken_packages = vec!["baz".to_string()];
+        report.broken_runtime_packages.push(BrokenRuntimePackage {
+            runtime: "Python".to_string(),
+            package: "python-qux".to_string(),
+            dir: "/usr/lib/python3.11".to_string(),
+        });
+        assert_eq!(
+            csv_report(&report).unwrap(),
+            "package,missing_soname,file,providing_package,category\n\
+             foo,libbar.so.1,/usr/bin/foo,bar,missing\n\
+             foo,libbar.so.1,\"/usr/bin/foo,\"\"2\"\"\",bar,missing\n\
+             baz,,,,transitive\n\
+             python-qux,,/usr/lib/python3.11,,python\n"
+        );
+    }
+
+    #[test]
+    fn test_tap_report() {
+        let mut report = BrokenReport::default();
+        report.broken_packages.push(BrokenPackage {
+            package: "foo".to_string(),
+            missing: vec![MissingLibrary {
+                soname: "libbar.so.1".to_string(),
+                files: vec!["/usr/bin/foo".to_string()],
+                provider: Some("bar".to_string()),
+                provider_installed: true,
+                kind: MissingKind::VersionBump,
+            }],
+            required_by: Vec::new(),
+            security_modules: Vec::new(),
+        });
+        report.broken_systemd_links.push(BrokenLink {
+            link: PathBuf::from("/etc/systemd/system/multi-user.target.wants/foo.service"),
+            ..Default::default()
+        });
+        assert_eq!(
+            tap_report(
+                &report,
+                &["baz".to_string(), "foo".to_string()],
+                true,
+                false,
+                true
+            ),
+            "ok 1 - baz\n\
+             not ok 2 - foo\n\
+             # missing libbar.so.1 (version bump, rebuild)\n\
+             ok 3 - interpreter and toolkit directories\n\
+             not ok 4 - systemd links\n\
+             # broken link /etc/systemd/system/multi-user.target.wants/foo.service\n\
+             1..4\n"
+        );
+        assert_eq!(tap_report(&report, &[], false, false, false), "1..0\n");
+
+        report.broken_pip_packages.push(BrokenRuntimePackage {
+            runtime: "Python".to_string(),
+            package: "black".to_string(),
+            dir: "/home/user/.local/share/pipx/venvs/black/lib/python3.11".to_string(),
+        });
+        assert_eq!(
+            tap_report(&report, &[], false, true, false),
+            "not ok 1 - pip and pipx packages\n\
+             # black is installed in /home/user/.local/share/pipx/venvs/black/lib/python3.11 \
+             for an older Python\n\
+             1..1\n"
+        );
+
+        // Duplicate sonames do not fail packages
+        let mut report = BrokenReport::default();
+        report.duplicate_sonames.push(DuplicateSoname {
+            soname: "libfoo.so.1".to_string(),
+            providers: vec![
+                (
+                    "foo".to_string(),
+                    vec![PathBuf::from("/usr/lib/libfoo.so.1")],
+                ),
+                (
+                    "foo-git".to_string(),
+                    vec![PathBuf::from("/usr/local/lib/libfoo.so.1")],
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        });
+        assert_eq!(
+            tap_report(&report, &["foo".to_string()], false, false, false),
+            "ok 1 - foo\n\
+             # libfoo.so.1 also provided by foo-git\n\
+             1..1\n"
+        );
+    }
 
-        let mut paths_vec = env::split_paths(&path_orig).collect::<Vec<_>>();
-        paths_vec.insert(0, PathBuf::from(dir));
+    #[test]
+    fn test_timings_table() {
+        assert_eq!(
+            timings_table(&[
+                ("AUR packages", Duration::from_millis(120)),
+                ("Missing libraries", Duration::from_millis(3456)),
+                ("Total", Duration::from_secs(12)),
+            ]),
+            "Timings:\n\
+             \x20 AUR packages          0.12s\n\
+             \x20 Missing libraries     3.46s\n\
+             \x20 Total                12.00s\n"
+        );
+    }
 
-        let paths = env::join_paths(paths_vec).unwrap();
-        env::set_var("PATH", &paths);
+    #[test]
+    fn test_write_rebuild_list() {
+        use std::os::unix::io::AsRawFd;
 
-        path_orig
+        let tmp_dir = TempDir::new("").unwrap();
+        let list_filepath = tmp_dir.path().join("rebuild");
+        let file = fs::File::create(&list_filepath).unwrap();
+        let fd = file.as_raw_fd() as u32;
+        write_rebuild_list(fd, &["bar".to_string(), "foo".to_string()]).unwrap();
+        write_rebuild_list(fd, &[]).unwrap();
+        assert_eq!(fs::read_to_string(&list_filepath).unwrap(), "bar\nfoo\n");
+        drop(file);
+
+        assert!(write_rebuild_list(u32::MAX, &["foo".to_string()]).is_err());
     }
 
     #[test]
-    fn test_get_missing_dependencies() {
-        let ldd_output = "	linux-vdso.so.1 (0x00007ffea89a7000)
-	libavdevice.so.57 => not found
-	libavfilter.so.6 => not found
-	libavformat.so.57 => not found
-	libavcodec.so.57 => not found
-	libavresample.so.3 => not found
-	libpostproc.so.54 => not found
-	libswresample.so.2 => not found
-	libswscale.so.4 => not found
-	libavutil.so.55 => not found
-	libm.so.6 => /usr/lib/libm.so.6 (0x00007f4bd9cc3000)
-	libpthread.so.0 => /usr/lib/libpthread.so.0 (0x00007f4bd9ca2000)
-	libc.so.6 => /usr/lib/libc.so.6 (0x00007f4bd9add000)
-	/lib64/ld-linux-x86-64.so.2 => /usr/lib64/ld-linux-x86-64.so.2 (0x00007f4bda08d000)
-";
+    fn test_prometheus_metrics() {
+        let summary = Summary {
+            scanned_packages: 10,
+            broken_packages: 1,
+            transitively_broken_packages: 2,
+            duration_secs: 1.5,
+            ..Default::default()
+        };
+        let metrics = prometheus_metrics(&summary);
+        assert!(
+            metrics.contains("# TYPE arch_broken_aur_packages gauge\narch_broken_aur_packages 3\n")
+        );
+        assert!(metrics.contains("\narch_broken_systemd_links 0\n"));
+        assert!(metrics.ends_with("\narch_scan_duration_seconds 1.5\n"));
 
         let tmp_dir = TempDir::new("").unwrap();
+        let metrics_filepath = tmp_dir.path().join("check-broken-packages.prom");
+        write_file_atomic(&metrics_filepath, &metrics).unwrap();
+        assert_eq!(fs::read_to_string(&metrics_filepath).unwrap(), metrics);
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_plan_text() {
+        let cli = Cli::parse_from(["check-broken-packages", "--no-systemd", "--check-symlinks"]);
+        assert_eq!(
+            plan_text(
+                &cli.enabled_checks(),
+                &[PathBuf::from("/usr/lib")],
+                &cli.skip_dirs(),
+                &["foo".to_string(), "bar".to_string()]
+            ),
+            "Checks: libraries, interpreter and toolkit directories, package symlinks\n\
+             Library search paths:\n  /usr/lib\n\
+             Skipped directories:\n  /opt\n  /usr/share\n\
+             Packages to scan (2):\n  foo\n  bar\n"
+        );
+    }
+
+    #[test]
+    fn test_dependency_graph_dot() {
+        let dependencies = [FileDependencies {
+            file: PathBuf::from("/usr/bin/foo \"bar\""),
+            needed: vec!["libfoo.so.1".to_string(), "libbaz.so.2".to_string()],
+            missing: vec!["libbaz.so.2".to_string()],
+        }];
+        assert_eq!(
+            dependency_graph_dot("foo", &dependencies),
+            "digraph \"foo\" {\n    \
+             node [shape=box];\n    \
+             \"/usr/bin/foo \\\"bar\\\"\" [style=bold];\n    \
+             \"/usr/bin/foo \\\"bar\\\"\" -> \"libfoo.so.1\";\n    \
+             \"/usr/bin/foo \\\"bar\\\"\" -> \"libbaz.so.2\" [color=red];\n    \
+             \"libbaz.so.2\" [color=red, fontcolor=red];\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_required_programs() {
+        let programs = |args: &[&str]| -> Vec<&str> {
+            let cli = Cli::parse_from(
+                std::iter::once("check-broken-packages").chain(args.iter().copied()),
+            );
+            required_programs(&cli)
+                .into_iter()
+                .map(|(p, _)| p)
+                .collect()
+        };
+        assert_eq!(programs(&[]), ["pacman"]);
+        for args in [
+            &["--check-duplicate-sonames"][..],
+            &["--check-symlinks"],
+            &["--check-typelibs"],
+            &["--check-udev"],
+            &["--check-desktop-files"],
+            &["--check-pip"],
+            &["--since", "2024-01-01"],
+            &["--sync-files"],
+            &["--root", "/mnt"],
+            &["--dbpath", "/mnt/var/lib/pacman"],
+        ] {
+            let args: Vec<&str> = ["--no-libs", "--no-runtimes"]
+                .iter()
+                .chain(args)
+                .copied()
+                .collect();
+            assert_eq!(programs(&args), ["pacman"], "{:?}", args);
+        }
+        // Only checking systemd links of the running system does not need pacman
+        assert!(programs(&["--no-libs", "--no-runtimes"]).is_empty());
+        assert_eq!(
+            programs(&["--notify", "--rebuild-cmd", "paru", "--fix"]),
+            ["pacman", "notify-send", "paru"]
+        );
+        assert_eq!(programs(&["--rebuild-cmd", "paru"]), ["pacman"]);
+        assert_eq!(programs(&["--rebuild-cmd", "makepkg", "--fix"]), ["pacman"]);
+    }
+
+    #[test]
+    fn test_compact_text_report() {
+        let missing_library = |soname: &str, kind| MissingLibrary {
+            soname: soname.to_string(),
+            files: vec!["/usr/bin/foo".to_string()],
+            provider: Some("bar".to_string()),
+            provider_installed: true,
+            kind,
+        };
+        let report = BrokenReport {
+            broken_packages: vec![
+                BrokenPackage {
+                    package: "foo".to_string(),
+                    missing: vec![
+                        missing_library("libbar.so.1", MissingKind::VersionBump),
+                        missing_library("libbaz.so.2", MissingKind::Absent),
+                    ],
+                    required_by: vec!["qux".to_string()],
+                    security_modules: Vec::new(),
+                },
+                BrokenPackage {
+                    package: "quux".to_string(),
+                    missing: vec![missing_library("libbaz.so.2", MissingKind::Absent)],
+                    required_by: Vec::new(),
+                    security_modules: Vec::new(),
+                },
+            ],
+            ..Default::default()
+        };
+
+        let mut out = String::new();
+        write_text_report(&mut out, &report, &HashSet::new(), false, true, true, false).unwrap();
+        assert_eq!(
+            strip_ansi_codes(&out),
+            "[ERROR] foo: missing libbar.so.1, libbaz.so.2 (rebuild)\n\
+             [ERROR] quux: missing libbaz.so.2 (install provider)\n"
+        );
+
+        assert!(Cli::try_parse_from(["check-broken-packages", "--compact"]).is_ok());
+        assert!(
+            Cli::try_parse_from(["check-broken-packages", "--compact", "--format", "json"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_severity() {
+        let broken_package = |kinds: &[MissingKind]| BrokenPackage {
+            package: "foo".to_string(),
+            missing: kinds
+                .iter()
+                .map(|&kind| MissingLibrary {
+                    soname: "libbar.so.1".to_string(),
+                    files: vec!["/usr/bin/foo".to_string()],
+                    provider: None,
+                    provider_installed: false,
+                    kind,
+                })
+                .collect(),
+            required_by: Vec::new(),
+            security_modules: Vec::new(),
+        };
+        assert_eq!(
+            Severity::of_package(&broken_package(&[MissingKind::VersionBump])),
+            Severity::Warning
+        );
+        assert_eq!(
+            Severity::of_package(&broken_package(&[
+                MissingKind::VersionBump,
+                MissingKind::Absent
+            ])),
+            Severity::Error
+        );
 
-        let output_filepath = tmp_dir.path().join("output.txt");
-        let mut output_file = File::create(&output_filepath).unwrap();
-        output_file.write_all(ldd_output.as_bytes()).unwrap();
-        drop(output_file);
-
-        let fake_ldd_filepath = tmp_dir.path().join("ldd");
-        let mut fake_ldd_file = File::create(fake_ldd_filepath).unwrap();
-        write!(
-            &mut fake_ldd_file,
-            "#!/bin/sh\ncat {}",
-            output_filepath.into_os_string().into_string().unwrap()
+        // Each category of findings has its own severity
+        let report = BrokenReport {
+            transitively_broken_packages: vec!["bar".to_string()],
+            broken_runtime_packages: vec![BrokenRuntimePackage {
+                runtime: "Python".to_string(),
+                package: "python-foo".to_string(),
+                dir: "/usr/lib/python3.11".to_string(),
+            }],
+            broken_systemd_links: vec![BrokenLink::default()],
+            unprovided_libraries: vec![UnprovidedLibrary {
+                package: "baz".to_string(),
+                soname: "libbaz.so.1".to_string(),
+                files: vec!["/usr/bin/baz".to_string()],
+            }],
+            duplicate_sonames: vec![DuplicateSoname {
+                soname: "libqux.so.1".to_string(),
+                providers: BTreeMap::new(),
+            }],
+            ..Default::default()
+        };
+        let mut out = String::new();
+        write_text_report(
+            &mut out,
+            &report,
+            &HashSet::new(),
+            false,
+            true,
+            false,
+            false,
         )
         .unwrap();
-        fake_ldd_file
-            .set_permissions(Permissions::from_mode(0o700))
+        let tags: Vec<&str> = strip_ansi_codes(&out)
+            .lines()
+            .filter_map(|l| l.split(' ').next())
+            .map(|t| match t {
+                "[ERROR]" => "E",
+                "[WARNING]" => "W",
+                "[INFO]" => "I",
+                _ => "?",
+            })
+            .collect();
+        // Transitively broken, runtime, systemd, duplicate, unprovided
+        assert_eq!(tags, ["I", "W", "E", "I", "W"]);
+    }
+
+    #[test]
+    fn test_severity_legend() {
+        let legend =
+            "[ERROR] breaks programs now, [WARNING] needs a rebuild or cleanup, [INFO] is \
+                      for information";
+        let report = BrokenReport {
+            transitively_broken_packages: vec!["foo".to_string()],
+            ..Default::default()
+        };
+        let text_report = |report: &BrokenReport, quiet: bool| {
+            let mut out = String::new();
+            write_text_report(
+                &mut out,
+                report,
+                &HashSet::new(),
+                false,
+                quiet,
+                false,
+                false,
+            )
             .unwrap();
-        drop(fake_ldd_file);
+            strip_ansi_codes(&out)
+        };
+        assert!(text_report(&report, false).contains(legend));
+        // Not printed in quiet mode, or without any finding
+        assert!(!text_report(&report, true).contains(legend));
+        assert!(!text_report(&BrokenReport::default(), false).contains(legend));
+    }
+
+    #[test]
+    fn test_fail_on() {
+        let report = BrokenReport {
+            broken_runtime_packages: vec![BrokenRuntimePackage {
+                runtime: "Python".to_string(),
+                package: "python-foo".to_string(),
+                dir: "/usr/lib/python3.11".to_string(),
+            }],
+            ..Default::default()
+        };
+        let fail_on = |args: &[&str]| {
+            Cli::try_parse_from(
+                std::iter::once("check-broken-packages").chain(args.iter().copied()),
+            )
+            .unwrap()
+            .fail_on
+        };
+
+        assert!(report_fails(&report, &fail_on(&[])));
+        assert!(!report_fails(
+            &report,
+            &fail_on(&["--fail-on=libs,systemd"])
+        ));
+        assert!(report_fails(&report, &fail_on(&["--fail-on=libs,python"])));
+        assert!(!report_fails(&BrokenReport::default(), &fail_on(&[])));
+
+        // Duplicate sonames are only informational, unless explicitly failed on
+        let report = BrokenReport {
+            duplicate_sonames: vec![DuplicateSoname {
+                soname: "libfoo.so.1".to_string(),
+                providers: BTreeMap::new(),
+            }],
+            ..Default::default()
+        };
+        assert!(report.is_clean());
+        assert!(!report_fails(&report, &fail_on(&[])));
+        assert!(report_fails(&report, &fail_on(&["--fail-on=duplicates"])));
+
+        // Pip packages are told apart from interpreter packages
+        let report = BrokenReport {
+            broken_pip_packages: vec![BrokenRuntimePackage {
+                runtime: "Python".to_string(),
+                package: "black".to_string(),
+                dir: "/home/user/.local/share/pipx/venvs/black/lib/python3.11".to_string(),
+            }],
+            ..Default::default()
+        };
+        assert!(report_fails(&report, &fail_on(&["--fail-on=pip"])));
+        assert!(!report_fails(&report, &fail_on(&["--fail-on=runtimes"])));
+    }
+
+    #[test]
+    fn test_transitive_tree() {
+        let broken_package = |package: &str| BrokenPackage {
+            package: package.to_string(),
+            missing: Vec::new(),
+            required_by: Vec::new(),
+            security_modules: Vec::new(),
+        };
+        let mut report = BrokenReport {
+            broken_packages: vec![broken_package("a"), broken_package("e")],
+            transitively_broken_packages: vec![
+                "b".to_string(),
+                "c".to_string(),
+                "d".to_string(),
+                "f".to_string(),
+            ],
+            ..Default::default()
+        };
+
+        // No dependency data, all packages are left to the flat list
+        let mut out = String::new();
+        assert_eq!(
+            write_transitive_tree(&mut out, &report).unwrap(),
+            ["b", "c", "d", "f"]
+        );
+        assert!(out.is_empty());
+
+        report.reverse_deps = vec![
+            ("a", vec!["b", "d", "x"]),
+            ("b", vec!["c", "a"]),
+            ("c", vec!["b"]),
+            ("e", vec!["d"]),
+        ]
+        .into_iter()
+        .map(|(p, r)| (p.to_string(), r.into_iter().map(str::to_string).collect()))
+        .collect();
+        let mut out = String::new();
+        assert_eq!(write_transitive_tree(&mut out, &report).unwrap(), ["f"]);
+        assert_eq!(
+            strip_ansi_codes(&out),
+            "[INFO] transitively broken packages, below the broken packages they depend on:\n\
+             a\n\
+             ├─ b\n\
+             │  └─ c\n\
+             └─ d\n"
+        );
+    }
 
-        let path_orig = update_path(tmp_dir.path().to_str().unwrap());
+    #[test]
+    fn test_sort_order() {
+        let broken_package = |package: &str, providers: &[Option<&str>]| BrokenPackage {
+            package: package.to_string(),
+            missing: providers
+                .iter()
+                .map(|provider| MissingLibrary {
+                    soname: "libfoo.so.1".to_string(),
+                    files: Vec::new(),
+                    provider: provider.map(str::to_string),
+                    provider_installed: true,
+                    kind: MissingKind::VersionBump,
+                })
+                .collect(),
+            required_by: Vec::new(),
+            security_modules: Vec::new(),
+        };
+        let mut broken_packages = vec![
+            broken_package("d", &[Some("?")]),
+            broken_package("c", &[Some("icu")]),
+            broken_package("b", &[Some("icu"), Some("ffmpeg"), None]),
+            broken_package("a", &[Some("icu")]),
+        ];
+        let names = |broken_packages: &[BrokenPackage]| -> Vec<String> {
+            broken_packages.iter().map(|p| p.package.clone()).collect()
+        };
+
+        SortOrder::Name.sort(&mut broken_packages);
+        assert_eq!(names(&broken_packages), ["a", "b", "c", "d"]);
+        SortOrder::Severity.sort(&mut broken_packages);
+        assert_eq!(names(&broken_packages), ["b", "a", "c", "d"]);
+        SortOrder::Provider.sort(&mut broken_packages);
+        assert_eq!(names(&broken_packages), ["b", "a", "c", "d"]);
+        broken_packages[0].missing.truncate(1);
+        SortOrder::Provider.sort(&mut broken_packages);
+        assert_eq!(names(&broken_packages), ["a", "b", "c", "d"]);
+
+        // Packages grouped by missing soname follow the same order
+        broken_packages[2].missing[0].soname = "libbar.so.1".to_string();
+        broken_packages[3].missing[0].soname = "libbaz.so.1".to_string();
+        broken_packages.reverse();
+        let report = BrokenReport {
+            broken_packages,
+            ..Default::default()
+        };
+        let mut out = String::new();
+        write_text_report(
+            &mut out,
+            &report,
+            &HashSet::new(),
+            false,
+            false,
+            false,
+            false,
+        )
+        .unwrap();
+        let groups: Vec<String> = strip_ansi_codes(&out)
+            .lines()
+            .filter(|l| l.contains("because of missing"))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(
+            groups,
+            [
+                "package need rebuild because of missing libbaz.so.1: d",
+                "package need rebuild because of missing libbar.so.1: c",
+                "packages need rebuild because of missing libfoo.so.1: b a",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file_kind_flags_conflict() {
+        assert!(Cli::try_parse_from(["check-broken-packages", "--libs-only"]).is_ok());
+        assert!(
+            Cli::try_parse_from(["check-broken-packages", "--libs-only", "--exec-only"]).is_err()
+        );
+    }
+
+    #[test]
+    fn test_hook_flag() {
+        assert!(
+            Cli::try_parse_from(["check-broken-packages", "--hook"])
+                .unwrap()
+                .hook
+        );
+        assert!(Cli::try_parse_from(["check-broken-packages", "--hook", "--wait-lock"]).is_err());
+    }
+
+    #[test]
+    fn test_read_package_list() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let list_filepath = tmp_dir.path().join("packages.txt");
+        fs::write(&list_filepath, "foo\n\n  bar 1.0-1\n").unwrap();
+        assert_eq!(read_package_list(&list_filepath).unwrap(), ["foo", "bar"]);
+        assert!(read_package_list(&tmp_dir.path().join("missing")).is_err());
+
+        let cli = Cli::parse_from(["check-broken-packages", "--packages-from-file", "-"]);
+        assert!(cli.has_package_list());
+    }
+
+    #[test]
+    fn test_parse_confirmation() {
+        assert!(parse_confirmation("y\n"));
+        assert!(parse_confirmation(" Yes\n"));
+        assert!(!parse_confirmation("\n"));
+        assert!(!parse_confirmation("no\n"));
+        assert!(!parse_confirmation("yep\n"));
+    }
+
+    #[test]
+    fn test_jsonl_record() {
+        let missing_dep = MissingDependency {
+            package: Arc::new("foo".to_string()),
+            file: Arc::new(PathBuf::from("/usr/bin/foo")),
+            soname: "libbar.so.1".to_string(),
+            providers: vec!["bar".to_string()],
+            is_direct: true,
+        };
+        assert_eq!(
+            serde_json::to_string(&JsonlRecord::from(&missing_dep)).unwrap(),
+            r#"{"type":"missing-library","package":"foo","file":"/usr/bin/foo","soname":"libbar.so.1","providers":["bar"],"is_direct":true}"#
+        );
+
+        let broken_link = BrokenLink {
+            link: PathBuf::from("/etc/systemd/system/multi-user.target.wants/foo.service"),
+            target: PathBuf::from("/usr/lib/systemd/system/foo.service"),
+            missing: PathBuf::from("/usr/lib/systemd/system/foo.service"),
+        };
+        assert_eq!(
+            serde_json::to_string(&JsonlRecord::BrokenSystemdLink(&broken_link)).unwrap(),
+            r#"{"type":"broken-systemd-link","link":"/etc/systemd/system/multi-user.target.wants/foo.service","target":"/usr/lib/systemd/system/foo.service","missing":"/usr/lib/systemd/system/foo.service"}"#
+        );
+    }
+
+    #[test]
+    fn test_report_output() {
+        assert_eq!(
+            strip_ansi_codes(&format!(
+                "{} misses {}",
+                Red.paint("foo"),
+                Yellow.bold().paint("bar")
+            )),
+            "foo misses bar"
+        );
+
+        let tmp_dir = TempDir::new("").unwrap();
+        let report_filepath = tmp_dir.path().join("report.log");
+        fs::write(&report_filepath, "old\n").unwrap();
+        ReportOutput::open(Some(&report_filepath), false)
+            .unwrap()
+            .print(&format!("{}\n", Red.paint("first")));
+        assert_eq!(fs::read_to_string(&report_filepath).unwrap(), "first\n");
+        ReportOutput::open(Some(&report_filepath), true)
+            .unwrap()
+            .print("second\n");
+        assert_eq!(
+            fs::read_to_string(&report_filepath).unwrap(),
+            "first\nsecond\n"
+        );
 
-        let missing_deps = get_missing_dependencies("dummy");
-        assert!(missing_deps.is_ok());
+        // Each report of watch mode replaces the previous one
+        let report_output = ReportOutput::open(Some(&report_filepath), false).unwrap();
+        report_output.print("first scan\n");
+        report_output.truncate().unwrap();
+        report_output.print("second\n");
+        assert_eq!(fs::read_to_string(&report_filepath).unwrap(), "second\n");
+        ReportOutput::open(None, false).unwrap().truncate().unwrap();
+    }
+
+    #[test]
+    fn test_read_config() {
+        let tmp_dir = TempDir::new("").unwrap();
+        let config_filepath = tmp_dir.path().join("config.toml");
+        assert!(read_config(&config_filepath).unwrap().lib_dirs.is_empty());
+
+        fs::write(
+            &config_filepath,
+            "no-systemd = true\nno-libs = true\ncolor = \"never\"\nlib-dirs = [\"/opt/foo/lib\"]\n",
+        )
+        .unwrap();
+        let config = read_config(&config_filepath).unwrap();
+        assert_eq!(config.lib_dirs, [PathBuf::from("/opt/foo/lib")]);
+
+        // Command line options take precedence
+        let mut cli = Cli::parse_from(["check-broken-packages", "--color", "always", "--libs"]);
+        cli.merge_config(config);
+        assert_eq!(cli.extra_lib_dirs, [PathBuf::from("/opt/foo/lib")]);
+        assert!(cli.no_systemd && !cli.no_libs && !cli.no_runtimes);
+        assert!(matches!(cli.color, Some(ColorMode::Always)));
+
+        // The last of a pair of opposite options wins
+        let cli = Cli::parse_from(["check-broken-packages", "--no-libs", "--libs"]);
+        assert!(!cli.no_libs && cli.libs);
+        let cli = Cli::parse_from(["check-broken-packages", "--libs", "--no-libs"]);
+        assert!(cli.no_libs && !cli.libs);
+
+        // Library directories of the command line come first
+        let mut cli = Cli::parse_from([
+            "check-broken-packages",
+            "--extra-libdir",
+            "/opt/bar/lib",
+            "--extra-libdir",
+            "/opt/baz/lib",
+        ]);
+        cli.merge_config(toml::from_str("lib-dirs = [\"/opt/foo/lib\"]").unwrap());
+        assert_eq!(
+            cli.extra_lib_dirs,
+            [
+                PathBuf::from("/opt/bar/lib"),
+                PathBuf::from("/opt/baz/lib"),
+                PathBuf::from("/opt/foo/lib")
+            ]
+        );
+
+        // Skipped directories
+        let mut cli = Cli::parse_from(["check-broken-packages", "--skip-dir", "/usr/lib/foo"]);
         assert_eq!(
-            missing_deps.unwrap(),
+            cli.skip_dirs(),
             [
-                "libavdevice.so.57",
-                "libavfilter.so.6",
-                "libavformat.so.57",
-                "libavcodec.so.57",
-                "libavresample.so.3",
-                "libpostproc.so.54",
-                "libswresample.so.2",
-                "libswscale.so.4",
-                "libavutil.so.55"
+                PathBuf::from("/opt"),
+                PathBuf::from("/usr/share"),
+                PathBuf::from("/usr/lib/foo")
             ]
         );
+        cli.merge_config(toml::from_str("include-opt = true").unwrap());
+        assert_eq!(
+            cli.skip_dirs(),
+            [PathBuf::from("/usr/share"), PathBuf::from("/usr/lib/foo")]
+        );
+        let mut cli = Cli::parse_from(["check-broken-packages", "--no-include-opt"]);
+        cli.merge_config(toml::from_str("include-opt = true").unwrap());
+        assert!(!cli.include_opt);
+
+        // Excluded packages add up with those of the command line
+        fs::write(&config_filepath, "exclude = [\"*-bin\", \"foo-git\"]\n").unwrap();
+        let mut cli = Cli::parse_from(["check-broken-packages", "--exclude", "bar"]);
+        cli.merge_config(read_config(&config_filepath).unwrap());
+        assert_eq!(cli.exclude, ["bar", "*-bin", "foo-git"]);
+
+        fs::write(&config_filepath, "no-sytemd = true\n").unwrap();
+        assert!(read_config(&config_filepath).is_err());
+    }
 
-        env::set_var("PATH", &path_orig);
+    #[test]
+    fn test_parse_exclude_patterns() {
+        let patterns =
+            parse_exclude_patterns("# vendored binaries\nfoo-bin\n\n  *-git # dev builds\n")
+                .unwrap();
+        assert_eq!(patterns.len(), 2);
+        assert!(patterns.iter().any(|p| p.matches("foo-bin")));
+        assert!(patterns.iter().any(|p| p.matches("bar-git")));
+        assert!(!patterns.iter().any(|p| p.matches("foo")));
+
+        assert!(parse_exclude_patterns("[").is_err());
     }
 }